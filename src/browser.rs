@@ -1,34 +1,253 @@
 use anyhow::{anyhow, Result};
 use futures::Future;
+use js_sys::ArrayBuffer;
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::atomic::{AtomicU8, Ordering},
+};
 use wasm_bindgen::{
     closure::{Closure, WasmClosure, WasmClosureFnOnce},
     JsCast, JsValue,
 };
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    CanvasRenderingContext2d, Document, HtmlCanvasElement, HtmlImageElement, Response, Window,
+    BlobEvent, CanvasRenderingContext2d, Document, EventTarget, HtmlAnchorElement,
+    HtmlCanvasElement, HtmlImageElement, MediaRecorder, MediaRecorderOptions, Performance, Request,
+    RequestInit, Response, Storage, Url, UrlSearchParams, Window,
 };
 
+macro_rules! debug {
+    ( $( $t:tt )* ) => {
+        // リリースビルドではformat!ごと消える
+        if cfg!(debug_assertions) && $crate::browser::log_enabled($crate::browser::LogLevel::Debug) {
+            $crate::browser::write_log($crate::browser::LogLevel::Debug, &format!( $( $t )* ));
+        }
+    };
+}
+
+macro_rules! info {
+    ( $( $t:tt )* ) => {
+        if $crate::browser::log_enabled($crate::browser::LogLevel::Info) {
+            $crate::browser::write_log($crate::browser::LogLevel::Info, &format!( $( $t )* ));
+        }
+    };
+}
+
+macro_rules! warn {
+    ( $( $t:tt )* ) => {
+        if $crate::browser::log_enabled($crate::browser::LogLevel::Warn) {
+            $crate::browser::write_log($crate::browser::LogLevel::Warn, &format!( $( $t )* ));
+        }
+    };
+}
+
+macro_rules! error {
+    ( $( $t:tt )* ) => {
+        if $crate::browser::log_enabled($crate::browser::LogLevel::Error) {
+            $crate::browser::write_log($crate::browser::LogLevel::Error, &format!( $( $t )* ));
+        }
+    };
+}
+
+// 以前からのlog!はinfo!と同じ
+#[allow(unused_macros)]
+macro_rules! log {
+    ( $( $t:tt )* ) => {
+        info!( $( $t )* )
+    };
+}
+
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+pub enum LogLevel {
+    Debug = 0,
+    Info = 1,
+    Warn = 2,
+    Error = 3,
+}
+
+impl LogLevel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(if cfg!(debug_assertions) {
+    LogLevel::Debug as u8
+} else {
+    LogLevel::Info as u8
+});
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 >= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+#[cfg(not(test))]
+pub fn write_log(level: LogLevel, message: &str) {
+    let message = JsValue::from_str(message);
+    match level {
+        LogLevel::Debug => web_sys::console::debug_1(&message),
+        LogLevel::Info => web_sys::console::info_1(&message),
+        LogLevel::Warn => web_sys::console::warn_1(&message),
+        LogLevel::Error => web_sys::console::error_1(&message),
+    }
+}
+
+// ブラウザの外で動かすテストにはconsoleが無いので、ログは捨てる
+#[cfg(test)]
+pub fn write_log(_level: LogLevel, _message: &str) {}
+
+#[cfg(not(test))]
 pub fn window() -> Result<Window> {
     web_sys::window().ok_or_else(|| anyhow!("No Window Found"))
 }
 
+// ブラウザの外で動かすテストにはwindowが無い
+#[cfg(test)]
+pub fn window() -> Result<Window> {
+    Err(anyhow!("No Window Found"))
+}
+
 pub fn document() -> Result<Document> {
     window()?
         .document()
         .ok_or_else(|| anyhow!("No Document Found"))
 }
 
-pub fn canvas() -> Result<HtmlCanvasElement> {
+// URLのクエリ文字列(?name=value)から値を取り出す
+pub fn query_param(name: &str) -> Result<Option<String>> {
+    let search = window()?
+        .location()
+        .search()
+        .map_err(|err| anyhow!("Could not read location search {:#?}", err))?;
+    let params = UrlSearchParams::new_with_str(&search)
+        .map_err(|err| anyhow!("Could not parse query string {:#?}", err))?;
+    Ok(params.get(name))
+}
+
+fn local_storage() -> Result<Storage> {
+    window()?
+        .local_storage()
+        .map_err(|err| anyhow!("Could not access localStorage {:#?}", err))?
+        .ok_or_else(|| anyhow!("No localStorage Found"))
+}
+
+pub fn load_setting(key: &str) -> Result<Option<String>> {
+    local_storage()?
+        .get_item(key)
+        .map_err(|err| anyhow!("Could not read '{}' from localStorage {:#?}", key, err))
+}
+
+pub fn save_setting(key: &str, value: &str) -> Result<()> {
+    local_storage()?
+        .set_item(key, value)
+        .map_err(|err| anyhow!("Could not write '{}' to localStorage {:#?}", key, err))
+}
+
+// 設定や記録の保存先。テストではlocalStorageの代わりにメモリに置く
+pub trait Store {
+    fn load(&self, key: &str) -> Result<Option<String>>;
+    fn save(&self, key: &str, value: &str) -> Result<()>;
+}
+
+pub struct LocalStorage;
+
+impl Store for LocalStorage {
+    fn load(&self, key: &str) -> Result<Option<String>> {
+        load_setting(key)
+    }
+
+    fn save(&self, key: &str, value: &str) -> Result<()> {
+        save_setting(key, value)
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    items: RefCell<std::collections::HashMap<String, String>>,
+}
+
+#[cfg(test)]
+impl Store for MemoryStore {
+    fn load(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.items.borrow().get(key).cloned())
+    }
+
+    fn save(&self, key: &str, value: &str) -> Result<()> {
+        self.items
+            .borrow_mut()
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+// #seed=1234 のようなURLのフラグメントから値を読む
+pub fn hash_param(name: &str) -> Result<Option<String>> {
+    let hash = window()?
+        .location()
+        .hash()
+        .map_err(|err| anyhow!("Could not read location hash {:#?}", err))?;
+    let params = UrlSearchParams::new_with_str(hash.trim_start_matches('#'))
+        .map_err(|err| anyhow!("Could not parse location hash {:#?}", err))?;
+    Ok(params.get(name))
+}
+
+// ページを読み込み直さずにフラグメントだけを書き換える。
+// JSONのような値も読み戻せるようにエンコードしておく
+pub fn set_hash_param(name: &str, value: &str) -> Result<()> {
+    let value = String::from(js_sys::encode_uri_component(value));
+    window()?
+        .location()
+        .set_hash(&format!("{}={}", name, value))
+        .map_err(|err| anyhow!("Could not set location hash {:#?}", err))
+}
+
+pub fn canvas(id: &str) -> Result<HtmlCanvasElement> {
     document()?
-        .get_element_by_id("canvas")
-        .ok_or_else(|| anyhow!("No Canvas Element found with ID 'canvas'"))?
+        .get_element_by_id(id)
+        .ok_or_else(|| anyhow!("No element with id '{}'", id))?
         .dyn_into::<web_sys::HtmlCanvasElement>()
         .map_err(|element| anyhow!("Error converting {:#?} to HtmlCanvasElement", element))
 }
 
-pub fn context() -> Result<CanvasRenderingContext2d> {
-    canvas()?
+// canvasの内部の大きさを変えても、画面に表示される大きさは変わらないようにする
+pub fn set_display_size(canvas: &HtmlCanvasElement, width: u32, height: u32) -> Result<()> {
+    let style = canvas.style();
+    style
+        .set_property("width", &format!("{}px", width))
+        .and_then(|_| style.set_property("height", &format!("{}px", height)))
+        .map_err(|err| anyhow!("Could not set the canvas display size {:#?}", err))
+}
+
+pub fn context(canvas_id: &str) -> Result<CanvasRenderingContext2d> {
+    context_2d(&canvas(canvas_id)?)
+}
+
+// ページに置かないcanvasを作る。サムネイルのように画面の外で描く時に使う
+pub fn offscreen_context(width: u32, height: u32) -> Result<CanvasRenderingContext2d> {
+    let canvas = document()?
+        .create_element("canvas")
+        .map_err(|err| anyhow!("Could not create canvas element {:#?}", err))?
+        .dyn_into::<HtmlCanvasElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlCanvasElement", element))?;
+    canvas.set_width(width);
+    canvas.set_height(height);
+    context_2d(&canvas)
+}
+
+fn context_2d(canvas: &HtmlCanvasElement) -> Result<CanvasRenderingContext2d> {
+    canvas
         .get_context("2d")
         .map_err(|js_value| anyhow!("Error getting 2d context {:#?}", js_value))?
         .ok_or_else(|| anyhow!("No 2d context found"))?
@@ -54,6 +273,80 @@ pub async fn fetch_with_str(resource: &str) -> Result<JsValue> {
         .map_err(|err| anyhow!("error fetching {:#?}", err))
 }
 
+pub async fn fetch_array_buffer(resource: &str) -> Result<ArrayBuffer> {
+    let array_buffer = fetch_response(resource)
+        .await?
+        .array_buffer()
+        .map_err(|err| anyhow!("Error loading array buffer {:#?}", err))?;
+
+    JsFuture::from(array_buffer)
+        .await
+        .map_err(|err| anyhow!("Error converting array buffer into a future {:#?}", err))?
+        .dyn_into()
+        .map_err(|err| anyhow!("Error converting raw JSValue to ArrayBuffer {:#?}", err))
+}
+
+async fn fetch_response(resource: &str) -> Result<Response> {
+    fetch_with_str(resource)
+        .await?
+        .dyn_into()
+        .map_err(|err| anyhow!("error converting fetch to Response {:#?}", err))
+}
+
+// 足したリスナー。落とすと外れるので、使っている間は持っておくこと
+pub struct EventListener {
+    target: EventTarget,
+    event: &'static str,
+    callback: Closure<dyn FnMut()>,
+}
+
+impl Drop for EventListener {
+    fn drop(&mut self) {
+        if let Err(err) = self
+            .target
+            .remove_event_listener_with_callback(self.event, self.callback.as_ref().unchecked_ref())
+        {
+            error!("Could not remove the {} listener {:#?}", self.event, err);
+        }
+    }
+}
+
+// 他のゲームのリスナーを上書きしないように、onvisibilitychangeではなくリスナーを足す
+pub fn on_visibility_change(callback: impl FnMut() + 'static) -> Result<EventListener> {
+    let callback = closure_wrap(Box::new(callback) as Box<dyn FnMut()>);
+    let target: EventTarget = document()?.into();
+    target
+        .add_event_listener_with_callback("visibilitychange", callback.as_ref().unchecked_ref())
+        .map_err(|err| anyhow!("Could not listen for visibilitychange {:#?}", err))?;
+    Ok(EventListener {
+        target,
+        event: "visibilitychange",
+        callback,
+    })
+}
+
+pub async fn post_json(url: &str, body: &str) -> Result<()> {
+    let mut init = RequestInit::new();
+    init.method("POST").body(Some(&JsValue::from_str(body)));
+    let request = Request::new_with_str_and_init(url, &init)
+        .map_err(|err| anyhow!("Could not create request for {} {:#?}", url, err))?;
+    request
+        .headers()
+        .set("Content-Type", "application/json")
+        .map_err(|err| anyhow!("Could not set Content-Type {:#?}", err))?;
+
+    let response: Response = JsFuture::from(window()?.fetch_with_request(&request))
+        .await
+        .map_err(|err| anyhow!("error posting to {} {:#?}", url, err))?
+        .dyn_into()
+        .map_err(|err| anyhow!("error converting fetch to Response {:#?}", err))?;
+    if response.ok() {
+        Ok(())
+    } else {
+        Err(anyhow!("{} responded with {}", url, response.status()))
+    }
+}
+
 pub async fn fetch_json(json_path: &str) -> Result<JsValue> {
     let resp_value = fetch_with_str(json_path).await?;
     let resp = resp_value
@@ -86,6 +379,29 @@ pub fn request_animation_frame(callback: &LoopClosure) -> Result<i32> {
         .map_err(|err| anyhow!("Cannot request animation frame {:#?}", err))
 }
 
+// callbackは引数なしで呼ばれるので、LoopClosureにはNaNが渡る
+pub fn set_timeout(callback: &LoopClosure, millis: i32) -> Result<i32> {
+    window()?
+        .set_timeout_with_callback_and_timeout_and_arguments_0(
+            callback.as_ref().unchecked_ref(),
+            millis,
+        )
+        .map_err(|err| anyhow!("Cannot set timeout {:#?}", err))
+}
+
+pub fn set_interval(callback: &Closure<dyn FnMut()>, millis: i32) -> Result<i32> {
+    window()?
+        .set_interval_with_callback_and_timeout_and_arguments_0(
+            callback.as_ref().unchecked_ref(),
+            millis,
+        )
+        .map_err(|err| anyhow!("Cannot set interval {:#?}", err))
+}
+
+pub fn document_hidden() -> bool {
+    document().is_ok_and(|document| document.hidden())
+}
+
 pub fn create_raf_closure(f: impl FnMut(f64) + 'static) -> LoopClosure {
     closure_wrap(Box::new(f))
 }
@@ -94,9 +410,112 @@ pub fn closure_wrap<T: WasmClosure + ?Sized>(data: Box<T>) -> Closure<T> {
     Closure::wrap(data)
 }
 
-pub fn now() -> Result<f64> {
-    Ok(window()?
+fn performance() -> Result<Performance> {
+    window()?
         .performance()
-        .ok_or_else(|| anyhow!("Performance object not found"))?
-        .now())
+        .ok_or_else(|| anyhow!("Performance object not found"))
+}
+
+pub fn now() -> Result<f64> {
+    Ok(performance()?.now())
+}
+
+// UTCでの今日の日付を20261016のような数にする。どのタイムゾーンでも同じ日には同じ値になる
+pub fn utc_day() -> u32 {
+    let date = js_sys::Date::new_0();
+    date.get_utc_full_year() * 10000 + (date.get_utc_month() + 1) * 100 + date.get_utc_date()
+}
+
+pub fn perf_mark(name: &str) -> Result<()> {
+    performance()?
+        .mark(name)
+        .map_err(|err| anyhow!("Could not create performance mark {:#?}", err))
+}
+
+pub fn perf_measure(name: &str, start: &str, end: &str) -> Result<()> {
+    performance()?
+        .measure_with_start_mark_and_end_mark(name, start, end)
+        .map_err(|err| anyhow!("Could not create performance measure {:#?}", err))
+}
+
+pub fn screenshot(canvas: &HtmlCanvasElement) -> Result<String> {
+    canvas
+        .to_data_url()
+        .map_err(|err| anyhow!("Could not capture canvas {:#?}", err))
+}
+
+pub fn download(url: &str, file_name: &str) -> Result<()> {
+    let link = document()?
+        .create_element("a")
+        .map_err(|err| anyhow!("Could not create link element {:#?}", err))?
+        .dyn_into::<HtmlAnchorElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlAnchorElement", element))?;
+    link.set_href(url);
+    link.set_download(file_name);
+    link.click();
+    Ok(())
+}
+
+type BlobHandler = Rc<RefCell<Option<Closure<dyn FnMut(BlobEvent)>>>>;
+
+pub struct Recorder {
+    media_recorder: MediaRecorder,
+    // 録画はstopより後に届くので、届いた時にハンドラ自身がここから外して放す
+    ondataavailable: BlobHandler,
+}
+
+impl Recorder {
+    // MediaRecorderが使えないブラウザではErrを返す
+    pub fn start(canvas: &HtmlCanvasElement, file_name: &str) -> Result<Self> {
+        let stream = canvas
+            .capture_stream()
+            .map_err(|err| anyhow!("Could not capture canvas stream {:#?}", err))?;
+        let media_recorder = MediaRecorder::new_with_media_stream_and_media_recorder_options(
+            &stream,
+            MediaRecorderOptions::new().mime_type("video/webm"),
+        )
+        .map_err(|err| anyhow!("MediaRecorder is not supported {:#?}", err))?;
+
+        // time sliceを指定しないので、stopした時に録画全体が1つのBlobで届く
+        let file_name = file_name.to_string();
+        let handler: BlobHandler = Rc::new(RefCell::new(None));
+        let release = handler.clone();
+        let ondataavailable = closure_wrap(Box::new(move |event: BlobEvent| {
+            if let Some(blob) = event.data() {
+                if let Err(err) = Url::create_object_url_with_blob(&blob)
+                    .map_err(|err| anyhow!("Could not create object URL {:#?}", err))
+                    .and_then(|url| download(&url, &file_name))
+                {
+                    error!("{:#?}", err);
+                }
+            }
+            // 録画全体が1度で届くので、もう呼ばれない
+            release.borrow_mut().take();
+        }) as Box<dyn FnMut(BlobEvent)>);
+        media_recorder.set_ondataavailable(Some(ondataavailable.as_ref().unchecked_ref()));
+        *handler.borrow_mut() = Some(ondataavailable);
+
+        let recorder = Recorder {
+            media_recorder,
+            ondataavailable: handler,
+        };
+        if let Err(err) = recorder.media_recorder.start() {
+            recorder.release();
+            return Err(anyhow!("Could not start recording {:#?}", err));
+        }
+        Ok(recorder)
+    }
+
+    pub fn stop(self) -> Result<()> {
+        self.media_recorder.stop().map_err(|err| {
+            // 止められなければ録画は届かないので、ここで放す
+            self.release();
+            anyhow!("Could not stop recording {:#?}", err)
+        })
+    }
+
+    fn release(&self) {
+        self.media_recorder.set_ondataavailable(None);
+        self.ondataavailable.borrow_mut().take();
+    }
 }
@@ -0,0 +1,135 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::browser::Store;
+
+const DEATHS_KEY: &str = "walk_the_dog.deaths";
+// 古いものから捨てる
+const MAX_DEATHS: usize = 20;
+// この回数だけ近くで倒れたら、その辺りを易しくする
+const DEATHS_TO_EASE: usize = 3;
+// これ以内の距離で倒れたら同じ場所とみなす
+const NEARBY: i32 = 500;
+// 倒れる場所に差し掛かる前から易しくしておく距離
+const EASE_AHEAD: i32 = 1000;
+
+// これまでの走りで倒れた距離。ページを開き直しても残るようにlocalStorageに保存する
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DeathHistory {
+    deaths: Vec<i32>,
+}
+
+impl DeathHistory {
+    // 保存されていない時や読めない時は空にする
+    pub fn load(store: &dyn Store) -> Self {
+        store
+            .load(DEATHS_KEY)
+            .and_then(|json| {
+                json.map_or(Ok(DeathHistory::default()), |json| Self::from_json(&json))
+            })
+            .unwrap_or_else(|err| {
+                warn!("Could not read death history, starting over {:#?}", err);
+                DeathHistory::default()
+            })
+    }
+
+    pub fn save(&self, store: &dyn Store) -> Result<()> {
+        store.save(DEATHS_KEY, &serde_json::to_string(self)?)
+    }
+
+    fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str::<DeathHistory>(json)
+            .map_err(|err| anyhow!("Broken death history '{}': {}", json, err))
+    }
+
+    pub fn record(&mut self, distance: i32) {
+        self.deaths.push(distance);
+        if self.deaths.len() > MAX_DEATHS {
+            self.deaths.remove(0);
+        }
+    }
+
+    // DEATHS_TO_EASE回以上倒れた場所のうち、近くで倒れた回数が一番多いもの。
+    // 同じ回数なら先に倒れた方にする
+    pub fn hotspot(&self) -> Option<i32> {
+        self.deaths
+            .iter()
+            .rev()
+            .map(|&death| (self.deaths_near(death), death))
+            .filter(|(count, _)| *count >= DEATHS_TO_EASE)
+            .max_by_key(|(count, _)| *count)
+            .map(|(_, death)| death)
+    }
+
+    fn deaths_near(&self, distance: i32) -> usize {
+        self.deaths
+            .iter()
+            .filter(|death| (*death - distance).abs() <= NEARBY)
+            .count()
+    }
+
+    // この距離に置くセグメントを易しくするか
+    pub fn eases(&self, distance: i32) -> bool {
+        self.hotspot()
+            .is_some_and(|hotspot| (hotspot - EASE_AHEAD..=hotspot + NEARBY).contains(&distance))
+    }
+
+    // 倒れていた場所を越えたら、その辺りの記録を消して元の難しさに戻す。消したらtrue
+    pub fn pass(&mut self, distance: i32) -> bool {
+        match self.hotspot() {
+            Some(hotspot) if distance > hotspot + NEARBY => {
+                self.deaths
+                    .retain(|death| (*death - hotspot).abs() > NEARBY);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(deaths: &[i32]) -> DeathHistory {
+        let mut history = DeathHistory::default();
+        deaths.iter().for_each(|death| history.record(*death));
+        history
+    }
+
+    #[test]
+    fn eases_only_after_repeated_deaths_in_the_same_place() {
+        let scattered = history(&[1000, 3000, 5000, 7000]);
+        assert_eq!(scattered.hotspot(), None);
+        assert!(!scattered.eases(3000));
+
+        let stuck = history(&[3000, 8000, 3200, 2900]);
+        assert_eq!(stuck.hotspot(), Some(3000));
+        assert!(stuck.eases(2500));
+        assert!(stuck.eases(3400));
+        assert!(!stuck.eases(1000));
+        assert!(!stuck.eases(5000));
+    }
+
+    #[test]
+    fn passing_the_hotspot_resets_the_easing() {
+        let mut stuck = history(&[3000, 3200, 2900, 8000]);
+        assert!(!stuck.pass(3300));
+        assert!(stuck.pass(4000));
+        assert_eq!(stuck, history(&[8000]));
+        assert!(!stuck.eases(3000));
+    }
+
+    #[test]
+    fn keeps_only_the_latest_deaths() {
+        let many: Vec<i32> = (0..30).map(|index| index * 1000).collect();
+        let kept = history(&many);
+        assert_eq!(kept.deaths.len(), MAX_DEATHS);
+        assert_eq!(kept.deaths[0], 10000);
+        assert_eq!(
+            DeathHistory::from_json(&serde_json::to_string(&kept).unwrap()).unwrap(),
+            kept
+        );
+        assert!(DeathHistory::from_json("[1, 2]").is_err());
+    }
+}
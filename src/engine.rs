@@ -4,20 +4,24 @@ use futures::channel::{
     mpsc::{unbounded, UnboundedReceiver},
     oneshot::channel,
 };
+use gloo_utils::format::JsValueSerdeExt;
 use serde::Deserialize;
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     ops::{Add, AddAssign},
     rc::Rc,
     sync::Mutex,
 };
-use wasm_bindgen::JsCast;
-use web_sys::{CanvasRenderingContext2d, HtmlImageElement};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{
+    AudioBuffer, AudioBufferSourceNode, AudioContext, AudioContextState, CanvasRenderingContext2d,
+    GainNode, HtmlCanvasElement, HtmlImageElement,
+};
 
-use crate::browser;
+use crate::{browser, sound};
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
 pub struct Point {
     pub x: i16,
     pub y: i16,
@@ -52,11 +56,146 @@ pub struct SheetRect {
 pub struct Cell {
     pub frame: SheetRect,
     pub sprite_source_size: SheetRect,
+    // 余白を切り詰める前の絵の大きさ。切り詰めていないシートには無い
+    #[serde(default)]
+    pub source_size: Option<SheetSize>,
+    // Aseprite/TexturePackerの"duration"(ミリ秒)。無い場合は固定のフレーム間隔を使う
+    #[serde(default, rename = "duration")]
+    pub duration_ms: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SheetSize {
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SheetMeta {
+    pub size: Option<SheetSize>,
+    // TexturePackerは"1"のように文字列で出力する
+    pub scale: Option<String>,
+    // 別のツールで書き出したアトラスのフレーム名。無ければ"{name} ({n}).png"
+    #[serde(default, rename = "frameNames")]
+    pub frame_names: Option<FrameNamesMeta>,
+}
+
+// "default"は全アニメーションの書式、"animations"はアニメーションごとの書式
+#[derive(Deserialize, Clone, Default)]
+pub struct FrameNamesMeta {
+    #[serde(default)]
+    pub default: Option<FrameNameSpec>,
+    #[serde(default)]
+    pub animations: HashMap<String, FrameNameSpec>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameNameSpec {
+    pub template: String,
+    #[serde(default)]
+    pub first_index: Option<u16>,
+}
+
+impl FrameNameSpec {
+    pub fn format(&self) -> FrameNameFormat {
+        let format = FrameNameFormat::new(&self.template);
+        match self.first_index {
+            Some(first_index) => format.starting_at(first_index),
+            None => format,
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Sheet {
     pub frames: HashMap<String, Cell>,
+    #[serde(default)]
+    pub meta: Option<SheetMeta>,
+}
+
+impl Sheet {
+    // JSONに書かれた画像サイズと、実際に読み込んだ画像のサイズが違う場合に警告する。
+    // 大きさの分からない画像とは比べない
+    pub fn check_image_size(&self, image: &HtmlImageElement) {
+        if let Some(meta) = &self.meta {
+            let size = match &meta.size {
+                Some(size) => size,
+                None => return,
+            };
+            let (width, height) = image_size(image);
+            if width == 0 {
+                return;
+            }
+            if size.w != width || size.h != height {
+                warn!(
+                    "Sheet size {}x{} (scale {}) does not match image {} ({}x{})",
+                    size.w,
+                    size.h,
+                    meta.scale.as_deref().unwrap_or("1"),
+                    image.src(),
+                    width,
+                    height
+                );
+            }
+        }
+    }
+}
+
+// アトラスのフレーム名の書式。{name}はアニメーション名、{n}はフレーム番号に置き換わる。
+// {n:02}のように書くと0埋めした番号になる
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameNameFormat {
+    template: String,
+    first_index: u16,
+}
+
+impl Default for FrameNameFormat {
+    fn default() -> Self {
+        FrameNameFormat::new("{name} ({n}).png")
+    }
+}
+
+impl FrameNameFormat {
+    pub fn new(template: &str) -> Self {
+        FrameNameFormat {
+            template: template.to_string(),
+            first_index: 1,
+        }
+    }
+
+    // 番号が0から始まるアトラス用
+    pub fn starting_at(mut self, first_index: u16) -> Self {
+        self.first_index = first_index;
+        self
+    }
+
+    // indexはアニメーションの何枚目か(0始まり)
+    pub fn format(&self, name: &str, index: u16) -> String {
+        let number = index + self.first_index;
+        let mut result = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            result.push_str(&rest[..start]);
+            let placeholder = &rest[start..];
+            let end = match placeholder.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+            match &placeholder[1..end] {
+                "name" => result.push_str(name),
+                "n" => result.push_str(&number.to_string()),
+                spec if spec.starts_with("n:0") => {
+                    let width = spec[3..].parse().unwrap_or(0);
+                    result.push_str(&format!("{:0width$}", number, width = width));
+                }
+                _ => result.push_str(&placeholder[..=end]),
+            }
+            rest = &placeholder[end + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
 }
 
 pub struct SpriteSheet {
@@ -66,6 +205,7 @@ pub struct SpriteSheet {
 
 impl SpriteSheet {
     pub fn new(sheet: Sheet, image: HtmlImageElement) -> Self {
+        sheet.check_image_size(&image);
         SpriteSheet { sheet, image }
     }
 
@@ -74,77 +214,376 @@ impl SpriteSheet {
     }
 
     pub fn draw(&self, renderer: &Renderer, source: &Rect, destination: &Rect) {
-        renderer.draw_image(&self.image, source, destination);
+        renderer.draw_image_sub(&self.image, source, destination);
+    }
+
+    // 画像はそのままで、セルの位置だけを入れ替えたシートを作る
+    pub fn with_sheet(&self, sheet: Sheet) -> Self {
+        SpriteSheet::new(sheet, self.image.clone())
     }
 }
 
 #[async_trait(?Send)]
 pub trait Game {
     async fn initialize(&self) -> Result<Box<dyn Game>>;
-    fn update(&mut self, keystate: &KeyState);
+    // tickは固定ステップの更新ごとに1つ増える
+    fn update(&mut self, keystate: &KeyState, tick: u64);
+    // 描画フレームごとに実際の経過時間(ms)で呼ばれる。見た目だけの演出用で、
+    // 物理や当たり判定はupdateで行うこと
+    fn animate(&mut self, _delta: f32) {}
     fn draw(&self, context: &Renderer);
+    // 1フレーム分をrendererに描く。画面のcanvasと同じ描き方で、縮小したオフスクリーンのcanvasなどにも描ける
+    fn draw_to(&self, renderer: &Renderer) {
+        renderer.begin_frame();
+        self.draw(renderer);
+        renderer.end_frame();
+    }
+    // 固定ステップを進める速さの倍率。1.0なら実時間どおり
+    fn game_speed(&self) -> f32 {
+        1.0
+    }
+}
+
+pub const FRAME_SIZE: f32 = 1.0 / 60.0 * 1000.0;
+const SCREENSHOT_KEY: &str = "F2";
+const RECORD_KEY: &str = "F3";
+const PIXELATED_KEY: &str = "F4";
+// デバッグ用に一時停止し、停止中は"."で1フレームずつ進める
+const DEBUG_PAUSE_KEY: &str = "F8";
+const STEP_KEY: &str = "Period";
+// ?frametimes の時に、フレーム間隔の分布をログに出してから数え直す
+const FRAME_TIMES_KEY: &str = "F9";
+// 内部の解像度をRESOLUTIONSの順に切り替える
+const RESOLUTION_KEY: &str = "F10";
+const RESOLUTIONS: [f32; 3] = [1.0, 0.75, 0.5];
+
+// キーが押された瞬間のフレームだけtrueを返す
+pub struct KeyTrigger {
+    code: &'static str,
+    down: bool,
+}
+
+impl KeyTrigger {
+    pub fn new(code: &'static str) -> Self {
+        KeyTrigger { code, down: false }
+    }
+
+    pub fn triggered(&mut self, keystate: &KeyState) -> bool {
+        let pressed = keystate.is_pressed(self.code);
+        let triggered = pressed && !self.down;
+        self.down = pressed;
+        triggered
+    }
+}
+
+// 各バケットの上限(ms)。60fpsの1フレーム(16.7ms)は2番目に入る
+const FRAME_TIME_BUCKETS: [f32; 3] = [8.0, 16.7, 33.4];
+const FRAME_TIME_LABELS: [&str; 4] = ["<8ms", "8-16ms", "16-33ms", ">33ms"];
+
+// フレーム間隔の分布。固定長の配列に数えるだけなので、毎フレーム記録しても確保は起きない
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameHistogram {
+    counts: [u32; 4],
+}
+
+impl FrameHistogram {
+    pub fn record(&mut self, delta: f32) {
+        let bucket = FRAME_TIME_BUCKETS
+            .iter()
+            .position(|limit| delta < *limit)
+            .unwrap_or(FRAME_TIME_BUCKETS.len());
+        self.counts[bucket] += 1;
+    }
+
+    pub fn reset(&mut self) {
+        self.counts = [0; 4];
+    }
+}
+
+impl std::fmt::Display for FrameHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.counts.iter().sum::<u32>().max(1) as f32;
+        FRAME_TIME_LABELS
+            .iter()
+            .zip(self.counts)
+            .try_for_each(|(label, count)| {
+                write!(
+                    f,
+                    "{}: {} ({:.1}%) ",
+                    label,
+                    count,
+                    count as f32 / total * 100.0
+                )
+            })
+    }
+}
+
+// RESOLUTIONSに無い値からは最初に戻る
+fn next_resolution(current: f32) -> f32 {
+    RESOLUTIONS
+        .iter()
+        .position(|&factor| factor == current)
+        .map_or(RESOLUTIONS[0], |index| {
+            RESOLUTIONS[(index + 1) % RESOLUTIONS.len()]
+        })
+}
+
+// 表示の大きさのfactor倍のピクセル数。0にはしない
+fn backing_size(display_size: (u32, u32), factor: f32) -> (u32, u32) {
+    let scale = |length: u32| ((length as f32 * factor).round() as u32).max(1);
+    (scale(display_size.0), scale(display_size.1))
 }
 
-const FRAME_SIZE: f32 = 1.0 / 60.0 * 1000.0;
+// 最初のフレームは比べる時刻が無いので、ちょうど1ステップ分とする。
+// ロードにかかった時間を取り返そうとして、始まってすぐに何ステップも進まないようにする
+fn frame_delta(last_frame: Option<f64>, perf: f64) -> f32 {
+    match last_frame {
+        Some(last_frame) => (perf - last_frame) as f32,
+        None => FRAME_SIZE,
+    }
+}
 
 pub struct GameLoop {
-    last_frame: f64,
+    // まだ1度もフレームを処理していなければNone
+    last_frame: Option<f64>,
     accumulated_delta: f32,
+    tick: u64,
+    // ?frametimes を付けた時だけ集める
+    frame_times: Option<FrameHistogram>,
 }
 
 impl GameLoop {
-    pub async fn start(game: impl Game + 'static) -> Result<()> {
-        let mut keyevent_receiver = prepare_input()?;
+    fn record_frame_time(&mut self, delta: f32) {
+        if let Some(frame_times) = &mut self.frame_times {
+            frame_times.record(delta);
+        }
+    }
+
+    // 特定の場面だけを測れるよう、出力したら数え直す
+    fn log_frame_times(&mut self) {
+        if let Some(frame_times) = &mut self.frame_times {
+            info!("Frame times: {}", frame_times);
+            frame_times.reset();
+        }
+    }
+
+    pub async fn start(game: impl Game + 'static, renderer: Renderer) -> Result<()> {
+        let mut keyevent_receiver = prepare_input(&renderer.canvas()?)?;
         let mut game = game.initialize().await?;
         let mut game_loop = GameLoop {
-            last_frame: browser::now()?,
+            last_frame: None,
             accumulated_delta: 0.0,
+            tick: 0,
+            frame_times: browser::query_param("frametimes")?.map(|_| FrameHistogram::default()),
         };
-        let renderer = Renderer {
-            context: browser::context()?,
-        };
+        let mut pixelated = true;
+        renderer.set_pixelated(pixelated);
+        // ?scale=2 のようにしてドット絵を整数倍で拡大する
+        if let Some(scale) = browser::query_param("scale")?.and_then(|scale| scale.parse().ok()) {
+            renderer.set_scale(scale);
+        }
+        // ?resolution=0.5 のようにして、遅い端末では内部の解像度を下げる
+        if let Some(factor) =
+            browser::query_param("resolution")?.and_then(|factor| factor.parse().ok())
+        {
+            renderer.set_resolution(factor)?;
+        }
         let f = Rc::new(RefCell::new(None));
         let g = f.clone();
 
+        // ?profile を付けると、updateとdrawの時間をパフォーマンスパネルに記録する
+        let profiling = browser::query_param("profile")?.is_some();
+
         let mut keystate = KeyState::new();
+        let mut screenshot_key = KeyTrigger::new(SCREENSHOT_KEY);
+        let mut record_key = KeyTrigger::new(RECORD_KEY);
+        let mut pixelated_key = KeyTrigger::new(PIXELATED_KEY);
+        let mut debug_pause_key = KeyTrigger::new(DEBUG_PAUSE_KEY);
+        let mut step_key = KeyTrigger::new(STEP_KEY);
+        let mut frame_times_key = KeyTrigger::new(FRAME_TIMES_KEY);
+        let mut resolution_key = KeyTrigger::new(RESOLUTION_KEY);
+        let mut debug_paused = false;
+        let mut recorder: Option<browser::Recorder> = None;
+        let mut recording_supported = true;
+        let mut raf_available = true;
         *g.borrow_mut() = Some(browser::create_raf_closure(move |perf| {
+            // setTimeoutから呼ばれた時はタイムスタンプが無いので自分で測る。
+            // どちらもperformance.now()と同じ基準なのでlast_frameとの差はそのまま使える
+            let perf = if perf.is_nan() {
+                browser::now()
+                    .ok()
+                    .or(game_loop.last_frame)
+                    .unwrap_or_default()
+            } else {
+                perf
+            };
             process_input(&mut keystate, &mut keyevent_receiver);
-            game_loop.accumulated_delta += (perf - game_loop.last_frame) as f32;
+            // 1ステップの内容は変えずに、ステップの頻度だけを倍率で変える。
+            // 物理や当たり判定は速さによらず同じ結果になる
+            let real_delta = frame_delta(game_loop.last_frame, perf);
+            game_loop.record_frame_time(real_delta);
+            if frame_times_key.triggered(&keystate) {
+                game_loop.log_frame_times();
+            }
+            let frame_delta = real_delta * game.game_speed();
+            game_loop.accumulated_delta += frame_delta;
+            if profiling {
+                mark("update-start");
+            }
+            if debug_pause_key.triggered(&keystate) {
+                debug_paused = !debug_paused;
+            }
+            if debug_paused {
+                // 停止中は時間を貯めず、ステップの要求があった時だけ1回updateする
+                game_loop.accumulated_delta = 0.0;
+                if step_key.triggered(&keystate) {
+                    keystate.start_update();
+                    game.update(&keystate, game_loop.tick);
+                    game_loop.tick += 1;
+                }
+            }
             while game_loop.accumulated_delta > FRAME_SIZE {
-                game.update(&keystate);
+                keystate.start_update();
+                game.update(&keystate, game_loop.tick);
+                game_loop.tick += 1;
                 game_loop.accumulated_delta -= FRAME_SIZE;
             }
-            game_loop.last_frame = perf;
-            game.draw(&renderer);
-            browser::request_animation_frame(f.borrow().as_ref().unwrap()).unwrap();
+            game_loop.last_frame = Some(perf);
+            game.animate(frame_delta);
+            if profiling {
+                mark("update-end");
+                measure("update", "update-start", "update-end");
+                mark("draw-start");
+            }
+            game.draw_to(&renderer);
+            if profiling {
+                mark("draw-end");
+                measure("draw", "draw-start", "draw-end");
+            }
+            // 描画し終わったフレームを保存する
+            if screenshot_key.triggered(&keystate) {
+                if let Err(err) = renderer
+                    .canvas()
+                    .and_then(|canvas| browser::screenshot(&canvas))
+                    .and_then(|url| browser::download(&url, "screenshot.png"))
+                {
+                    error!("{:#?}", err);
+                }
+            }
+
+            if record_key.triggered(&keystate) && recording_supported {
+                match recorder.take() {
+                    Some(active) => {
+                        if let Err(err) = active.stop() {
+                            error!("{:#?}", err);
+                        }
+                    }
+                    None => match renderer
+                        .canvas()
+                        .and_then(|canvas| browser::Recorder::start(&canvas, "gameplay.webm"))
+                    {
+                        Ok(started) => recorder = Some(started),
+                        Err(err) => {
+                            warn!("Recording disabled: {:#?}", err);
+                            recording_supported = false;
+                        }
+                    },
+                }
+            }
+
+            // 拡大時のぼかしの有無を見比べられるように切り替える
+            if pixelated_key.triggered(&keystate) {
+                pixelated = !pixelated;
+                renderer.set_pixelated(pixelated);
+            }
+            if resolution_key.triggered(&keystate) {
+                let factor = next_resolution(renderer.resolution());
+                match renderer.set_resolution(factor) {
+                    Ok(()) => info!("Render resolution {}", factor),
+                    Err(err) => error!("{:#?}", err),
+                }
+            }
+            if let Err(err) = schedule_frame(f.borrow().as_ref().unwrap(), &mut raf_available) {
+                error!("Could not schedule the next frame {:#?}", err);
+            }
         }));
 
-        browser::request_animation_frame(
+        schedule_frame(
             g.borrow()
                 .as_ref()
                 .ok_or_else(|| anyhow!("GameLoop: Loop is None"))?,
+            &mut true,
         )?;
 
         Ok(())
     }
 }
 
+// 普段はrequestAnimationFrameで次のフレームを予約する。タブが隠れている時や
+// requestAnimationFrameが使えない時は、同じフレームレートのsetTimeoutで代わりに進める。
+// 1フレームが次のフレームを1回だけ予約するので、2つの仕組みで二重に進むことはない
+fn schedule_frame(callback: &browser::LoopClosure, raf_available: &mut bool) -> Result<()> {
+    if *raf_available && !browser::document_hidden() {
+        match browser::request_animation_frame(callback) {
+            Ok(_) => return Ok(()),
+            Err(err) => {
+                warn!("Falling back to setTimeout: {:#?}", err);
+                *raf_available = false;
+            }
+        }
+    }
+    browser::set_timeout(callback, FRAME_SIZE.round() as i32).map(|_| ())
+}
+
 pub struct Image {
-    element: HtmlImageElement,
+    element: Rc<HtmlImageElement>,
     bounding_box: Rect,
+    scale: f32,
 }
 
 impl Image {
-    pub fn new(element: HtmlImageElement, position: Point) -> Self {
-        let bounding_box = Rect::new(position, element.width() as i16, element.height() as i16);
+    pub fn new(element: Rc<HtmlImageElement>, position: Point) -> Self {
+        let (width, height) = image_size(&element);
+        let bounding_box = Rect::new(position, width as i16, height as i16);
         Self {
             element,
             bounding_box,
+            scale: 1.0,
         }
     }
 
+    // 左下を動かさずに拡大縮小するので、地面に置いた画像は地面に乗ったままになる
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        let anchor = Point {
+            x: self.bounding_box.x(),
+            y: self.bounding_box.bottom(),
+        };
+        self.bounding_box = self.bounding_box.scale_from(&anchor, scale / self.scale);
+        self.scale = scale;
+        self
+    }
+
+    // 拡大縮小した後の上端をyに合わせる。保存した場面から置き直す時に使う
+    pub fn with_top(mut self, y: i16) -> Self {
+        self.bounding_box.set_y(y);
+        self
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
     pub fn draw(&self, renderer: &Renderer) {
-        renderer.draw_entire_image(&self.element, &self.bounding_box.position);
+        if self.scale == 1.0 {
+            renderer.draw_entire_image(&self.element, &self.bounding_box.position);
+        } else {
+            let (width, height) = image_size(&self.element);
+            renderer.draw_image_rc(
+                &self.element,
+                &Rect::new_from_x_y(0, 0, width as i16, height as i16),
+                &self.bounding_box,
+            );
+        }
     }
 
     pub fn bounding_box(&self) -> &Rect {
@@ -162,20 +601,113 @@ impl Image {
     pub fn set_x(&mut self, x: i16) {
         self.bounding_box.set_x(x);
     }
+}
 
-    // pub fn set_y(&mut self, y: i16) {
-    //     self.bounding_box.set_y(y);
-    //     self.position.y += y;
-    // }
+// 画面の幅に足りない分だけ画像を横に並べて描画する。
+// offsetは何周分スクロールしても画像の幅で割った余りだけが使われる
+pub struct TiledImage {
+    element: HtmlImageElement,
+    y: i16,
+    view_width: i16,
+    // 遠くの背景は世界より遅く流れるので、端数も貯めておき描く時だけ丸める
+    offset: f32,
+    parallax: f32,
 }
 
-#[derive(Default)]
+impl TiledImage {
+    pub fn new(element: HtmlImageElement, y: i16, view_width: i16) -> Self {
+        TiledImage {
+            element,
+            y,
+            view_width,
+            offset: 0.0,
+            parallax: 1.0,
+        }
+    }
+
+    // 世界が1動く間にrateだけ動く。見た目だけで、当たり判定には関係しない
+    pub fn with_parallax(mut self, rate: f32) -> Self {
+        self.parallax = rate;
+        self
+    }
+
+    // 置き場所と流れる速さはそのままで、画像だけを替えたものを作る
+    pub fn with_element(&self, element: HtmlImageElement) -> Self {
+        TiledImage {
+            element,
+            y: self.y,
+            view_width: self.view_width,
+            offset: 0.0,
+            parallax: self.parallax,
+        }
+    }
+
+    pub fn move_horizontally(&mut self, distance: i16) {
+        self.offset = scroll_offset(self.offset, distance, self.parallax, self.tile_width());
+    }
+
+    pub fn draw(&self, renderer: &Renderer) {
+        // 読み込みが終わるまでは大きさが0で、何枚並べれば足りるかも分からないので描かない
+        if image_size(&self.element).0 == 0 {
+            return;
+        }
+        let offset = self.offset.round() as i32;
+        tile_positions(offset, self.tile_width(), self.view_width as i32).for_each(|x| {
+            renderer.draw_entire_image(
+                &self.element,
+                &Point {
+                    x: x as i16,
+                    y: self.y,
+                },
+            );
+        });
+    }
+
+    fn tile_width(&self) -> i32 {
+        (image_size(&self.element).0 as i32).max(1)
+    }
+}
+
+// 画像の幅で折り返すので、値は小さいまま保たれ、rateが1なら整数のまま端数が出ない
+fn scroll_offset(offset: f32, distance: i16, rate: f32, tile_width: i32) -> f32 {
+    (offset + distance as f32 * rate).rem_euclid(tile_width as f32)
+}
+
+// 画面に掛かるコピーの左端のx。何枚必要かは画面の幅と画像の幅から決まり、
+// どのコピーも前のコピーの右端から始まるので継ぎ目ができない
+fn tile_positions(offset: i32, tile_width: i32, view_width: i32) -> impl Iterator<Item = i32> {
+    // 左端の1枚は部分的に画面外に出るので1枚余分に描く
+    let copies = view_width / tile_width + 2;
+    let start = offset.rem_euclid(tile_width) - tile_width;
+    (0..copies)
+        .map(move |copy| start + copy * tile_width)
+        // 完全に画面の外に出ている分は描かない
+        .filter(move |x| *x < view_width && x + tile_width > 0)
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Side {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+// JSONではアトラスのframeと同じ{x, y, w, h}の形で書く
+#[derive(Clone, Default, Debug, PartialEq, Deserialize)]
+#[serde(from = "SheetRect")]
 pub struct Rect {
     pub position: Point,
     pub w: i16,
     pub h: i16,
 }
 
+impl From<SheetRect> for Rect {
+    fn from(rect: SheetRect) -> Self {
+        Rect::new_from_x_y(rect.x, rect.y, rect.w, rect.h)
+    }
+}
+
 impl Rect {
     pub fn new(position: Point, w: i16, h: i16) -> Self {
         Rect { position, w, h }
@@ -196,6 +728,49 @@ impl Rect {
             && self.bottom() > rect.y()
     }
 
+    // 重なっている幅と高さ。重なっていない場合は0以下になる
+    pub fn overlap_depth(&self, rect: &Rect) -> Point {
+        Point {
+            x: self.right().min(rect.right()) - self.x().max(rect.x()),
+            y: self.bottom().min(rect.bottom()) - self.y().max(rect.y()),
+        }
+    }
+
+    // anchorの位置を変えずにscale倍した矩形
+    pub fn scale_from(&self, anchor: &Point, scale: f32) -> Rect {
+        let scale_axis =
+            |value: i16, origin: i16| origin + ((value - origin) as f32 * scale).round() as i16;
+        let x = scale_axis(self.x(), anchor.x);
+        let y = scale_axis(self.y(), anchor.y);
+        Rect::new_from_x_y(
+            x,
+            y,
+            scale_axis(self.right(), anchor.x) - x,
+            scale_axis(self.bottom(), anchor.y) - y,
+        )
+    }
+
+    // selfのどの辺がrectに当たったか。重なりが小さい方の軸で判定し、
+    // 縦と横の重なりが同じ場合は縦(Top/Bottom)を優先する
+    pub fn collision_side(&self, rect: &Rect) -> Option<Side> {
+        let depth = self.overlap_depth(rect);
+        if depth.x <= 0 || depth.y <= 0 {
+            return None;
+        }
+        let side = if depth.x < depth.y {
+            if self.x() + self.right() < rect.x() + rect.right() {
+                Side::Right
+            } else {
+                Side::Left
+            }
+        } else if self.y() + self.bottom() < rect.y() + rect.bottom() {
+            Side::Bottom
+        } else {
+            Side::Top
+        };
+        Some(side)
+    }
+
     pub fn x(&self) -> i16 {
         self.position.x
     }
@@ -221,24 +796,228 @@ impl Rect {
     }
 }
 
+// Rendererが描いたものを、ピクセルではなく命令の列として表したもの
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum DrawCommand {
+    BeginFrame {
+        scale: f32,
+    },
+    EndFrame,
+    BeginCamera(Point),
+    EndCamera,
+    BeginFilter(String),
+    EndFilter,
+    Clear(Rect),
+    FillRect {
+        rect: Rect,
+        color: String,
+    },
+    // 画像はtest_imageで付けた名前で表す
+    DrawImage {
+        image: String,
+        frame: Rect,
+        destination: Rect,
+    },
+    DrawEntireImage {
+        image: String,
+        position: Point,
+    },
+    BeginPath,
+    MoveTo(Point),
+    LineTo(Point),
+    ClosePath,
+    Fill(String),
+    Stroke {
+        color: String,
+        width: f32,
+    },
+    Text {
+        text: String,
+        position: Point,
+    },
+    Error(String),
+    SetPixelated(bool),
+    SetAlpha(f64),
+}
+
+enum Target {
+    Canvas(CanvasRenderingContext2d),
+    #[cfg(test)]
+    Recording(RefCell<Vec<DrawCommand>>),
+}
+
+// 記録中(テストのみ)なら命令を記録してNoneを返す。canvasに描く時だけcontextを返す
+macro_rules! context {
+    ($renderer:expr, $command:expr) => {{
+        #[cfg(test)]
+        $renderer.record(|| $command);
+        $renderer.canvas_context()
+    }};
+}
+
 pub struct Renderer {
-    context: CanvasRenderingContext2d,
+    target: Target,
+    scale: std::cell::Cell<f32>,
+    // canvasの実際のピクセル数の、表示の大きさに対する比。1より小さいとCSSで拡大される
+    resolution: std::cell::Cell<f32>,
+    // 最初に解像度を変えた時のcanvasの大きさ。これを表示の大きさとしてCSSで固定する
+    display_size: std::cell::Cell<Option<(u32, u32)>>,
 }
 
+const ERROR_LINE_LENGTH: usize = 60;
+const ERROR_LINE_HEIGHT: i16 = 20;
+
 impl Renderer {
+    pub fn new(context: CanvasRenderingContext2d) -> Self {
+        Renderer {
+            target: Target::Canvas(context),
+            scale: std::cell::Cell::new(1.0),
+            resolution: std::cell::Cell::new(1.0),
+            display_size: std::cell::Cell::new(None),
+        }
+    }
+
+    fn canvas_context(&self) -> Option<&CanvasRenderingContext2d> {
+        match &self.target {
+            Target::Canvas(context) => Some(context),
+            #[cfg(test)]
+            Target::Recording(_) => None,
+        }
+    }
+
+    #[cfg(test)]
+    fn record(&self, command: impl FnOnce() -> DrawCommand) {
+        if let Target::Recording(commands) = &self.target {
+            commands.borrow_mut().push(command());
+        }
+    }
+
+    // 2や3を指定すると、全てのワールド座標がその倍率で描画される
+    pub fn set_scale(&self, factor: f32) {
+        self.scale.set(factor);
+    }
+
+    // canvasのピクセル数だけを表示の大きさのfactor倍にする。表示の大きさは変えないので、
+    // ワールド座標は解像度によらない
+    pub fn set_resolution(&self, factor: f32) -> Result<()> {
+        if !(factor.is_finite() && factor > 0.0) {
+            return Err(anyhow!("Invalid resolution {}", factor));
+        }
+        if let Some(context) = self.canvas_context() {
+            let canvas = self.canvas()?;
+            let display_size = match self.display_size.get() {
+                Some(display_size) => display_size,
+                None => (canvas.width(), canvas.height()),
+            };
+            browser::set_display_size(&canvas, display_size.0, display_size.1)?;
+            self.display_size.set(Some(display_size));
+            // 大きさを変えるとcontextの設定が初期化されるので、ドット絵の設定を戻す
+            let smoothing = context.image_smoothing_enabled();
+            let (width, height) = backing_size(display_size, factor);
+            canvas.set_width(width);
+            canvas.set_height(height);
+            self.set_pixelated(!smoothing);
+        }
+        self.resolution.set(factor);
+        Ok(())
+    }
+
+    pub fn resolution(&self) -> f32 {
+        self.resolution.get()
+    }
+
+    // 拡大はフレームごとにsave/restoreするので、clearも拡大後の範囲を覆う
+    pub fn begin_frame(&self) {
+        let scale = self.scale.get() * self.resolution.get();
+        if let Some(context) = context!(self, DrawCommand::BeginFrame { scale }) {
+            context.save();
+            if let Err(err) = context.scale(scale as f64, scale as f64) {
+                error!("Could not scale canvas {:#?}", err);
+            }
+        }
+    }
+
+    pub fn end_frame(&self) {
+        if let Some(context) = context!(self, DrawCommand::EndFrame) {
+            context.restore();
+        }
+    }
+
+    // end_cameraまでの描画をカメラの位置の分だけずらす
+    pub fn begin_camera(&self, camera: &Point) {
+        if let Some(context) = context!(self, DrawCommand::BeginCamera(*camera)) {
+            context.save();
+            if let Err(err) = context.translate(-camera.x as f64, -camera.y as f64) {
+                error!("Could not move camera {:#?}", err);
+            }
+        }
+    }
+
+    pub fn end_camera(&self) {
+        if let Some(context) = context!(self, DrawCommand::EndCamera) {
+            context.restore();
+        }
+    }
+
+    // end_filterまでの描画にCSSのfilter("hue-rotate(90deg)"など)をかける
+    pub fn begin_filter(&self, filter: &str) {
+        if let Some(context) = context!(self, DrawCommand::BeginFilter(filter.to_string())) {
+            context.save();
+            context.set_filter(filter);
+        }
+    }
+
+    pub fn end_filter(&self) {
+        if let Some(context) = context!(self, DrawCommand::EndFilter) {
+            context.restore();
+        }
+    }
+
     pub fn clear(&self, rect: &Rect) {
-        self.context.clear_rect(
-            rect.x().into(),
-            rect.y().into(),
-            rect.w.into(),
-            rect.h.into(),
-        );
+        if let Some(context) = context!(self, DrawCommand::Clear(rect.clone())) {
+            context.clear_rect(
+                rect.x().into(),
+                rect.y().into(),
+                rect.w.into(),
+                rect.h.into(),
+            );
+        }
+    }
+
+    // 透明にせず、指定した色で塗りつぶす
+    pub fn clear_with_color(&self, rect: &Rect, color: &str) {
+        self.fill_rect(rect, color);
     }
 
+    /// Draws the `frame` region of `image` into `destination`.
+    ///
+    /// ```ignore
+    /// renderer.draw_image(
+    ///     &image,
+    ///     &Rect::new_from_x_y(0, 0, 64, 64),
+    ///     &Rect::new_from_x_y(100, 200, 64, 64),
+    /// );
+    /// ```
     pub fn draw_image(&self, image: &HtmlImageElement, frame: &Rect, destination: &Rect) {
-        self.context
+        let context = match context!(
+            self,
+            DrawCommand::DrawImage {
+                image: image_name(image),
+                frame: frame.clone(),
+                destination: destination.clone(),
+            }
+        ) {
+            Some(context) => context,
+            None => return,
+        };
+        if !image_ready(image) {
+            return;
+        }
+        // 1枚描けなくてもゲームは止めず、その画像だけ描かずに進む
+        if let Err(err) = context
             .draw_image_with_html_image_element_and_sw_and_sh_and_dx_and_dy_and_dw_and_dh(
-                &image,
+                image,
                 frame.x().into(),
                 frame.y().into(),
                 frame.w.into(),
@@ -248,17 +1027,632 @@ impl Renderer {
                 destination.w.into(),
                 destination.h.into(),
             )
-            .expect("Drawing is throwing exceptions! Unrecoverable error.");
+        {
+            error!("Could not draw {} {:?}: {:#?}", image.src(), frame, err);
+        }
+    }
+
+    // sourceは画像の中の切り出す範囲、destinationはそれを伸ばして描く画面上の範囲。
+    // sourceが画像からはみ出していたら、canvasの例外になる前にログを出して描かない
+    pub fn draw_image_sub(&self, image: &HtmlImageElement, source: &Rect, destination: &Rect) {
+        // 記録する時は画像を読まずに、描くはずだった命令だけを残す
+        if self.canvas_context().is_none() {
+            self.draw_image(image, source, destination);
+            return;
+        }
+        if !image_ready(image) {
+            return;
+        }
+        let (width, height) = (image.natural_width(), image.natural_height());
+        if !source_within(source, width, height) {
+            error!(
+                "Source {:?} is outside {} ({}x{}), skipping it",
+                source,
+                image.src(),
+                width,
+                height
+            );
+            return;
+        }
+        self.draw_image(image, source, destination);
+    }
+
+    /// Same as [`Renderer::draw_image`], but for images shared through an `Rc`
+    /// so callers don't have to clone the element.
+    ///
+    /// ```ignore
+    /// let image = Rc::new(engine::load_image("Stone.png").await?);
+    /// renderer.draw_image_rc(
+    ///     &image,
+    ///     &Rect::new_from_x_y(0, 0, 90, 54),
+    ///     &Rect::new_from_x_y(210, 546, 90, 54),
+    /// );
+    /// ```
+    pub fn draw_image_rc(&self, image: &Rc<HtmlImageElement>, frame: &Rect, destination: &Rect) {
+        self.draw_image(image.as_ref(), frame, destination);
+    }
+
+    // 角はcornerの大きさのまま、辺と中央だけを伸ばしてパネルを描く。
+    // 元と同じ大きさならdraw_imageと全く同じに描く
+    pub fn draw_nine_slice(
+        &self,
+        image: &HtmlImageElement,
+        source: &Rect,
+        corner: i16,
+        destination: &Rect,
+    ) {
+        if source.w == destination.w && source.h == destination.h {
+            self.draw_image(image, source, destination);
+            return;
+        }
+        nine_slice(source, corner, destination)
+            .iter()
+            .for_each(|(frame, destination)| self.draw_image(image, frame, destination));
+    }
+
+    pub fn fill_rect(&self, rect: &Rect, color: &str) {
+        if let Some(context) = context!(
+            self,
+            DrawCommand::FillRect {
+                rect: rect.clone(),
+                color: color.to_string(),
+            }
+        ) {
+            context.set_fill_style(&JsValue::from_str(color));
+            context.fill_rect(
+                rect.x().into(),
+                rect.y().into(),
+                rect.w.into(),
+                rect.h.into(),
+            );
+        }
+    }
+
+    // パスの色や線の太さはfillとstrokeの中だけで設定して戻すので、
+    // スプライトの描画には影響しない
+    pub fn begin_path(&self) {
+        if let Some(context) = context!(self, DrawCommand::BeginPath) {
+            context.begin_path();
+        }
+    }
+
+    pub fn move_to(&self, point: &Point) {
+        if let Some(context) = context!(self, DrawCommand::MoveTo(*point)) {
+            context.move_to(point.x.into(), point.y.into());
+        }
+    }
+
+    pub fn line_to(&self, point: &Point) {
+        if let Some(context) = context!(self, DrawCommand::LineTo(*point)) {
+            context.line_to(point.x.into(), point.y.into());
+        }
+    }
+
+    pub fn close_path(&self) {
+        if let Some(context) = context!(self, DrawCommand::ClosePath) {
+            context.close_path();
+        }
+    }
+
+    pub fn fill(&self, color: &str) {
+        if let Some(context) = context!(self, DrawCommand::Fill(color.to_string())) {
+            context.save();
+            context.set_fill_style(&JsValue::from_str(color));
+            context.fill();
+            context.restore();
+        }
+    }
+
+    pub fn stroke(&self, color: &str, width: f32) {
+        if let Some(context) = context!(
+            self,
+            DrawCommand::Stroke {
+                color: color.to_string(),
+                width,
+            }
+        ) {
+            context.save();
+            context.set_stroke_style(&JsValue::from_str(color));
+            context.set_line_width(width.into());
+            context.stroke();
+            context.restore();
+        }
+    }
+
+    // 文字はpositionを中心に描画する
+    pub fn draw_text(&self, text: &str, position: &Point) {
+        let context = match context!(
+            self,
+            DrawCommand::Text {
+                text: text.to_string(),
+                position: *position,
+            }
+        ) {
+            Some(context) => context,
+            None => return,
+        };
+        context.set_font("32px sans-serif");
+        context.set_text_align("center");
+        context.set_fill_style(&JsValue::from_str("#ffffff"));
+        if let Err(err) = context.fill_text(text, position.x.into(), position.y.into()) {
+            error!("Could not draw text {:#?}", err);
+        }
+    }
+
+    pub fn canvas(&self) -> Result<HtmlCanvasElement> {
+        match &self.target {
+            Target::Canvas(context) => context
+                .canvas()
+                .ok_or_else(|| anyhow!("Context is not attached to a canvas")),
+            #[cfg(test)]
+            Target::Recording(_) => Err(anyhow!("A recording renderer has no canvas")),
+        }
+    }
+
+    // ゲームを開始できなかった理由を画面に表示する
+    pub fn draw_error(&self, message: &str) {
+        let context = match context!(self, DrawCommand::Error(message.to_string())) {
+            Some(context) => context,
+            None => return,
+        };
+        let width = context
+            .canvas()
+            .map_or(600.0, |canvas| canvas.width() as f64);
+        let height = context
+            .canvas()
+            .map_or(600.0, |canvas| canvas.height() as f64);
+        context.set_fill_style(&JsValue::from_str("#202020"));
+        context.fill_rect(0.0, 0.0, width, height);
+
+        context.set_font("16px monospace");
+        context.set_text_align("left");
+        context.set_fill_style(&JsValue::from_str("#ff6060"));
+        let chars: Vec<char> = message.chars().collect();
+        chars
+            .chunks(ERROR_LINE_LENGTH)
+            .enumerate()
+            .for_each(|(line, text)| {
+                let text: String = text.iter().collect();
+                let y = ERROR_LINE_HEIGHT * (line as i16 + 1);
+                if let Err(err) = context.fill_text(&text, 10.0, y.into()) {
+                    error!("Could not draw error {:#?}", err);
+                }
+            });
+    }
+
+    // ドット絵を拡大してもぼやけないように、画像の補間を切る
+    pub fn set_pixelated(&self, enabled: bool) {
+        let context = match context!(self, DrawCommand::SetPixelated(enabled)) {
+            Some(context) => context,
+            None => return,
+        };
+        context.set_image_smoothing_enabled(!enabled);
+        // 古いブラウザ向けのベンダープレフィックス付きのプロパティ。無ければ何もしない
+        [
+            "mozImageSmoothingEnabled",
+            "webkitImageSmoothingEnabled",
+            "msImageSmoothingEnabled",
+        ]
+        .iter()
+        .for_each(|property| {
+            let _ = js_sys::Reflect::set(
+                context,
+                &JsValue::from_str(property),
+                &JsValue::from_bool(!enabled),
+            );
+        });
+    }
+
+    pub fn set_alpha(&self, alpha: f64) {
+        if let Some(context) = context!(self, DrawCommand::SetAlpha(alpha)) {
+            context.set_global_alpha(alpha);
+        }
     }
 
     pub fn draw_entire_image(&self, image: &HtmlImageElement, position: &Point) {
-        self.context
-            .draw_image_with_html_image_element(image, position.x.into(), position.y.into())
-            .expect("Drawing is throwing exceptions! Unrecoverable error.");
+        if let Some(context) = context!(
+            self,
+            DrawCommand::DrawEntireImage {
+                image: image_name(image),
+                position: *position,
+            }
+        ) {
+            if !image_ready(image) {
+                return;
+            }
+            if let Err(err) = context.draw_image_with_html_image_element(
+                image,
+                position.x.into(),
+                position.y.into(),
+            ) {
+                error!("Could not draw {}: {:#?}", image.src(), err);
+            }
+        }
     }
 }
 
+#[cfg(not(test))]
+pub fn image_size(image: &HtmlImageElement) -> (u32, u32) {
+    (image.width(), image.height())
+}
+
+// テストはJSを呼べないので、test_imageで覚えておいた大きさを返す
+#[cfg(test)]
+pub fn image_size(image: &HtmlImageElement) -> (u32, u32) {
+    test_image_info(image).map_or((0, 0), |(_, width, height)| (width, height))
+}
+
+// テストで使う画像の名前と大きさ。番号はJSのオブジェクトの代わりにJsValueに持たせる
+#[cfg(test)]
+thread_local! {
+    static TEST_IMAGES: RefCell<Vec<(String, u32, u32)>> = RefCell::new(vec![]);
+}
+
+// 中身の無い画像を作る。128未満の番号のJsValueは、捨てる時もJSを呼ばない
+#[cfg(test)]
+pub fn test_image(name: &str, width: u32, height: u32) -> HtmlImageElement {
+    use wasm_bindgen::convert::FromWasmAbi;
+    let index = TEST_IMAGES.with(|images| {
+        let mut images = images.borrow_mut();
+        images.push((name.to_string(), width, height));
+        images.len() as u32
+    });
+    assert!(index < 128, "Too many test images");
+    unsafe { JsValue::from_abi(index) }.unchecked_into()
+}
+
+#[cfg(test)]
+fn test_image_info(image: &HtmlImageElement) -> Option<(String, u32, u32)> {
+    use wasm_bindgen::convert::IntoWasmAbi;
+    let value: &JsValue = image.as_ref();
+    let index = value.into_abi() as usize;
+    TEST_IMAGES.with(|images| {
+        index
+            .checked_sub(1)
+            .and_then(|i| images.borrow().get(i).cloned())
+    })
+}
+
+// 記録の中で画像を見分ける名前。test_imageで作っていない画像は"?"になる
+#[cfg(test)]
+fn image_name(image: &HtmlImageElement) -> String {
+    test_image_info(image).map_or_else(|| "?".to_string(), |(name, _, _)| name)
+}
+
+// デコードが終わる前の画像は、そのフレームだけ描かずに飛ばす
+fn image_ready(image: &HtmlImageElement) -> bool {
+    image.complete() && image.natural_width() > 0
+}
+
+fn source_within(source: &Rect, width: u32, height: u32) -> bool {
+    source.x() >= 0
+        && source.y() >= 0
+        && source.w > 0
+        && source.h > 0
+        && source.right() as i64 <= width as i64
+        && source.bottom() as i64 <= height as i64
+}
+
+// 区切りの座標を整数で求めて隣のスライスと共有するので、丸めで隙間ができない
+fn slice_edges(start: i16, length: i16, corner: i16) -> [i16; 4] {
+    [
+        start,
+        start + corner,
+        start + length - corner,
+        start + length,
+    ]
+}
+
+// 9つのスライスの(元の範囲, 描く範囲)を返す。幅や高さが0のスライスは除く
+fn nine_slice(source: &Rect, corner: i16, destination: &Rect) -> Vec<(Rect, Rect)> {
+    // 角同士が重ならないように、どちらの矩形にも収まる大きさにする
+    let corner = corner.clamp(
+        0,
+        source.w.min(source.h).min(destination.w).min(destination.h) / 2,
+    );
+    let source_xs = slice_edges(source.x(), source.w, corner);
+    let source_ys = slice_edges(source.y(), source.h, corner);
+    let destination_xs = slice_edges(destination.x(), destination.w, corner);
+    let destination_ys = slice_edges(destination.y(), destination.h, corner);
+    let span = |edges: &[i16; 4], index: usize| (edges[index], edges[index + 1] - edges[index]);
+
+    (0..3)
+        .flat_map(|row| (0..3).map(move |column| (row, column)))
+        .filter_map(|(row, column)| {
+            let (source_x, source_w) = span(&source_xs, column);
+            let (source_y, source_h) = span(&source_ys, row);
+            let (destination_x, destination_w) = span(&destination_xs, column);
+            let (destination_y, destination_h) = span(&destination_ys, row);
+            if source_w <= 0 || source_h <= 0 || destination_w <= 0 || destination_h <= 0 {
+                return None;
+            }
+            Some((
+                Rect::new_from_x_y(source_x, source_y, source_w, source_h),
+                Rect::new_from_x_y(destination_x, destination_y, destination_w, destination_h),
+            ))
+        })
+        .collect()
+}
+
+// canvasに描く代わりにDrawCommandを記録する。
+// ピクセルを見ないので、ブラウザ無しで描画の回帰テストができる
+#[cfg(test)]
+pub struct RecordingRenderer {
+    renderer: Renderer,
+}
+
+#[cfg(test)]
+impl RecordingRenderer {
+    pub fn new() -> Self {
+        RecordingRenderer {
+            renderer: Renderer {
+                target: Target::Recording(RefCell::new(vec![])),
+                scale: std::cell::Cell::new(1.0),
+                resolution: std::cell::Cell::new(1.0),
+                display_size: std::cell::Cell::new(None),
+            },
+        }
+    }
+
+    pub fn renderer(&self) -> &Renderer {
+        &self.renderer
+    }
+
+    // これまでに記録した命令を取り出して、記録を空にする
+    pub fn take_commands(&self) -> Vec<DrawCommand> {
+        match &self.renderer.target {
+            Target::Recording(commands) => commands.take(),
+            Target::Canvas(_) => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for RecordingRenderer {
+    fn default() -> Self {
+        RecordingRenderer::new()
+    }
+}
+
+// ゲーム全体をゆっくり(または速く)動かすための倍率
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GameSpeed(f32);
+
+impl GameSpeed {
+    pub const MIN: f32 = 0.5;
+    pub const MAX: f32 = 1.5;
+    const STEP: f32 = 0.25;
+
+    pub fn new(speed: f32) -> Self {
+        if speed.is_finite() {
+            GameSpeed(speed.clamp(Self::MIN, Self::MAX))
+        } else {
+            GameSpeed::default()
+        }
+    }
+
+    pub fn value(&self) -> f32 {
+        self.0
+    }
+
+    pub fn slower(&self) -> Self {
+        GameSpeed::new(self.0 - Self::STEP)
+    }
+
+    pub fn faster(&self) -> Self {
+        GameSpeed::new(self.0 + Self::STEP)
+    }
+}
+
+impl Default for GameSpeed {
+    fn default() -> Self {
+        GameSpeed(1.0)
+    }
+}
+
+// Music::fade_toで音量を変えるのにかける時間
+const MUSIC_RAMP_SECONDS: f64 = 0.05;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioSettings {
+    pub muted: bool,
+    pub volume: f32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            muted: false,
+            volume: 1.0,
+        }
+    }
+}
+
+impl AudioSettings {
+    // 壊れた音量は既定値にする
+    pub fn new(muted: bool, volume: f32) -> Self {
+        let volume = if volume.is_finite() {
+            volume.clamp(0.0, 1.0)
+        } else {
+            AudioSettings::default().volume
+        };
+        AudioSettings { muted, volume }
+    }
+
+    fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume
+        }
+    }
+}
+
+// AudioContextが作れなかった時はoutputが無く、何も鳴らさない
+#[derive(Clone)]
+pub struct Audio {
+    output: Option<AudioOutput>,
+    settings: Rc<std::cell::Cell<AudioSettings>>,
+    // タブの表示が変わった時のリスナー。最後のAudioが無くなった時に外れる
+    visibility: Rc<RefCell<Option<browser::EventListener>>>,
+}
+
+#[derive(Clone)]
+struct AudioOutput {
+    context: AudioContext,
+    master: GainNode,
+}
+
+#[derive(Clone)]
+pub struct Sound {
+    buffer: AudioBuffer,
+}
+
+// 鳴っている曲。ミュートと全体の音量はマスターの側で掛かる
+pub struct Music {
+    context: AudioContext,
+    gain: GainNode,
+    source: AudioBufferSourceNode,
+}
+
+impl Music {
+    // 毎フレーム呼ばれても音が跳ねないよう、値は少しの時間をかけて変える
+    pub fn fade_to(&self, gain: f32) -> Result<()> {
+        sound::ramp_gain(&self.context, &self.gain, gain, MUSIC_RAMP_SECONDS)
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        sound::stop_sound(&self.source)
+    }
+}
+
+impl Audio {
+    // 最初の音が鳴る前に、保存されているミュートと音量を反映しておく
+    pub fn new(settings: AudioSettings) -> Result<Self> {
+        let context = sound::create_audio_context()?;
+        let master = sound::create_master_gain(&context)?;
+        let audio = Audio {
+            output: Some(AudioOutput { context, master }),
+            ..Audio::silent(settings)
+        };
+        audio.apply_gain();
+        Ok(audio)
+    }
+
+    // 音が出せない環境でもゲームは続けられるように、何も鳴らさないAudio
+    pub fn silent(settings: AudioSettings) -> Self {
+        Audio {
+            output: None,
+            settings: Rc::new(std::cell::Cell::new(settings)),
+            visibility: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    fn output(&self) -> Result<&AudioOutput> {
+        self.output
+            .as_ref()
+            .ok_or_else(|| anyhow!("Audio is not available"))
+    }
+
+    // ミュートを解除すると、ミュート前の音量に戻る。保存は設定を変えた側で行う
+    pub fn set_settings(&self, settings: AudioSettings) {
+        self.settings.set(settings);
+        self.apply_gain();
+    }
+
+    #[cfg(test)]
+    pub fn gain(&self) -> f32 {
+        self.settings.get().gain()
+    }
+
+    fn apply_gain(&self) {
+        if let Some(output) = &self.output {
+            output.master.gain().set_value(self.settings.get().gain());
+        }
+    }
+
+    pub async fn load_sound(&self, filename: &str) -> Result<Sound> {
+        let output = self.output()?;
+        let array_buffer = browser::fetch_array_buffer(filename).await?;
+        let audio_buffer = sound::decode_audio_data(&output.context, &array_buffer).await?;
+        Ok(Sound {
+            buffer: audio_buffer,
+        })
+    }
+
+    // 音量を後から変えられるように、曲ごとのGainNodeを挟んで繰り返し鳴らす
+    pub fn play_music(&self, sound: &Sound, gain: f32) -> Result<Music> {
+        let output = self.output()?;
+        let node = sound::create_gain(&output.context, &output.master)?;
+        node.gain().set_value(gain);
+        let source = sound::play_looping_sound(&output.context, &node, &sound.buffer)?;
+        Ok(Music {
+            context: output.context.clone(),
+            gain: node,
+            source,
+        })
+    }
+
+    // タブが隠れている間はAudioContextごと止めて、戻ったら再開する。
+    // 自動再生の制限でまだ止まっているAudioContextは、こちらで止めたものではないので再開しない
+    pub fn pause_while_hidden(&self) -> Result<()> {
+        let context = match &self.output {
+            Some(output) => output.context.clone(),
+            None => return Ok(()),
+        };
+        let mut suspended_by_us = false;
+        let listener = browser::on_visibility_change(move || {
+            let result = if browser::document_hidden() {
+                if context.state() == AudioContextState::Running {
+                    suspended_by_us = true;
+                    sound::suspend(&context)
+                } else {
+                    Ok(())
+                }
+            } else if suspended_by_us {
+                suspended_by_us = false;
+                sound::resume(&context)
+            } else {
+                Ok(())
+            };
+            if let Err(err) = result {
+                error!("{:#?}", err);
+            }
+        })?;
+        *self.visibility.borrow_mut() = Some(listener);
+        Ok(())
+    }
+}
+
+thread_local! {
+    // Assets::prefetchで読み込み終わったもの。URLごとに1つだけ持つ
+    static PREFETCHED: RefCell<Prefetched> = RefCell::new(Prefetched::default());
+}
+
+#[derive(Default)]
+struct Prefetched {
+    images: HashMap<String, HtmlImageElement>,
+    json: HashMap<String, JsValue>,
+}
+
+// 先読み済みならすぐに返す
 pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
+    if let Some(image) =
+        PREFETCHED.with(|prefetched| prefetched.borrow().images.get(source).cloned())
+    {
+        return Ok(image);
+    }
+    download_image(source).await
+}
+
+pub async fn load_json(path: &str) -> Result<JsValue> {
+    if let Some(json) = PREFETCHED.with(|prefetched| prefetched.borrow().json.get(path).cloned()) {
+        return Ok(json);
+    }
+    browser::fetch_json(path).await
+}
+
+async fn download_image(source: &str) -> Result<HtmlImageElement> {
     let image = browser::new_image()?;
 
     let (complete_tx, complete_rx) = channel::<Result<()>>();
@@ -285,36 +1679,279 @@ pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
     Ok(image)
 }
 
+#[derive(Deserialize)]
+pub struct SheetSource {
+    pub json: String,
+    pub image: String,
+}
+
+#[derive(Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub images: HashMap<String, String>,
+    #[serde(default)]
+    pub sheets: HashMap<String, SheetSource>,
+}
+
+impl Manifest {
+    // シートの画像も単独の画像と同じ表に入るので、名前が重なると片方が黙って上書きされる
+    fn check_names(&self) -> Result<()> {
+        let mut duplicates: Vec<&str> = self
+            .sheets
+            .keys()
+            .filter(|name| self.images.contains_key(*name))
+            .map(String::as_str)
+            .collect();
+        if duplicates.is_empty() {
+            return Ok(());
+        }
+        duplicates.sort_unstable();
+        Err(anyhow!(
+            "The asset manifest uses {} as both an image and a sheet name",
+            duplicates.join(", ")
+        ))
+    }
+}
+
+pub struct Assets {
+    images: HashMap<String, HtmlImageElement>,
+    sheets: HashMap<String, Sheet>,
+    // 読み直す時のために、シートのJSONの場所と読み込んだ時の中身を覚えておく
+    sheet_sources: HashMap<String, (String, String)>,
+}
+
+impl Assets {
+    pub async fn load_manifest(manifest_path: &str) -> Result<Self> {
+        let manifest = load_json(manifest_path).await?.into_serde::<Manifest>()?;
+        manifest.check_names()?;
+
+        let mut images = HashMap::new();
+        let mut sheets = HashMap::new();
+        let mut sheet_sources = HashMap::new();
+        for (name, source) in manifest.images {
+            images.insert(name, load_image(&source).await?);
+        }
+        for (name, source) in manifest.sheets {
+            let json = load_json(&source.json).await?;
+            sheets.insert(name.clone(), json.into_serde::<Sheet>()?);
+            sheet_sources.insert(name.clone(), (source.json, stringify(&json)?));
+            images.insert(name, load_image(&source.image).await?);
+        }
+
+        Ok(Assets {
+            images,
+            sheets,
+            sheet_sources,
+        })
+    }
+
+    // 開発用。シートのJSONを定期的に読み直し、中身が変わったものをSheetReloaderに渡す
+    pub fn watch_sheets(&self) -> Result<SheetReloader> {
+        let changed = Rc::new(RefCell::new(HashMap::new()));
+        let sources = Rc::new(RefCell::new(self.sheet_sources.clone()));
+        let pending = changed.clone();
+        let callback = browser::closure_wrap(Box::new(move || {
+            let names: Vec<String> = sources.borrow().keys().cloned().collect();
+            names.into_iter().for_each(|name| {
+                let sources = sources.clone();
+                let pending = pending.clone();
+                browser::spawn_local(async move {
+                    if let Err(err) = reload_sheet(&name, &sources, &pending).await {
+                        warn!("Could not reload sheet '{}': {:#}", name, err);
+                    }
+                });
+            });
+        }) as Box<dyn FnMut()>);
+        browser::set_interval(&callback, SHEET_RELOAD_INTERVAL)?;
+        // ページを閉じるまで動かし続ける
+        callback.forget();
+        Ok(SheetReloader { changed })
+    }
+
+    // 待たずに読み込みを始め、後のload_imageやload_jsonですぐに返せるようにしておく。
+    // 失敗したものは覚えないので、実際に使う時にもう一度読み込まれる
+    pub fn prefetch(urls: &[&str]) {
+        urls.iter().for_each(|url| {
+            let url = url.to_string();
+            browser::spawn_local(async move {
+                if let Err(err) = prefetch(&url).await {
+                    warn!("Could not prefetch {}: {:#}", url, err);
+                }
+            });
+        });
+    }
+
+    pub fn image(&self, name: &str) -> Result<&HtmlImageElement> {
+        self.images
+            .get(name)
+            .ok_or_else(|| anyhow!("No image named '{}' in the asset manifest", name))
+    }
+
+    pub fn sheet(&self, name: &str) -> Result<&Sheet> {
+        self.sheets
+            .get(name)
+            .ok_or_else(|| anyhow!("No sheet named '{}' in the asset manifest", name))
+    }
+}
+
+const SHEET_RELOAD_INTERVAL: i32 = 1000;
+
+pub struct SheetReloader {
+    changed: Rc<RefCell<HashMap<String, Sheet>>>,
+}
+
+impl SheetReloader {
+    // 前回から読み直されたシートを名前ごとに取り出す
+    pub fn take_changed(&self) -> HashMap<String, Sheet> {
+        self.changed.take()
+    }
+}
+
+fn stringify(json: &JsValue) -> Result<String> {
+    js_sys::JSON::stringify(json)
+        .map(String::from)
+        .map_err(|err| anyhow!("Could not serialize JSON {:#?}", err))
+}
+
+async fn reload_sheet(
+    name: &str,
+    sources: &RefCell<HashMap<String, (String, String)>>,
+    changed: &RefCell<HashMap<String, Sheet>>,
+) -> Result<()> {
+    let path = match sources.borrow().get(name) {
+        Some((path, _)) => path.clone(),
+        None => return Ok(()),
+    };
+    // ブラウザのキャッシュを使わずに取りに行く
+    let json = browser::fetch_json(&format!("{}?reload={}", path, browser::now()? as u64)).await?;
+    let contents = stringify(&json)?;
+    if let Some((_, previous)) = sources.borrow_mut().get_mut(name) {
+        if *previous == contents {
+            return Ok(());
+        }
+        // 壊れたJSONでも、直るまでは同じ中身で何度も警告しない
+        *previous = contents;
+    }
+    let sheet = json.into_serde::<Sheet>()?;
+    info!("Reloaded sheet '{}' from {}", name, path);
+    changed.borrow_mut().insert(name.to_string(), sheet);
+    Ok(())
+}
+
+async fn prefetch(url: &str) -> Result<()> {
+    if url.ends_with(".json") {
+        let json = browser::fetch_json(url).await?;
+        PREFETCHED.with(|prefetched| prefetched.borrow_mut().json.insert(url.to_string(), json));
+    } else {
+        let image = download_image(url).await?;
+        // 読み込みに失敗した画像も完了扱いになるので、大きさで確かめる
+        if image.natural_width() == 0 {
+            return Err(anyhow!("Image {} is empty or failed to load", url));
+        }
+        PREFETCHED.with(|prefetched| {
+            prefetched
+                .borrow_mut()
+                .images
+                .insert(url.to_string(), image)
+        });
+    }
+    Ok(())
+}
+
 enum KeyPress {
     KeyUp(web_sys::KeyboardEvent),
     KeyDown(web_sys::KeyboardEvent),
 }
 
 pub struct KeyState {
-    pressed_keys: HashMap<String, web_sys::KeyboardEvent>,
+    pressed_keys: HashSet<String>,
+    // 押された順に貯めておき、updateの直前に1回だけ取り出す
+    queued_presses: VecDeque<String>,
+    presses: HashSet<String>,
+    // 文字入力用。codeではなくkeyの値を、キーリピートも含めて押された順に貯める
+    queued_keys: VecDeque<String>,
+    typed_keys: Vec<String>,
 }
 
 impl KeyState {
-    fn new() -> Self {
+    pub fn new() -> Self {
         KeyState {
-            pressed_keys: HashMap::new(),
+            pressed_keys: HashSet::new(),
+            queued_presses: VecDeque::new(),
+            presses: HashSet::new(),
+            queued_keys: VecDeque::new(),
+            typed_keys: vec![],
         }
     }
 
     pub fn is_pressed(&self, code: &str) -> bool {
-        self.pressed_keys.contains_key(code)
+        self.pressed_keys.contains(code)
+    }
+
+    // 前のupdateから今回のupdateまでの間に押されたか。
+    // フレームの間に押して離した短いタップも取りこぼさず、
+    // 1フレームに複数回updateする時も最初の1回だけtrueになる
+    pub fn was_pressed(&self, code: &str) -> bool {
+        self.presses.contains(code)
+    }
+
+    // 前のupdateから今回のupdateまでに押されたキーのkeyの値("a"や"Backspace"など)
+    pub fn typed_keys(&self) -> &[String] {
+        &self.typed_keys
+    }
+
+    // updateを呼ぶ直前に1回呼ぶ
+    pub fn start_update(&mut self) {
+        self.presses = self.queued_presses.drain(..).collect();
+        self.typed_keys = self.queued_keys.drain(..).collect();
     }
 
     fn set_pressed(&mut self, code: &str, event: web_sys::KeyboardEvent) {
-        self.pressed_keys.insert(code.into(), event);
+        // 押しっぱなしによるキーリピートは新しい入力として数えない
+        if !event.repeat() {
+            self.queued_presses.push_back(code.into());
+        }
+        self.queued_keys.push_back(event.key());
+        self.pressed_keys.insert(code.into());
     }
 
     fn set_released(&mut self, code: &str) {
-        self.pressed_keys.remove(code.into());
+        self.pressed_keys.remove(code);
+    }
+
+    // テストで、キーボードの代わりに決まった入力を与える
+    #[cfg(test)]
+    pub fn press(&mut self, code: &str) {
+        self.queued_presses.push_back(code.into());
+        self.pressed_keys.insert(code.into());
+    }
+
+    #[cfg(test)]
+    pub fn release(&mut self, code: &str) {
+        self.set_released(code);
+    }
+}
+
+impl Default for KeyState {
+    fn default() -> Self {
+        KeyState::new()
+    }
+}
+
+fn mark(name: &str) {
+    if let Err(err) = browser::perf_mark(name) {
+        error!("{:#?}", err);
+    }
+}
+
+fn measure(name: &str, start: &str, end: &str) {
+    if let Err(err) = browser::perf_measure(name, start, end) {
+        error!("{:#?}", err);
     }
 }
 
-fn prepare_input() -> Result<UnboundedReceiver<KeyPress>> {
+// キー入力はそのcanvasだけで受け取るので、複数のゲームを1つのページに置いても混ざらない
+fn prepare_input(canvas: &HtmlCanvasElement) -> Result<UnboundedReceiver<KeyPress>> {
     let (keydown_sender, keyevent_receiver) = unbounded();
     let keydown_sender = Rc::new(RefCell::new(keydown_sender));
     let keyup_sender = Rc::clone(&keydown_sender);
@@ -332,8 +1969,11 @@ fn prepare_input() -> Result<UnboundedReceiver<KeyPress>> {
             .unwrap();
     }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
 
-    browser::window()?.set_onkeydown(Some(onkeydown.as_ref().unchecked_ref()));
-    browser::window()?.set_onkeyup(Some(onkeyup.as_ref().unchecked_ref()));
+    canvas.set_onkeydown(Some(onkeydown.as_ref().unchecked_ref()));
+    canvas.set_onkeyup(Some(onkeyup.as_ref().unchecked_ref()));
+    if let Err(err) = canvas.focus() {
+        warn!("Could not focus canvas {:#?}", err);
+    }
 
     onkeydown.forget();
     onkeyup.forget();
@@ -353,3 +1993,329 @@ fn process_input(state: &mut KeyState, keyevent_receiver: &mut UnboundedReceiver
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silent_audio_plays_nothing_but_keeps_its_settings() {
+        let audio = Audio::silent(AudioSettings::default());
+        let sound = Sound {
+            buffer: JsValue::NULL.unchecked_into(),
+        };
+
+        assert!(audio.play_music(&sound, 1.0).is_err());
+        assert!(audio.pause_while_hidden().is_ok());
+        audio.set_settings(AudioSettings::new(true, 0.5));
+        assert_eq!(audio.settings.get(), AudioSettings::new(true, 0.5));
+    }
+
+    #[test]
+    fn default_frame_name_format_matches_the_original_names() {
+        assert_eq!(FrameNameFormat::default().format("Run", 0), "Run (1).png");
+    }
+
+    #[test]
+    fn frame_name_format_can_zero_pad() {
+        assert_eq!(
+            FrameNameFormat::new("{name} ({n:02}).png").format("Run", 0),
+            "Run (01).png"
+        );
+        assert_eq!(
+            FrameNameFormat::new("{name}_{n:04}.png")
+                .starting_at(0)
+                .format("Run", 11),
+            "Run_0011.png"
+        );
+        assert_eq!(FrameNameFormat::new("{name}-{n}").format("Run", 0), "Run-1");
+    }
+
+    #[test]
+    fn lower_resolution_scales_drawing() {
+        let recording = RecordingRenderer::new();
+        let renderer = recording.renderer();
+
+        renderer.begin_frame();
+        assert!(matches!(
+            recording.take_commands()[..],
+            [DrawCommand::BeginFrame { scale }] if scale == 1.0
+        ));
+
+        renderer.set_resolution(400.0 / 600.0).unwrap();
+        renderer.begin_frame();
+        assert!(matches!(
+            recording.take_commands()[..],
+            [DrawCommand::BeginFrame { scale }] if (scale - 400.0 / 600.0).abs() < 1e-6
+        ));
+
+        renderer.set_resolution(1.0).unwrap();
+        assert!(renderer.set_resolution(0.0).is_err());
+        assert_eq!(renderer.resolution(), 1.0);
+    }
+
+    #[test]
+    fn backing_store_follows_the_resolution() {
+        assert_eq!(backing_size((600, 600), 1.0), (600, 600));
+        assert_eq!(backing_size((600, 600), 400.0 / 600.0), (400, 400));
+        assert_eq!(backing_size((600, 300), 0.001), (1, 1));
+        assert_eq!(next_resolution(1.0), 0.75);
+        assert_eq!(next_resolution(0.5), 1.0);
+        assert_eq!(next_resolution(0.6), 1.0);
+    }
+
+    #[test]
+    fn first_frame_advances_exactly_one_step() {
+        assert_eq!(frame_delta(None, 5000.0), FRAME_SIZE);
+        assert_eq!(frame_delta(Some(5000.0), 5020.0), 20.0);
+        // 最初のフレームの後は時刻の差をそのまま使う
+        assert_eq!(frame_delta(Some(1000.0), 5000.0), 4000.0);
+    }
+
+    #[test]
+    fn frame_times_fall_into_buckets() {
+        let mut histogram = FrameHistogram::default();
+        [4.0, 16.6, 16.7, 20.0, 33.4, 100.0]
+            .into_iter()
+            .for_each(|delta| histogram.record(delta));
+        assert_eq!(
+            histogram,
+            FrameHistogram {
+                counts: [1, 1, 2, 2]
+            }
+        );
+        histogram.reset();
+        assert_eq!(histogram, FrameHistogram::default());
+    }
+
+    #[test]
+    fn slow_parallax_accumulates_fractions() {
+        let mut offset = 0.0;
+        let positions: Vec<i32> = (0..4)
+            .map(|_| {
+                offset = scroll_offset(offset, -3, 0.25, 1000);
+                offset.round() as i32
+            })
+            .collect();
+        // 毎回の0.75pxを切り捨てずに貯めるので、止まらずに進む
+        assert_eq!(positions, [999, 999, 998, 997]);
+        assert_eq!(offset, 997.0);
+    }
+
+    #[test]
+    fn full_speed_scrolling_stays_on_whole_pixels() {
+        let mut offset = 0.0;
+        let mut whole = 0;
+        (0..10_000).for_each(|_| {
+            offset = scroll_offset(offset, -7, 1.0, 1000);
+            whole = (whole - 7i32).rem_euclid(1000);
+            assert_eq!(offset, whole as f32);
+        });
+    }
+
+    #[test]
+    fn background_copies_cover_wide_views_without_seams() {
+        for (tile_width, view_width) in [(1000, 600), (300, 1900), (64, 64), (7, 1000)] {
+            for offset in [
+                0,
+                -1,
+                -tile_width / 2,
+                -(tile_width - 1),
+                5 * tile_width - 3,
+            ] {
+                let positions: Vec<i32> = tile_positions(offset, tile_width, view_width).collect();
+                assert!(positions[0] <= 0, "gap on the left: {:?}", positions);
+                assert!(
+                    positions.last().unwrap() + tile_width >= view_width,
+                    "gap on the right: {:?}",
+                    positions
+                );
+                positions
+                    .windows(2)
+                    .for_each(|pair| assert_eq!(pair[1] - pair[0], tile_width));
+            }
+        }
+    }
+
+    #[test]
+    fn source_must_lie_inside_the_image() {
+        assert!(source_within(&Rect::new_from_x_y(0, 0, 64, 32), 64, 32));
+        assert!(source_within(&Rect::new_from_x_y(10, 5, 20, 20), 64, 32));
+        assert!(!source_within(&Rect::new_from_x_y(50, 0, 20, 20), 64, 32));
+        assert!(!source_within(&Rect::new_from_x_y(-1, 0, 20, 20), 64, 32));
+        assert!(!source_within(&Rect::new_from_x_y(0, 0, 0, 20), 64, 32));
+        // 読み込みが終わっていない画像は大きさが0
+        assert!(!source_within(&Rect::new_from_x_y(0, 0, 1, 1), 0, 0));
+    }
+
+    #[test]
+    fn nine_slice_covers_the_panel_without_gaps() {
+        let source = Rect::new_from_x_y(10, 20, 30, 30);
+        let destination = Rect::new_from_x_y(101, 50, 157, 61);
+        let slices = nine_slice(&source, 8, &destination);
+
+        assert_eq!(slices.len(), 9);
+        // 角は伸ばさない
+        assert_eq!(
+            slices[0],
+            (
+                Rect::new_from_x_y(10, 20, 8, 8),
+                Rect::new_from_x_y(101, 50, 8, 8)
+            )
+        );
+        assert_eq!(
+            slices[8].1,
+            Rect::new_from_x_y(101 + 157 - 8, 50 + 61 - 8, 8, 8)
+        );
+        let area: i32 = slices
+            .iter()
+            .map(|(_, destination)| destination.w as i32 * destination.h as i32)
+            .sum();
+        assert_eq!(area, 157 * 61);
+        slices.windows(2).for_each(|pair| {
+            let (left, right) = (&pair[0].1, &pair[1].1);
+            if left.y() == right.y() {
+                assert_eq!(left.right(), right.x());
+            }
+        });
+    }
+
+    #[test]
+    fn nine_slice_at_native_size_matches_the_source() {
+        let source = Rect::new_from_x_y(0, 0, 32, 24);
+        let destination = Rect::new_from_x_y(40, 40, 32, 24);
+        nine_slice(&source, 6, &destination)
+            .iter()
+            .for_each(|(frame, destination)| {
+                assert_eq!(frame.w, destination.w);
+                assert_eq!(frame.h, destination.h);
+                assert_eq!(destination.x() - frame.x(), 40);
+                assert_eq!(destination.y() - frame.y(), 40);
+            });
+    }
+
+    #[test]
+    fn game_speed_stays_within_its_range() {
+        assert_eq!(GameSpeed::default().value(), 1.0);
+        assert_eq!(GameSpeed::new(0.1).value(), GameSpeed::MIN);
+        assert_eq!(GameSpeed::new(f32::NAN), GameSpeed::default());
+        assert_eq!(GameSpeed::default().slower().slower().slower().value(), 0.5);
+        assert_eq!(GameSpeed::default().faster().faster().faster().value(), 1.5);
+    }
+
+    #[test]
+    fn manifest_rejects_a_name_used_for_an_image_and_a_sheet() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+                "images": { "BG": "BG.png", "Stone": "Stone.png" },
+                "sheets": { "Stone": { "json": "stone.json", "image": "stone.png" } }
+            }"#,
+        )
+        .unwrap();
+        let err = manifest.check_names().unwrap_err();
+        assert!(err.to_string().contains("Stone"));
+
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+                "images": { "BG": "BG.png" },
+                "sheets": { "rhb": { "json": "rhb.json", "image": "rhb.png" } }
+            }"#,
+        )
+        .unwrap();
+        assert!(manifest.check_names().is_ok());
+    }
+
+    #[test]
+    fn collision_side_uses_the_smaller_overlap() {
+        let platform = Rect::new_from_x_y(100, 100, 100, 50);
+
+        let landing = Rect::new_from_x_y(120, 60, 40, 45);
+        let wall = Rect::new_from_x_y(65, 110, 40, 40);
+
+        assert_eq!(landing.collision_side(&platform), Some(Side::Bottom));
+        assert_eq!(wall.collision_side(&platform), Some(Side::Right));
+    }
+
+    #[test]
+    fn collision_side_prefers_vertical_on_a_tie() {
+        let platform = Rect::new_from_x_y(100, 100, 100, 50);
+        let corner = Rect::new_from_x_y(70, 70, 40, 40);
+
+        assert_eq!(corner.collision_side(&platform), Some(Side::Bottom));
+    }
+
+    #[test]
+    fn scaling_keeps_the_anchor_in_place() {
+        let stone = Rect::new_from_x_y(100, 500, 40, 50);
+        let anchor = Point { x: 100, y: 550 };
+
+        let giant = stone.scale_from(&anchor, 2.0);
+
+        assert_eq!(
+            (giant.x(), giant.y(), giant.w, giant.h),
+            (100, 450, 80, 100)
+        );
+        assert_eq!(giant.bottom(), stone.bottom());
+    }
+
+    #[test]
+    fn collision_side_is_none_without_overlap() {
+        let platform = Rect::new_from_x_y(100, 100, 100, 50);
+        let touching = Rect::new_from_x_y(60, 100, 40, 40);
+
+        assert_eq!(touching.collision_side(&platform), None);
+    }
+}
+
+// Canvasが必要なテストはブラウザで実行する(wasm-pack test --chrome など)
+#[cfg(all(test, target_arch = "wasm32"))]
+mod browser_tests {
+    use super::*;
+    use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    fn test_context() -> CanvasRenderingContext2d {
+        let canvas = browser::document()
+            .unwrap()
+            .create_element("canvas")
+            .unwrap()
+            .dyn_into::<HtmlCanvasElement>()
+            .unwrap();
+        canvas.set_width(10);
+        canvas.set_height(10);
+        canvas
+            .get_context("2d")
+            .unwrap()
+            .unwrap()
+            .dyn_into::<CanvasRenderingContext2d>()
+            .unwrap()
+    }
+
+    fn pixel(context: &CanvasRenderingContext2d, x: f64, y: f64) -> Vec<u8> {
+        context
+            .get_image_data(x, y, 1.0, 1.0)
+            .unwrap()
+            .data()
+            .to_vec()
+    }
+
+    #[wasm_bindgen_test]
+    fn fills_a_triangle_without_leaking_the_style() {
+        let context = test_context();
+        let renderer = Renderer::new(context.clone());
+
+        renderer.begin_path();
+        renderer.move_to(&Point { x: 0, y: 0 });
+        renderer.line_to(&Point { x: 10, y: 0 });
+        renderer.line_to(&Point { x: 0, y: 10 });
+        renderer.close_path();
+        renderer.fill("#ff0000");
+        renderer.stroke("#00ff00", 3.0);
+
+        assert_eq!(pixel(&context, 2.0, 2.0)[..2], [255, 0]);
+        assert_eq!(pixel(&context, 8.0, 8.0)[3], 0);
+        assert_eq!(context.fill_style().as_string().unwrap(), "#000000");
+        assert_eq!(context.line_width(), 1.0);
+    }
+}
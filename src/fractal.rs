@@ -0,0 +1,169 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    browser,
+    engine::{Game, GameLoop, KeyState, KeyTrigger, Point, Rect, Renderer},
+};
+
+const SIZE: i16 = 600;
+const MAX_DEPTH: u8 = 8;
+const DEEPER_KEY: &str = "ArrowUp";
+const SHALLOWER_KEY: &str = "ArrowDown";
+// 上、左、右の三角形の色。分割していない時は最後の色を使う
+const COLORS: [&str; 4] = ["#ff6b6b", "#feca57", "#48dbfb", "#1dd1a1"];
+
+// スプライトを使わないGameの例。シェルピンスキーの三角形をパスで描く。
+// ArrowUpとArrowDownで分割の深さを変えられる
+pub struct FractalDemo {
+    depth: u8,
+    deeper: KeyTrigger,
+    shallower: KeyTrigger,
+}
+
+impl FractalDemo {
+    pub fn new(depth: u8) -> Self {
+        FractalDemo {
+            depth: depth.min(MAX_DEPTH),
+            deeper: KeyTrigger::new(DEEPER_KEY),
+            shallower: KeyTrigger::new(SHALLOWER_KEY),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Game for FractalDemo {
+    async fn initialize(&self) -> Result<Box<dyn Game>> {
+        Ok(Box::new(FractalDemo::new(self.depth)))
+    }
+
+    fn update(&mut self, keystate: &KeyState, _tick: u64) {
+        if self.deeper.triggered(keystate) {
+            self.depth = (self.depth + 1).min(MAX_DEPTH);
+        }
+        if self.shallower.triggered(keystate) {
+            self.depth = self.depth.saturating_sub(1);
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        renderer.clear(&Rect::new_from_x_y(0, 0, SIZE, SIZE));
+        sierpinski(
+            renderer,
+            [
+                Point { x: SIZE / 2, y: 0 },
+                Point { x: 0, y: SIZE },
+                Point { x: SIZE, y: SIZE },
+            ],
+            self.depth,
+            COLORS[3],
+        );
+    }
+}
+
+fn sierpinski(renderer: &Renderer, points: [Point; 3], depth: u8, color: &str) {
+    if depth == 0 {
+        draw_triangle(renderer, &points, color);
+        return;
+    }
+    let [top, left, right] = points;
+    let left_middle = midpoint(top, left);
+    let right_middle = midpoint(top, right);
+    let bottom_middle = midpoint(left, right);
+    sierpinski(
+        renderer,
+        [top, left_middle, right_middle],
+        depth - 1,
+        COLORS[0],
+    );
+    sierpinski(
+        renderer,
+        [left_middle, left, bottom_middle],
+        depth - 1,
+        COLORS[1],
+    );
+    sierpinski(
+        renderer,
+        [right_middle, bottom_middle, right],
+        depth - 1,
+        COLORS[2],
+    );
+}
+
+fn draw_triangle(renderer: &Renderer, points: &[Point; 3], color: &str) {
+    let [top, left, right] = points;
+    renderer.begin_path();
+    renderer.move_to(top);
+    renderer.line_to(left);
+    renderer.line_to(right);
+    renderer.close_path();
+    renderer.fill(color);
+}
+
+fn midpoint(a: Point, b: Point) -> Point {
+    Point {
+        x: (a.x + b.x) / 2,
+        y: (a.y + b.y) / 2,
+    }
+}
+
+// start_gameの代わりに呼ぶと、ゲームではなくフラクタルのデモを動かす
+#[wasm_bindgen]
+pub fn start_fractal_demo(canvas_id: &str, depth: u8) -> Result<(), JsValue> {
+    console_error_panic_hook::set_once();
+    let context =
+        browser::context(canvas_id).map_err(|err| JsValue::from_str(&format!("{:#}", err)))?;
+
+    browser::spawn_local(async move {
+        if let Err(err) = GameLoop::start(FractalDemo::new(depth), Renderer::new(context)).await {
+            error!("Could not start fractal demo: {:#}", err);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{DrawCommand, RecordingRenderer};
+
+    fn triangle(points: [(i16, i16); 3], color: &str) -> Vec<DrawCommand> {
+        let [top, left, right] = points.map(|(x, y)| Point { x, y });
+        vec![
+            DrawCommand::BeginPath,
+            DrawCommand::MoveTo(top),
+            DrawCommand::LineTo(left),
+            DrawCommand::LineTo(right),
+            DrawCommand::ClosePath,
+            DrawCommand::Fill(color.to_string()),
+        ]
+    }
+
+    #[test]
+    fn draws_one_level_deeper_after_arrow_up() {
+        let mut demo = FractalDemo::new(0);
+        let mut keystate = KeyState::new();
+        let recorder = RecordingRenderer::new();
+
+        // 1フレーム目で押し、3フレーム目で離す
+        for tick in 0..4 {
+            match tick {
+                0 => keystate.press(DEEPER_KEY),
+                2 => keystate.release(DEEPER_KEY),
+                _ => {}
+            }
+            keystate.start_update();
+            demo.update(&keystate, tick);
+        }
+        demo.draw(recorder.renderer());
+
+        let mut golden = vec![DrawCommand::Clear(Rect::new_from_x_y(0, 0, SIZE, SIZE))];
+        golden.extend(triangle([(300, 0), (150, 300), (450, 300)], COLORS[0]));
+        golden.extend(triangle([(150, 300), (0, 600), (300, 600)], COLORS[1]));
+        golden.extend(triangle([(450, 300), (300, 600), (600, 600)], COLORS[2]));
+        assert_eq!(recorder.take_commands(), golden);
+        assert!(recorder.take_commands().is_empty());
+    }
+}
@@ -1,835 +1,6096 @@
-use std::rc::Rc;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    mem::{self, Discriminant},
+    rc::Rc,
+    str::FromStr,
+};
 
 use crate::{
-    browser,
+    browser::{self, LocalStorage, Store},
+    deaths::DeathHistory,
     engine::{
-        self, Cell, Game, GameLoop, Image, KeyState, Point, Rect, Renderer, Sheet, SpriteSheet,
+        self, Assets, Audio, Cell, FrameNameFormat, Game, GameLoop, Image, KeyState, Music, Point,
+        Rect, Renderer, Sheet, SheetReloader, Side, Sound, SpriteSheet, TiledImage,
+    },
+    leaderboard::{Leaderboard, LeaderboardStatus},
+    segments::{
+        self, stone_and_platform, Palettes, SegmentKind, SegmentPicker, SpawnWeights, SEGMENT_TYPES,
     },
-    segments::{platform_and_stone, stone_and_platform},
+    settings::{Action, InputWindows, Settings},
+    tween::{self, Tween},
 };
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use gloo_utils::format::JsValueSerdeExt;
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
-use web_sys::HtmlImageElement;
+use web_sys::{HtmlCanvasElement, HtmlImageElement};
 
+// auto-startで起動する時のcanvas要素のid
+#[cfg(feature = "auto-start")]
+const CANVAS_ID: &str = "canvas";
 const HEIGHT: i16 = 600;
-const TIMELINE_MINIMUM: i16 = 1000;
+const WIDTH: i16 = 600;
 const OBSTACLE_BUFFER: i16 = 20;
+const MAX_OBSTACLES: usize = 64;
+// 最初の障害物は、少年の出発点からこれ以上離して置く
+const OPENING_LEAD: i16 = 200;
+// この距離を走るごとにテレメトリのチェックポイントを送る
+const TELEMETRY_CHECKPOINT: i32 = 2000;
+// 壁に触れてから壁ジャンプを受け付けるフレーム数
+const WALL_CONTACT_GRACE: u8 = 6;
+// ArrowDownをこのフレーム数より長く押し続けるとしゃがみ歩きになる
+const CROUCH_HOLD_FRAMES: u8 = 10;
+// 復帰直後に障害物に当たっても倒れない時間
+const RESPAWN_INVINCIBLE_FRAMES: u16 = 90;
+const INVINCIBLE_ALPHA: f64 = 0.4;
+const STARTING_LIVES: u8 = 3;
+// この距離を走ったら障害物の生成をやめてゴールを置く
+const LEVEL_DISTANCE: i32 = 20000;
+// localStorageに保存する最長距離のキー
+const BEST_DISTANCE_KEY: &str = "walk_the_dog.best_distance";
+// デイリーチャレンジの最長距離は、どの日の記録かと一緒に別のキーに保存する
+const DAILY_BEST_KEY: &str = "walk_the_dog.daily_best";
+// ランキングに送る名前
+const PLAYER_NAME: &str = "Player";
+// ゲームオーバー画面に出すランキングの行数
+const LEADERBOARD_ROWS: usize = 5;
+const MAX_NAME_LENGTH: usize = 12;
+// ゲームオーバー画面で押すと、この走りのseedをURLのフラグメントに書き込む
+const COPY_LINK_KEY: &str = "KeyC";
+// ボーナスの表示を出しておくフレーム数
+const BONUS_POPUP_FRAMES: u8 = 60;
+const SHADOW_COLOR: &str = "rgba(0, 0, 0, 0.25)";
+const SHADOW_HEIGHT: i16 = 10;
+// この高さまで跳ぶと影が一番小さくなる
+const SHADOW_FADE_HEIGHT: f32 = 250.0;
+const SHADOW_MIN_SCALE: f32 = 0.3;
+// ゲーム全体の速さを一段階ずつ変える
+const SLOWER_KEY: &str = "Minus";
+const FASTER_KEY: &str = "Equal";
+// 設定画面を開閉する。開いている間はゲームを止める
+const SETTINGS_KEY: &str = "Escape";
+// 今の障害物の配置をURLのフラグメントとログに書き出し、縮小した場面の画像も保存する
+const REPORT_KEY: &str = "KeyB";
+const REPORT_THUMBNAIL_WIDTH: u32 = 300;
+// デバッグビルドのみ。押すとSKIP_DISTANCEだけ先へ飛ぶ
+const SKIP_KEY: &str = "F7";
+const MUTE_KEY: &str = "KeyM";
+// 操作の割り当てとは別に、ゲームが使うキー。どのプリセットもこれらには割り当てない
+#[cfg(test)]
+const RESERVED_KEYS: [&str; 7] = [
+    COPY_LINK_KEY,
+    SLOWER_KEY,
+    FASTER_KEY,
+    SETTINGS_KEY,
+    REPORT_KEY,
+    SKIP_KEY,
+    MUTE_KEY,
+];
+const SKIP_DISTANCE: i16 = 1000;
+// 1度に進める幅。セグメント1つ分より十分小さくして、生成の判定を飛ばさないようにする
+const SKIP_STEP: i16 = 50;
+const VOLUME_STEP: f32 = 0.1;
+const FINISH_LINE_WIDTH: i16 = 12;
+const HEART_MARGIN: i16 = 10;
+// Some("#87ceeb")のように指定すると、透明ではなくその色で画面をクリアする
+const CLEAR_COLOR: Option<&str> = None;
+const TRAJECTORY_TICKS: u16 = 60;
+const TRAJECTORY_LINE_WIDTH: f32 = 3.0;
+const PROGRESS_BAR_HEIGHT: i16 = 4;
+const PROGRESS_MARKER_WIDTH: i16 = 4;
+// 風が止んでから次の突風までのフレーム数
+const GUST_INTERVAL: std::ops::Range<u16> = 300..900;
+const GUST_DURATION: std::ops::Range<u16> = 90..180;
+// 風で変わるスクロール速度。走る速さより小さくしておく
+const GUST_STRENGTH: i16 = 1;
+const GUST_STREAKS: i16 = 8;
+const GUST_STREAK_LENGTH: i16 = 30;
+// この距離を走るごとに昼→夜→昼と一巡する
+const DAY_NIGHT_CYCLE: i32 = 16000;
+const NIGHT_ALPHA: f32 = 0.45;
+// 走っている間、カメラは少年のこれだけ先を映す
+const CAMERA_LOOK_AHEAD: i16 = 20;
+// 1フレームで目標との差をどれだけ詰めるか
+const CAMERA_SMOOTHING: f32 = 0.08;
+// 向きが変わった時に、先を映す側をこのフレーム数かけて振り替える
+const CAMERA_TURN_FRAMES: f32 = 20.0;
+// 設定画面が浮かび上がるまでのフレーム数
+const MENU_FADE_FRAMES: f32 = 12.0;
+// 障害物に当たったと判定する時に、少年の当たり判定から削る幅
+const HAZARD_MARGIN: i16 = 4;
+// 崩れる足場に乗ってから、当たり判定が無くなるまでのフレーム数
+const CRUMBLE_FRAMES: u8 = 30;
+// 崩れている間に左右に揺れる幅
+const CRUMBLE_SHAKE: i16 = 2;
+// テーマの背景と曲が入れ替わり終わるまでに流れる距離
+const THEME_FADE_DISTANCE: i32 = 600;
+const DUSK_COLOR: (f32, f32, f32) = (255.0, 140.0, 60.0);
+const NIGHT_COLOR: (f32, f32, f32) = (20.0, 30.0, 90.0);
 
-macro_rules! _log {
-    ( $( $t:tt )* ) => {
-        web_sys::console::log_1(&format!( $( $t )* ).into());
-    };
+#[derive(Deserialize)]
+pub struct SegmentSpec {
+    #[serde(rename = "type")]
+    pub kind: String,
+    // 直前のセグメントの右端からの距離
+    pub offset: i16,
+    // 少年がこのセグメントに着いたら、このテーマに切り替える
+    #[serde(default)]
+    pub theme: Option<String>,
+    // 障害物の大きさの倍率。大きな岩や小さな足場を置く時に使う
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+}
+
+#[derive(Deserialize)]
+pub struct ThemeSpec {
+    pub background: String,
+    // 省略するとそれまでの曲のまま
+    #[serde(default)]
+    pub music: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Level {
+    pub background: String,
+    #[serde(default)]
+    pub music: Option<String>,
+    // 遊んでいる間に先読みしておく次のレベル
+    #[serde(default)]
+    pub next: Option<String>,
+    // 背景が世界に対して流れる速さ。1より小さいと遠くに見える
+    #[serde(default = "default_parallax")]
+    pub background_parallax: f32,
+    // セグメントのthemeから名前で参照される
+    #[serde(default)]
+    pub themes: HashMap<String, ThemeSpec>,
+    pub segments: Vec<SegmentSpec>,
+}
+
+fn default_parallax() -> f32 {
+    1.0
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn is_unscaled(scale: &f32) -> bool {
+    *scale == 1.0
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SpawnConfig {
+    // timelineがこれより手前に来たら次のセグメントを生成する
+    pub timeline_minimum: i16,
+    // セグメント同士の間隔
+    pub obstacle_buffer: i16,
+    // 障害物がこれ以上あったら減るまで生成しない。普通に遊んでいて届く数ではない
+    pub max_obstacles: usize,
+    // 走り始めてから最初の障害物を越えられるように空けておく、出発点からの距離
+    pub opening_lead: i16,
+}
+
+impl SpawnConfig {
+    // 画面の幅の2/3だけ先まで障害物を用意しておく。幅600なら1000になる
+    pub fn for_width(width: i16) -> Self {
+        SpawnConfig {
+            timeline_minimum: width + width * 2 / 3,
+            obstacle_buffer: OBSTACLE_BUFFER,
+            max_obstacles: MAX_OBSTACLES,
+            opening_lead: OPENING_LEAD,
+        }
+    }
+}
+
+// ホストのページに送るイベント。JSにはtypeフィールド付きのオブジェクトとして渡る
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TelemetryEvent {
+    RunStarted {
+        seed: u64,
+    },
+    Checkpoint {
+        seed: u64,
+        distance: i32,
+    },
+    // 残機が残っていても倒れるたびに送る。stateは倒れる直前の状態
+    Knockout {
+        seed: u64,
+        distance: i32,
+        state: RedHatBoyStateTag,
+        cause: Option<KnockOutCause>,
+    },
+    GameOver {
+        seed: u64,
+        distance: i32,
+        cause: Option<KnockOutCause>,
+    },
+}
+
+pub type TelemetrySink = Rc<dyn Fn(TelemetryEvent)>;
+
+pub enum SegmentSource {
+    Procedural,
+    Authored(VecDeque<SegmentSpec>),
+    // 練習用に同じセグメントを終わりなく繰り返す
+    Practice(SegmentKind),
 }
 
 pub struct Walk {
     boy: RedHatBoy,
-    background: [Image; 2],
+    background: TiledImage,
     obstacles: Vec<Box<dyn Obstacle>>,
     obstacle_sheet: Rc<SpriteSheet>,
-    stone: HtmlImageElement,
+    stone: Rc<HtmlImageElement>,
     timeline: i16,
+    heart: HtmlImageElement,
+    lives: u8,
+    distance: i32,
+    elapsed_frames: u32,
+    finish_line: Option<FinishLine>,
+    level_complete: Option<LevelComplete>,
+    segment_source: SegmentSource,
+    level_length: Option<i32>,
+    show_trajectory: bool,
+    show_hitboxes: bool,
+    seed: u64,
+    // デイリーチャレンジならその日付
+    daily: Option<u32>,
+    rng: StdRng,
+    segment_picker: SegmentPicker,
+    spawn_config: SpawnConfig,
+    wind: Wind,
+    camera: Camera,
+    telemetry: Option<TelemetrySink>,
+    obstacle_cap_hit: bool,
+    audio: Audio,
+    // falseの間は背景と障害物を流さない
+    scrolling: bool,
+    // スコアとは別の、世界が流れた距離の合計。固定ステップの速度だけから求める
+    travelled: u64,
+    best_distance: u64,
+    leaderboard: Option<Rc<Leaderboard>>,
+    game_over: Option<GameOver>,
+    scoring: ScoringConfig,
+    score: u32,
+    // 障害物を左に動かした量の合計。障害物のright()に足すとワールド座標になる
+    obstacles_scrolled: i32,
+    // このtickで障害物をもう動かしたか。2回動かすと当たり判定とずれる
+    obstacles_moved: bool,
+    // ここまでのワールド座標にある障害物は採点済み
+    scored_until: i32,
+    // 今のジャンプで越えた障害物の数
+    air_chain: u32,
+    // 少年の状態が変わった通知。次のupdateでまとめて読む
+    state_changes: Rc<RefCell<Vec<StateChanged>>>,
+    bonus_popups: Vec<BonusPopup>,
+    palettes: Palettes,
+    settings: Settings,
+    // 設定の保存先。テストではメモリに置く
+    store: Rc<dyn Store>,
+    settings_menu: Option<SettingsMenu>,
+    sheet_reloader: Option<SheetReloader>,
+    themes: HashMap<String, Theme>,
+    // テーマが始まるワールド座標。少年の右端がそこに着いたら切り替えを始める
+    theme_markers: VecDeque<(i32, String)>,
+    theme_fade: Option<ThemeFade>,
+    music: Option<Music>,
+    deaths: DeathHistory,
+    // 共有されたseedで遊んでいる。障害物の並びが変わらないよう、設定に関わらず調整しない
+    shared_seed: bool,
 }
 
-impl Walk {
-    fn velocity(&self) -> i16 {
-        -self.boy.walk_speed()
-    }
+#[derive(Clone)]
+struct Theme {
+    background: HtmlImageElement,
+    music: Option<Sound>,
+}
 
-    fn generate_next_segment(&mut self) {
-        let mut rng = thread_rng();
-        let next_segment = rng.gen_range(0..=1);
-
-        let mut next_obstacles = match next_segment {
-            0 => stone_and_platform(
-                self.stone.clone(),
-                self.obstacle_sheet.clone(),
-                self.timeline + OBSTACLE_BUFFER,
-            ),
-            1 => platform_and_stone(
-                self.stone.clone(),
-                self.obstacle_sheet.clone(),
-                self.timeline + OBSTACLE_BUFFER,
-            ),
-            _ => vec![],
-        };
+// テーマの切り替え中。同じシードで同じ場所になるよう、進み具合は時間ではなく流れた距離で決める
+struct ThemeFade {
+    background: TiledImage,
+    music: Option<Music>,
+    // 切り替えを始めた時のobstacles_scrolled
+    start: i32,
+    progress: f32,
+}
 
-        self.timeline = rightmost(&next_obstacles);
-        self.obstacles.append(&mut next_obstacles);
+// ボーナス点の設定
+#[derive(Copy, Clone, Debug)]
+pub struct ScoringConfig {
+    // 障害物の上をこの距離以内で越えたらニアミス
+    pub near_miss_distance: i16,
+    pub near_miss_bonus: u32,
+    // 1回のジャンプで越えた障害物ごとのボーナス。続けて越えるほど増える
+    pub air_clear_bonus: u32,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        ScoringConfig {
+            near_miss_distance: 12,
+            near_miss_bonus: 50,
+            air_clear_bonus: 25,
+        }
     }
 }
 
-pub enum WalkTheDog {
-    Loading,
-    Loaded(Walk),
+struct BonusPopup {
+    text: String,
+    frames_left: u8,
 }
 
-#[async_trait(?Send)]
-impl Game for WalkTheDog {
-    async fn initialize(&self) -> Result<Box<dyn Game>> {
-        match self {
-            WalkTheDog::Loading => {
-                let tiles = browser::fetch_json("tiles.json").await?;
-                let sprite_sheet = Rc::new(SpriteSheet::new(
-                    tiles.into_serde::<Sheet>()?,
-                    engine::load_image("tiles.png").await?,
-                ));
+// 走りに使う、読み込み済みの画像
+struct WalkImages {
+    background: TiledImage,
+    stone: Rc<HtmlImageElement>,
+    heart: HtmlImageElement,
+    obstacle_sheet: Rc<SpriteSheet>,
+}
 
-                let json = browser::fetch_json("rhb_trimmed.json").await?;
-                let rhb = RedHatBoy::new(
-                    json.into_serde::<Sheet>()?,
-                    engine::load_image("rhb_trimmed.png").await?,
-                );
+// 最初に置く障害物と、その後に続く区間の作り方
+struct Course {
+    obstacles: Vec<Box<dyn Obstacle>>,
+    source: SegmentSource,
+    // ゴールまでの長さ。終わりの無い時はNone
+    length: Option<i32>,
+}
 
-                let background = engine::load_image("BG.png").await?;
-                let stone = engine::load_image("Stone.png").await?;
-                let background_width = background.width() as i16;
-                let starting_obstacles = stone_and_platform(stone.clone(), sprite_sheet.clone(), 0);
-                let timeline = rightmost(&starting_obstacles);
-                Ok(Box::new(WalkTheDog::Loaded(Walk {
-                    boy: rhb,
-                    background: [
-                        Image::new(background.clone(), Point { x: 0, y: 0 }),
-                        Image::new(
-                            background,
-                            Point {
-                                x: background_width,
-                                y: 0,
-                            },
-                        ),
-                    ],
-                    obstacles: starting_obstacles,
-                    obstacle_sheet: sprite_sheet,
-                    stone,
-                    timeline,
-                })))
-            }
-            WalkTheDog::Loaded(_) => Err(anyhow!("Error: Game is already initialized!")),
+impl Walk {
+    // 読み込んだものだけから走りを始める。URLや保存した記録など、
+    // ブラウザから読むものはinitializeが後から入れる
+    fn new(
+        boy: RedHatBoy,
+        images: WalkImages,
+        course: Course,
+        seed: u64,
+        settings: &Settings,
+        store: Rc<dyn Store>,
+        audio: Audio,
+    ) -> Self {
+        let mut boy = boy;
+        boy.set_physics(settings.physics());
+        let state_changes = Rc::new(RefCell::new(vec![]));
+        let pending = state_changes.clone();
+        boy.on_state_change(move |change| pending.borrow_mut().push(*change));
+        let spawn_config = SpawnConfig::for_width(WIDTH);
+        let mut obstacles = course.obstacles;
+        keep_opening_clear(&mut obstacles, spawn_config.opening_lead);
+        let timeline = rightmost(&obstacles);
+        Walk {
+            boy,
+            background: images.background,
+            obstacles,
+            obstacle_sheet: images.obstacle_sheet,
+            stone: images.stone,
+            timeline,
+            heart: images.heart,
+            lives: STARTING_LIVES,
+            distance: 0,
+            elapsed_frames: 0,
+            finish_line: None,
+            level_complete: None,
+            segment_source: course.source,
+            level_length: course.length,
+            show_trajectory: false,
+            show_hitboxes: false,
+            seed,
+            daily: None,
+            rng: StdRng::seed_from_u64(seed),
+            segment_picker: SegmentPicker::new(SpawnWeights::default()),
+            spawn_config,
+            wind: Wind::default(),
+            camera: Camera::default(),
+            telemetry: None,
+            obstacle_cap_hit: false,
+            scrolling: true,
+            travelled: 0,
+            best_distance: 0,
+            leaderboard: None,
+            game_over: None,
+            scoring: ScoringConfig::default(),
+            score: 0,
+            obstacles_scrolled: 0,
+            obstacles_moved: false,
+            scored_until: i32::MIN,
+            air_chain: 0,
+            state_changes,
+            bonus_popups: vec![],
+            palettes: Palettes::default(),
+            settings: settings.clone(),
+            store,
+            settings_menu: None,
+            sheet_reloader: None,
+            audio,
+            themes: HashMap::new(),
+            theme_markers: VecDeque::new(),
+            theme_fade: None,
+            music: None,
+            deaths: DeathHistory::default(),
+            shared_seed: false,
         }
     }
 
-    fn update(&mut self, keystate: &KeyState) {
-        if let WalkTheDog::Loaded(walk) = self {
-            if keystate.is_pressed("ArrowRight") {
-                walk.boy.run_right();
-            }
-            if keystate.is_pressed("ArrowDown") {
-                walk.boy.slide();
-            }
-            if keystate.is_pressed("Space") {
-                walk.boy.jump();
-            }
-            walk.boy.update();
+    // シンクが無ければイベントを作りもしない。シンクはゲームループの外で呼ぶ
+    fn emit(&self, event: impl FnOnce() -> TelemetryEvent) {
+        if let Some(sink) = &self.telemetry {
+            let sink = sink.clone();
+            let event = event();
+            browser::spawn_local(async move { sink(event) });
+        }
+    }
 
-            let velocity = walk.velocity();
-            // 条件を満たす要素のみを残す
-            walk.obstacles.retain(|obstacle| obstacle.right() > 0);
-            walk.obstacles.iter_mut().for_each(|obstacle| {
-                obstacle.move_horizontally(velocity);
-                obstacle.check_intersection(&mut walk.boy);
+    fn report_state_changes(&mut self) {
+        let changes = self.state_changes.take();
+        changes
+            .iter()
+            .filter(|change| change.to == RedHatBoyStateTag::Falling)
+            .for_each(|change| {
+                self.emit(|| TelemetryEvent::Knockout {
+                    seed: self.seed,
+                    distance: self.distance,
+                    state: change.from,
+                    cause: self.boy.last_knockout_cause(),
+                })
             });
+    }
 
-            let [first_background, second_background] = &mut walk.background;
-            first_background.move_horizontally(velocity);
-            second_background.move_horizontally(velocity);
-            if first_background.right() < 0 {
-                first_background.set_x(second_background.right());
-            }
-            if second_background.right() < 0 {
-                second_background.set_x(first_background.right());
+    // 倒れきったら残機を1つ減らし、残っていれば無敵状態で復帰する
+    fn lose_life(&mut self) {
+        // 練習中は残機を減らさずにすぐ復帰する
+        if self.boy.knocked_out() && matches!(self.segment_source, SegmentSource::Practice(_)) {
+            self.respawn();
+            return;
+        }
+        if self.boy.knocked_out() && self.lives > 0 {
+            self.lives -= 1;
+            self.record_death();
+            if self.lives > 0 {
+                self.respawn();
+            } else {
+                let new_best = self.record_distance();
+                self.end_run(new_best);
+                self.emit(|| TelemetryEvent::GameOver {
+                    seed: self.seed,
+                    distance: self.distance,
+                    cause: self.boy.last_knockout_cause(),
+                });
             }
+        }
+    }
 
-            walk.obstacles.iter_mut().for_each(|obstacle| {
-                obstacle.move_horizontally(velocity);
-                obstacle.check_intersection(&mut walk.boy);
-            });
+    // 設定で変えた倒れ方は、倒れている途中ではなく次に復帰した時から使う
+    fn respawn(&mut self) {
+        self.boy.set_physics(self.settings.physics());
+        self.boy.respawn();
+    }
 
-            if walk.timeline < TIMELINE_MINIMUM {
-                walk.generate_next_segment();
-            } else {
-                walk.timeline += velocity;
+    // 作成済みのレベルは距離の意味が違うので、ランダムに生成した走りだけを記録する
+    fn record_death(&mut self) {
+        if !matches!(self.segment_source, SegmentSource::Procedural) {
+            return;
+        }
+        self.deaths.record(self.distance);
+        if let Err(err) = self.deaths.save(self.store.as_ref()) {
+            error!("Could not save death history {:#?}", err);
+        }
+    }
+
+    fn check_passed_deaths(&mut self) {
+        if self.deaths.pass(self.distance) {
+            if let Err(err) = self.deaths.save(self.store.as_ref()) {
+                error!("Could not save death history {:#?}", err);
             }
         }
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        renderer.clear(&Rect::new_from_x_y(0, 0, 600, 600));
+    // ゲームオーバー画面を出し、ランキングへの送信は裏で行う。
+    // 通信に失敗しても画面に"offline"と出すだけで、ゲームは止めない
+    fn end_run(&mut self, new_best: bool) {
+        // 自己ベストを更新した時は名前を入力してもらってから送る
+        let (name_entry, leaderboard) = if new_best {
+            (Some(NameEntry::default()), None)
+        } else {
+            (None, self.submit_score(PLAYER_NAME))
+        };
+        // 倒れた後も滑っていくので、画面の後ろで世界が流れ続けないよう止める
+        self.set_scrolling(false);
+        self.game_over = Some(GameOver {
+            distance: self.travelled,
+            seed: self.seed,
+            daily: self.daily,
+            name_entry,
+            leaderboard,
+        });
+    }
 
-        if let WalkTheDog::Loaded(walk) = self {
-            walk.background.iter().for_each(|background| {
-                background.draw(renderer);
-            });
-            walk.boy.draw(renderer);
-            walk.obstacles.iter().for_each(|obstacle| {
-                obstacle.draw(renderer);
-            })
-        }
+    fn entering_name(&self) -> bool {
+        self.game_over
+            .as_ref()
+            .is_some_and(|game_over| game_over.name_entry.is_some())
     }
-}
-impl WalkTheDog {
-    fn new() -> Self {
-        WalkTheDog::Loading
+
+    fn update_name_entry(&mut self, keystate: &KeyState) {
+        let game_over = match &mut self.game_over {
+            Some(game_over) => game_over,
+            None => return,
+        };
+        let name_entry = match &mut game_over.name_entry {
+            Some(name_entry) => name_entry,
+            None => return,
+        };
+        let confirmed = keystate
+            .typed_keys()
+            .iter()
+            .any(|key| name_entry.handle_key(key));
+        if confirmed {
+            let name = name_entry.name().to_string();
+            game_over.name_entry = None;
+            let leaderboard = self.submit_score(&name);
+            if let Some(game_over) = &mut self.game_over {
+                game_over.leaderboard = leaderboard;
+            }
+        }
     }
-}
 
-pub trait Obstacle {
-    fn check_intersection(&self, boy: &mut RedHatBoy);
-    fn draw(&self, renderer: &Renderer);
-    fn move_horizontally(&mut self, x: i16);
-    fn right(&self) -> i16;
-}
+    fn submit_score(&self, name: &str) -> Option<Rc<RefCell<LeaderboardStatus>>> {
+        self.leaderboard.clone().map(|leaderboard| {
+            let status = Rc::new(RefCell::new(LeaderboardStatus::Loading));
+            let shared = status.clone();
+            let score = u32::try_from(self.travelled).unwrap_or(u32::MAX);
+            let name = name.to_string();
+            browser::spawn_local(async move {
+                let result = match leaderboard.submit_score(&name, score).await {
+                    Ok(()) => leaderboard.fetch_leaderboard().await,
+                    Err(err) => Err(err),
+                };
+                *shared.borrow_mut() = match result {
+                    Ok(entries) => LeaderboardStatus::Loaded(entries),
+                    Err(err) => {
+                        warn!("Leaderboard is offline {:#?}", err);
+                        LeaderboardStatus::Offline
+                    }
+                };
+            });
+            status
+        })
+    }
 
-fn rightmost(obstacle_list: &Vec<Box<dyn Obstacle>>) -> i16 {
-    obstacle_list
-        .iter()
-        .map(|obstacle| obstacle.right())
-        .max_by(|x, y| x.cmp(&y))
-        .unwrap_or(0)
-}
+    // 自己ベストを更新したらtrue
+    fn record_distance(&mut self) -> bool {
+        if self.travelled <= self.best_distance {
+            return false;
+        }
+        self.best_distance = self.travelled;
+        let saved = match self.daily {
+            Some(day) => serde_json::to_string(&DailyBest {
+                day,
+                distance: self.best_distance,
+            })
+            .map_err(|err| anyhow!("{:#?}", err))
+            .and_then(|json| browser::save_setting(DAILY_BEST_KEY, &json)),
+            None => browser::save_setting(BEST_DISTANCE_KEY, &self.best_distance.to_string()),
+        };
+        if let Err(err) = saved {
+            error!("Could not save best distance {:#?}", err);
+        }
+        true
+    }
 
-pub struct Platform {
-    sheet: Rc<SpriteSheet>,
-    position: Point,
-    sprites: Vec<Cell>,
-    bounding_boxes: Vec<Rect>,
-}
+    fn place_finish_line(&mut self) {
+        let finish_line = FinishLine {
+            x: self.timeline + self.spawn_config.obstacle_buffer,
+        };
+        self.timeline = finish_line.right();
+        // ゴールの位置が決まったので正確な長さに置き換える
+        self.level_length =
+            Some(self.distance + (finish_line.x - self.boy.bounding_box().right()) as i32);
+        self.finish_line = Some(finish_line);
+    }
 
-impl Obstacle for Platform {
-    fn check_intersection(&self, boy: &mut RedHatBoy) {
-        if let Some(box_to_land_on) = self
-            .bounding_boxes()
-            .iter()
-            .find(|&bounding_box| boy.bounding_box().intersects(bounding_box))
-        {
-            if boy.velocity_y() > 0 && boy.pos_y() < self.position.y {
-                boy.land_on(box_to_land_on.y());
-            } else {
-                boy.knock_out();
+    fn check_finish(&mut self) {
+        if let Some(finish_line) = &self.finish_line {
+            if self.boy.bounding_box().right() >= finish_line.x {
+                self.record_distance();
+                self.level_complete = Some(LevelComplete {
+                    elapsed_frames: self.elapsed_frames,
+                    distance: self.distance,
+                });
             }
         }
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        let mut x = 0;
-        self.sprites.iter().for_each(|sprite| {
-            self.sheet.draw(
-                renderer,
-                &Rect::new_from_x_y(
-                    sprite.frame.x,
-                    sprite.frame.y,
-                    sprite.frame.w,
-                    sprite.frame.h,
-                ),
+    fn draw_progress(&self, renderer: &Renderer) {
+        if let Some(level_length) = self.level_length {
+            let progress = (self.distance as f32 / level_length.max(1) as f32).clamp(0.0, 1.0);
+            let filled = (progress * WIDTH as f32) as i16;
+            renderer.fill_rect(
+                &Rect::new_from_x_y(0, 0, WIDTH, PROGRESS_BAR_HEIGHT),
+                "rgba(0, 0, 0, 0.4)",
+            );
+            renderer.fill_rect(
+                &Rect::new_from_x_y(0, 0, filled, PROGRESS_BAR_HEIGHT),
+                "#ffcc00",
+            );
+            renderer.fill_rect(
                 &Rect::new_from_x_y(
-                    self.position.x + x,
-                    self.position.y,
-                    sprite.frame.w,
-                    sprite.frame.h,
+                    (filled - PROGRESS_MARKER_WIDTH).max(0),
+                    0,
+                    PROGRESS_MARKER_WIDTH,
+                    PROGRESS_BAR_HEIGHT * 2,
                 ),
+                "#d62020",
             );
-            x += sprite.frame.w;
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        clear_screen(renderer);
+        renderer.begin_camera(&self.camera.position());
+        self.background.draw(renderer);
+        if let Some(fade) = &self.theme_fade {
+            renderer.set_alpha(fade.progress as f64);
+            fade.background.draw(renderer);
+            renderer.set_alpha(1.0);
+        }
+        self.boy.draw_shadow(renderer);
+        self.boy.draw(renderer);
+        self.obstacles.iter().for_each(|obstacle| {
+            obstacle.draw(renderer);
         });
+        if let Some(finish_line) = &self.finish_line {
+            finish_line.draw(renderer);
+        }
+        if self.show_trajectory {
+            self.draw_trajectory(renderer);
+        }
+        if self.show_hitboxes || self.settings.show_hitboxes {
+            self.boy.draw_hitboxes(renderer);
+        }
+        renderer.end_camera();
+        self.wind.draw(renderer);
+        self.draw_tint(renderer);
+        self.draw_progress(renderer);
+        self.draw_lives(renderer);
+        self.draw_score(renderer);
+        if let Some(level_complete) = &self.level_complete {
+            level_complete.draw(renderer);
+        }
+        if let Some(game_over) = &self.game_over {
+            game_over.draw(renderer, &self.stone);
+        }
+        if let Some(settings_menu) = &self.settings_menu {
+            settings_menu.draw(renderer, &self.settings, &self.stone);
+        }
     }
 
-    fn move_horizontally(&mut self, x: i16) {
-        self.position.x += x;
-        self.bounding_boxes.iter_mut().for_each(|bounding_box| {
-            bounding_box.set_x(bounding_box.position.x + x);
-        })
+    // GameLoopを通さずに1フレーム分を描く。Game::draw_toと同じ区切り方にする
+    fn draw_frame(&self, renderer: &Renderer) {
+        renderer.begin_frame();
+        self.draw(renderer);
+        renderer.end_frame();
     }
 
-    fn right(&self) -> i16 {
-        self.bounding_boxes()
-            .last()
-            .unwrap_or(&Rect::default())
-            .right()
+    // レベル選択の画面や報告に出す、幅widthに縮小した今の場面。
+    // 画面と同じdrawで描くので、縮小されるだけで内容は画面に出ているものと同じになる
+    pub fn thumbnail(&self, width: u32) -> Result<HtmlCanvasElement> {
+        let (width, height, scale) = thumbnail_size(width);
+        let renderer = Renderer::new(browser::offscreen_context(width, height)?);
+        renderer.set_scale(scale);
+        self.draw_frame(&renderer);
+        renderer.canvas()
     }
-}
 
-impl Platform {
-    pub fn new(
-        sheet: Rc<SpriteSheet>,
-        position: Point,
-        sprite_names: &[&str],
-        bounding_boxes: &[Rect],
-    ) -> Self {
-        let sprites = sprite_names
-            .iter()
-            .filter_map(|sprite_name| sheet.cell(&sprite_name).cloned())
-            .collect();
-        let bounding_boxes = bounding_boxes
-            .iter()
-            .map(|bounding_box| {
-                Rect::new_from_x_y(
-                    bounding_box.x() + position.x,
-                    bounding_box.y() + position.y,
-                    bounding_box.w,
-                    bounding_box.h,
-                )
-            })
-            .collect();
+    fn draw_trajectory(&self, renderer: &Renderer) {
+        let arc = self.boy.predicted_arc(TRAJECTORY_TICKS);
+        let (first, rest) = match arc.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        renderer.begin_path();
+        renderer.move_to(first);
+        rest.iter().for_each(|point| renderer.line_to(point));
+        renderer.stroke("#00ff88", TRAJECTORY_LINE_WIDTH);
+    }
 
-        Platform {
-            sheet,
-            position,
-            sprites,
-            bounding_boxes,
+    // ワールドの上、HUDの下に重ねる
+    fn draw_tint(&self, renderer: &Renderer) {
+        if let Some(color) = day_night_tint(self.distance) {
+            renderer.fill_rect(&Rect::new_from_x_y(0, 0, WIDTH, HEIGHT), &color);
         }
     }
 
-    fn bounding_boxes(&self) -> &Vec<Rect> {
-        &self.bounding_boxes
+    fn draw_lives(&self, renderer: &Renderer) {
+        let (width, height) = engine::image_size(&self.heart);
+        let (width, height) = (width as i16, height as i16);
+        (0..self.lives as i16).for_each(|life| {
+            renderer.draw_image(
+                &self.heart,
+                &Rect::new_from_x_y(0, 0, width, height),
+                &Rect::new_from_x_y(
+                    HEART_MARGIN + life * (width + HEART_MARGIN / 2),
+                    HEART_MARGIN,
+                    width,
+                    height,
+                ),
+            );
+        });
     }
-}
 
-pub struct Barrier {
-    image: Image,
-}
+    // falseにすると少年が走っていても止まる。trueで通常の動きに戻る
+    pub fn set_scrolling(&mut self, scrolling: bool) {
+        self.scrolling = scrolling;
+    }
 
-impl Obstacle for Barrier {
-    fn check_intersection(&self, boy: &mut RedHatBoy) {
-        if boy.bounding_box().intersects(self.image.bounding_box()) {
-            boy.knock_out();
+    fn velocity(&self) -> i16 {
+        if !self.scrolling {
+            return 0;
         }
+        scroll_velocity(self.boy.walk_speed(), self.wind_push())
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        self.image.draw(renderer);
+    fn wind_push(&self) -> i16 {
+        if self.boy.feels_wind() {
+            self.wind.push(self.boy.is_jumping())
+        } else {
+            0
+        }
     }
 
-    fn move_horizontally(&mut self, x: i16) {
-        self.image.move_horizontally(x);
+    // 少年より後ろにある障害物は除いて、一番近い障害物を返す
+    pub fn next_obstacle_ahead(&self) -> Option<&dyn Obstacle> {
+        let boy_x = self.boy.bounding_box().x();
+        self.obstacles
+            .iter()
+            .filter(|obstacle| obstacle.right() > boy_x)
+            .min_by_key(|obstacle| obstacle.left())
+            .map(|obstacle| obstacle.as_ref())
     }
 
-    fn right(&self) -> i16 {
-        self.image.right()
+    // 重なっている場合は0
+    pub fn distance_to_next(&self) -> Option<i16> {
+        let boy_right = self.boy.bounding_box().right();
+        self.next_obstacle_ahead()
+            .map(|obstacle| (obstacle.left() - boy_right).max(0))
     }
-}
 
-impl Barrier {
-    pub fn new(image: Image) -> Self {
-        Barrier { image }
+    pub fn set_spawn_weights(&mut self, spawn_weights: SpawnWeights) {
+        self.segment_picker.set_weights(spawn_weights);
     }
-}
 
-#[derive(Copy, Clone)]
-enum RedHatBoyStateMachine {
-    Idle(RedHatBoyState<Idle>),
-    Running(RedHatBoyState<Running>),
-    Sliding(RedHatBoyState<Sliding>),
-    Jumping(RedHatBoyState<Jumping>),
-    Falling(RedHatBoyState<Falling>),
-    KnockedOut(RedHatBoyState<KnockedOut>),
-}
-impl From<RedHatBoyState<Idle>> for RedHatBoyStateMachine {
-    fn from(state: RedHatBoyState<Idle>) -> Self {
-        RedHatBoyStateMachine::Idle(state)
+    pub fn set_camera_config(&mut self, config: CameraConfig) {
+        self.camera.set_config(config);
     }
-}
-impl From<RedHatBoyState<Running>> for RedHatBoyStateMachine {
-    fn from(state: RedHatBoyState<Running>) -> Self {
-        RedHatBoyStateMachine::Running(state)
+
+    // カメラはスタート位置からのずれで動くので、ゴールの線も同じくスタート位置から測る。
+    // 線は流れてくるので、画面上の位置がそのまま残りの距離になる
+    fn camera_level_end(&self) -> Option<i16> {
+        self.finish_line
+            .as_ref()
+            .map(|finish_line| finish_line.right() - red_hat_boy_states::STARTING_POINT)
     }
-}
-impl From<RedHatBoyState<Sliding>> for RedHatBoyStateMachine {
-    fn from(state: RedHatBoyState<Sliding>) -> Self {
-        RedHatBoyStateMachine::Sliding(state)
+
+    // 障害物を動かし、少年とx方向に重なりうるものだけ当たり判定をする
+    fn move_obstacles(&mut self, velocity: i16) {
+        debug_assert!(
+            !self.obstacles_moved,
+            "obstacles must move only once per tick"
+        );
+        self.obstacles_moved = true;
+        scroll_obstacles(&mut self.obstacles, velocity);
+        self.obstacles_scrolled -= velocity as i32;
+        let boy_box = self.boy.bounding_box();
+        let mut landed = vec![];
+        obstacles_in_range(&self.obstacles, boy_box.x(), boy_box.right()).for_each(
+            |(index, obstacle)| {
+                if obstacle.check_intersection(&mut self.boy) == Contact::Land {
+                    landed.push(index);
+                }
+            },
+        );
+        self.obstacles
+            .iter_mut()
+            .enumerate()
+            .for_each(|(index, obstacle)| obstacle.update(landed.contains(&index)));
     }
-}
-impl From<RedHatBoyState<Jumping>> for RedHatBoyStateMachine {
-    fn from(state: RedHatBoyState<Jumping>) -> Self {
-        RedHatBoyStateMachine::Jumping(state)
+
+    // 障害物のright()が少年を越えた時に1度だけ採点する。
+    // 採点済みの位置はワールド座標で覚えるので、少年が後ろに戻っても二重に数えない
+    fn score_passed_obstacles(&mut self) {
+        if self.boy.is_grounded() {
+            self.air_chain = 0;
+        }
+        let boy_box = self.boy.hazard_box();
+        let boy_left = boy_box.x() as i32 + self.obstacles_scrolled;
+        // 採点するとscored_untilが進むので、このtickで越えたかは進める前の値で決める
+        let scored_until = self.scored_until;
+        for index in 0..self.obstacles.len() {
+            let world_right = self.obstacles[index].right() as i32 + self.obstacles_scrolled;
+            if world_right <= scored_until || world_right > boy_left {
+                continue;
+            }
+            self.scored_until = self.scored_until.max(world_right);
+            if self.boy.is_down() {
+                continue;
+            }
+            if self.boy.is_jumping() {
+                self.air_chain += 1;
+                self.award(self.scoring.air_clear_bonus * self.air_chain, "Air Time!");
+            }
+            let clearance = self.obstacles[index].clearance(&boy_box);
+            if clearance
+                .is_some_and(|clearance| (0..=self.scoring.near_miss_distance).contains(&clearance))
+            {
+                self.award(self.scoring.near_miss_bonus, "Close Call!");
+            }
+        }
     }
-}
-impl From<SlidingEndState> for RedHatBoyStateMachine {
-    fn from(end_state: SlidingEndState) -> Self {
-        match end_state {
-            SlidingEndState::Complete(running_state) => running_state.into(),
-            SlidingEndState::Sliding(sliding_state) => sliding_state.into(),
+
+    fn award(&mut self, points: u32, reason: &str) {
+        self.score += points;
+        self.bonus_popups.push(BonusPopup {
+            text: format!("+{} {}", points, reason),
+            frames_left: BONUS_POPUP_FRAMES,
+        });
+    }
+
+    // 変わった時だけ反映して保存する
+    fn change_settings(&mut self, f: impl FnOnce(&mut Settings)) {
+        let mut settings = self.settings.clone();
+        f(&mut settings);
+        if settings == self.settings {
+            return;
+        }
+        self.audio.set_settings(settings.audio());
+        self.boy.set_input_windows(settings.input_windows());
+        self.settings = settings;
+        if let Err(err) = self.settings.save(self.store.as_ref()) {
+            error!("Could not save settings {:#?}", err);
         }
     }
-}
-impl From<JumpingEndState> for RedHatBoyStateMachine {
-    fn from(end_state: JumpingEndState) -> Self {
-        match end_state {
-            JumpingEndState::Landing(running_state) => running_state.into(),
-            JumpingEndState::Jumping(jumping_state) => jumping_state.into(),
+
+    // 設定を途中で変えても、共有されたseedの走りは調整しないまま
+    fn anti_frustration(&self) -> bool {
+        self.settings.anti_frustration && !self.shared_seed
+    }
+
+    // 置いてある足場は作った時のセルを持っているので、新しいシートは次に作る足場から使われる
+    fn apply_reloaded_sheets(&mut self) {
+        let changed = match &self.sheet_reloader {
+            Some(reloader) => reloader.take_changed(),
+            None => return,
+        };
+        changed
+            .into_iter()
+            .for_each(|(name, sheet)| match name.as_str() {
+                "boy" => {
+                    if let Err(err) = self.boy.set_sheet(sheet) {
+                        warn!("Keeping the previous boy sheet: {:#}", err);
+                    }
+                }
+                "tiles" => self.obstacle_sheet = Rc::new(self.obstacle_sheet.with_sheet(sheet)),
+                _ => {}
+            });
+    }
+
+    pub fn dump_scene(&self) -> String {
+        let scene = Scene {
+            seed: self.seed,
+            distance: self.distance,
+            obstacles: self
+                .obstacles
+                .iter()
+                .filter_map(|obstacle| obstacle.snapshot())
+                .collect(),
+        };
+        serde_json::to_string(&scene).expect("Scene is always serializable")
+    }
+
+    // 障害物を保存した時と同じ座標に置き直す。以降のセグメントは保存したseedから作り直す
+    pub fn load_scene(&mut self, json: &str) -> Result<()> {
+        let scene = serde_json::from_str::<Scene>(json)
+            .map_err(|err| anyhow!("Could not read scene '{}': {}", json, err))?;
+        let obstacles = scene
+            .obstacles
+            .into_iter()
+            .map(|snapshot| -> Box<dyn Obstacle> {
+                match snapshot {
+                    ObstacleSnapshot::Stone {
+                        x,
+                        y,
+                        filter,
+                        scale,
+                    } => Box::new(
+                        Barrier::new(
+                            Image::new(self.stone.clone(), Point { x, y })
+                                .with_scale(scale)
+                                .with_top(y),
+                        )
+                        .with_filter(filter.as_deref()),
+                    ),
+                    ObstacleSnapshot::Platform {
+                        x,
+                        y,
+                        sprites,
+                        filter,
+                        scale,
+                    } => Box::new(
+                        segments::floating_platform(
+                            self.obstacle_sheet.clone(),
+                            Point { x, y },
+                            &sprites.iter().map(String::as_str).collect::<Vec<_>>(),
+                            filter.as_deref(),
+                        )
+                        .with_scale(scale)
+                        .with_top(y),
+                    ),
+                    ObstacleSnapshot::CrumblingPlatform {
+                        x,
+                        y,
+                        sprites,
+                        filter,
+                        scale,
+                    } => Box::new(CrumblingPlatform::new(
+                        segments::floating_platform(
+                            self.obstacle_sheet.clone(),
+                            Point { x, y },
+                            &sprites.iter().map(String::as_str).collect::<Vec<_>>(),
+                            filter.as_deref(),
+                        )
+                        .with_scale(scale)
+                        .with_top(y),
+                    )),
+                }
+            })
+            .collect();
+        self.obstacles = vec![];
+        self.add_obstacles(obstacles);
+        self.timeline = rightmost(&self.obstacles);
+        self.seed = scene.seed;
+        self.rng = StdRng::seed_from_u64(scene.seed);
+        self.distance = scene.distance;
+        info!("Loaded a scene with {} obstacles", self.obstacles.len());
+        Ok(())
+    }
+
+    fn update_settings_menu(&mut self, keystate: &KeyState) {
+        let mut menu = match self.settings_menu.take() {
+            Some(menu) => menu,
+            None => return,
+        };
+        menu.appear.advance(1.0);
+        let mut open = true;
+        self.change_settings(|settings| {
+            open = keystate
+                .typed_keys()
+                .iter()
+                .all(|key| menu.handle_key(key, settings));
+        });
+        if open {
+            self.settings_menu = Some(menu);
         }
     }
-}
-impl From<RedHatBoyState<Falling>> for RedHatBoyStateMachine {
-    fn from(state: RedHatBoyState<Falling>) -> Self {
-        RedHatBoyStateMachine::Falling(state)
+
+    fn update_bonus_popups(&mut self) {
+        self.bonus_popups
+            .iter_mut()
+            .for_each(|popup| popup.frames_left -= 1);
+        self.bonus_popups.retain(|popup| popup.frames_left > 0);
     }
-}
-impl From<RedHatBoyState<KnockedOut>> for RedHatBoyStateMachine {
-    fn from(state: RedHatBoyState<KnockedOut>) -> Self {
-        RedHatBoyStateMachine::KnockedOut(state)
+
+    fn draw_score(&self, renderer: &Renderer) {
+        renderer.draw_text(&format!("Score: {}", self.score), &Point { x: 480, y: 60 });
+        self.bonus_popups
+            .iter()
+            .enumerate()
+            .for_each(|(index, popup)| {
+                renderer.draw_text(
+                    &popup.text,
+                    &Point {
+                        x: 300,
+                        y: 140 + index as i16 * 40,
+                    },
+                );
+            });
     }
-}
-impl From<FallingEndState> for RedHatBoyStateMachine {
-    fn from(end_state: FallingEndState) -> Self {
-        match end_state {
-            FallingEndState::Complete(knocked_out_state) => knocked_out_state.into(),
-            FallingEndState::Falling(falling_state) => falling_state.into(),
+
+    // obstaclesは常にleftの昇順に並べておく
+    fn add_obstacles(&mut self, mut next_obstacles: Vec<Box<dyn Obstacle>>) {
+        self.obstacles.append(&mut next_obstacles);
+        self.obstacles.sort_by_key(|obstacle| obstacle.left());
+    }
+
+    // 上限に達した時に1回だけ警告する
+    fn at_obstacle_cap(&mut self) -> bool {
+        let capped = self.obstacles.len() >= self.spawn_config.max_obstacles;
+        if capped && !self.obstacle_cap_hit {
+            warn!(
+                "{} obstacles reached the cap, pausing spawns",
+                self.obstacles.len()
+            );
         }
+        self.obstacle_cap_hit = capped;
+        capped
     }
-}
 
-pub enum Event {
-    Run,
-    Slide,
-    Jump,
-    KnockOut,
-    Land(i16),
-    Update,
-}
+    fn has_more_segments(&self) -> bool {
+        match &self.segment_source {
+            SegmentSource::Procedural => self.distance < LEVEL_DISTANCE,
+            SegmentSource::Authored(specs) => !specs.is_empty(),
+            SegmentSource::Practice(_) => true,
+        }
+    }
 
-impl RedHatBoyStateMachine {
-    fn transition(self, event: Event) -> Self {
-        match (self, event) {
-            (RedHatBoyStateMachine::Idle(state), Event::Run) => state.run().into(),
-            (RedHatBoyStateMachine::Running(state), Event::Slide) => state.slide().into(),
-            (RedHatBoyStateMachine::Running(state), Event::Jump) => state.jump().into(),
-            (RedHatBoyStateMachine::Running(state), Event::KnockOut) => state.knock_out().into(),
-            (RedHatBoyStateMachine::Sliding(state), Event::KnockOut) => state.knock_out().into(),
-            (RedHatBoyStateMachine::Jumping(state), Event::KnockOut) => state.knock_out().into(),
-            (RedHatBoyStateMachine::Idle(state), Event::Update) => state.update().into(),
-            (RedHatBoyStateMachine::Running(state), Event::Update) => state.update().into(),
-            (RedHatBoyStateMachine::Sliding(state), Event::Update) => state.update().into(),
-            (RedHatBoyStateMachine::Jumping(state), Event::Update) => state.update().into(),
-            (RedHatBoyStateMachine::Falling(state), Event::Update) => state.update().into(),
-            (RedHatBoyStateMachine::Jumping(state), Event::Land(position)) => {
-                state.land_on(position).into()
+    // 世界がvelocityだけ流れた後に、足りなければ次のセグメントかゴールを置く
+    fn advance_timeline(&mut self, velocity: i16) {
+        if self.timeline < self.spawn_config.timeline_minimum && self.finish_line.is_none() {
+            if self.at_obstacle_cap() {
+                // 減るまでは生成しない。再開した時に画面内に現れないよう、
+                // timelineは画面の右端より手前には来させない
+                self.timeline = (self.timeline + velocity).max(WIDTH);
+            } else if self.has_more_segments() {
+                self.generate_next_segment();
+            } else {
+                self.place_finish_line();
             }
-            (RedHatBoyStateMachine::Running(state), Event::Land(position)) => {
-                state.land_on(position).into()
+        } else {
+            self.timeline += velocity;
+        }
+    }
+
+    // デバッグ用。distanceだけ走ったことにして、障害物と背景を流し生成も進める。
+    // 当たり判定と採点はしない。ゴールに着いたらそこで止める
+    pub fn skip_forward(&mut self, distance: i16) {
+        for step in skip_steps(distance, SKIP_STEP) {
+            let velocity = -step;
+            self.distance += step as i32;
+            self.travelled += step as u64;
+            self.obstacles.retain(|obstacle| obstacle.right() > 0);
+            scroll_obstacles(&mut self.obstacles, velocity);
+            self.obstacles_scrolled += step as i32;
+            self.scroll_background(velocity);
+            if let Some(finish_line) = &mut self.finish_line {
+                finish_line.move_horizontally(velocity);
             }
-            (RedHatBoyStateMachine::Sliding(state), Event::Land(position)) => {
-                state.land_on(position).into()
+            self.advance_timeline(velocity);
+            self.update_theme();
+            self.check_finish();
+            if self.level_complete.is_some() {
+                break;
             }
-            _ => self,
         }
+        // 飛ばした障害物は後から採点しない
+        let boy_left = self.boy.hazard_box().x() as i32 + self.obstacles_scrolled;
+        self.scored_until = self.scored_until.max(boy_left);
     }
 
-    fn frame_name(&self) -> &str {
-        match self {
-            RedHatBoyStateMachine::Idle(state) => state.frame_name(),
-            RedHatBoyStateMachine::Running(state) => state.frame_name(),
-            RedHatBoyStateMachine::Sliding(state) => state.frame_name(),
-            RedHatBoyStateMachine::Jumping(state) => state.frame_name(),
-            RedHatBoyStateMachine::Falling(state) => state.frame_name(),
-            RedHatBoyStateMachine::KnockedOut(state) => state.frame_name(),
+    fn scroll_background(&mut self, velocity: i16) {
+        self.background.move_horizontally(velocity);
+        if let Some(fade) = &mut self.theme_fade {
+            fade.background.move_horizontally(velocity);
         }
     }
 
-    fn context(&self) -> &RedHatBoyContext {
-        match self {
-            RedHatBoyStateMachine::Idle(state) => &state.context(),
-            RedHatBoyStateMachine::Running(state) => &state.context(),
-            RedHatBoyStateMachine::Sliding(state) => &state.context(),
-            RedHatBoyStateMachine::Jumping(state) => &state.context(),
-            RedHatBoyStateMachine::Falling(state) => &state.context(),
-            RedHatBoyStateMachine::KnockedOut(state) => &state.context(),
+    // テーマの境目を越えたら切り替えを始め、流れた距離に合わせて背景と曲を混ぜる。
+    // 切り替え中に次の境目を越えた時は、今の切り替えが終わってから始める
+    fn update_theme(&mut self) {
+        if self.theme_fade.is_none() {
+            let boy_right = self.boy.bounding_box().right() as i32 + self.obstacles_scrolled;
+            if matches!(self.theme_markers.front(), Some((x, _)) if *x <= boy_right) {
+                if let Some((_, name)) = self.theme_markers.pop_front() {
+                    self.start_theme(&name);
+                }
+            }
+        }
+        let fade = match &mut self.theme_fade {
+            Some(fade) => fade,
+            None => return,
+        };
+        fade.progress = fade_progress(self.obstacles_scrolled - fade.start, THEME_FADE_DISTANCE);
+        if let Some(next) = &fade.music {
+            let result = next.fade_to(fade.progress).and_then(|_| match &self.music {
+                Some(current) => current.fade_to(1.0 - fade.progress),
+                None => Ok(()),
+            });
+            if let Err(err) = result {
+                error!("{:#?}", err);
+            }
+        }
+        if fade.progress < 1.0 {
+            return;
+        }
+        if let Some(fade) = self.theme_fade.take() {
+            self.background = fade.background;
+            if let Some(next) = fade.music {
+                if let Some(Err(err)) = self.music.replace(next).map(|current| current.stop()) {
+                    error!("{:#?}", err);
+                }
+            }
         }
     }
 
-    fn update(self) -> Self {
-        self.transition(Event::Update)
+    fn start_theme(&mut self, name: &str) {
+        // 名前はロード時に検証済み
+        let theme = match self.themes.get(name) {
+            Some(theme) => theme.clone(),
+            None => return,
+        };
+        let music = theme
+            .music
+            .and_then(|sound| match self.audio.play_music(&sound, 0.0) {
+                Ok(music) => Some(music),
+                Err(err) => {
+                    error!("{:#?}", err);
+                    None
+                }
+            });
+        self.theme_fade = Some(ThemeFade {
+            background: self.background.with_element(theme.background),
+            music,
+            start: self.obstacles_scrolled,
+            progress: 0.0,
+        });
+    }
+
+    fn generate_next_segment(&mut self) {
+        if let SegmentSource::Authored(specs) = &mut self.segment_source {
+            if let Some(spec) = specs.pop_front() {
+                // 種類はロード時に検証済み
+                let next_obstacles = segments::segment(
+                    &spec.kind,
+                    self.stone.clone(),
+                    self.obstacle_sheet.clone(),
+                    self.timeline + spec.offset,
+                    &self.palettes,
+                    spec.scale,
+                )
+                .unwrap_or_default();
+                if let Some(theme) = spec.theme {
+                    let left = (self.timeline + spec.offset) as i32 + self.obstacles_scrolled;
+                    self.theme_markers.push_back((left, theme));
+                }
+                self.timeline = rightmost(&next_obstacles);
+                self.add_obstacles(next_obstacles);
+            }
+            return;
+        }
+
+        // セグメントが置かれるのは少年からtimelineだけ先
+        let distance = self.distance + self.timeline as i32;
+        self.segment_picker
+            .set_easing(self.anti_frustration() && self.deaths.eases(distance));
+        let kind = match &self.segment_source {
+            SegmentSource::Practice(kind) => kind.name(),
+            _ => self.segment_picker.next(&mut self.rng),
+        };
+
+        // kindはSEGMENT_TYPESの中から選ばれる
+        let next_obstacles = segments::segment(
+            kind,
+            self.stone.clone(),
+            self.obstacle_sheet.clone(),
+            self.timeline + self.spawn_config.obstacle_buffer,
+            &self.palettes,
+            1.0,
+        )
+        .unwrap_or_default();
+
+        self.timeline = rightmost(&next_obstacles);
+        self.add_obstacles(next_obstacles);
     }
 }
 
-pub struct RedHatBoy {
-    state_machine: RedHatBoyStateMachine,
-    sprite_sheet: Sheet,
-    image: HtmlImageElement,
+pub enum WalkTheDog {
+    Loading {
+        telemetry: Option<TelemetrySink>,
+        seed: Option<u64>,
+        daily: Option<u32>,
+        practice: Option<SegmentKind>,
+        settings: Settings,
+    },
+    Loaded(Box<Walk>),
 }
 
-impl RedHatBoy {
-    fn new(sheet: Sheet, image: HtmlImageElement) -> Self {
-        RedHatBoy {
-            state_machine: RedHatBoyStateMachine::Idle(RedHatBoyState::new()),
-            sprite_sheet: sheet,
-            image,
+#[async_trait(?Send)]
+impl Game for WalkTheDog {
+    async fn initialize(&self) -> Result<Box<dyn Game>> {
+        match self {
+            WalkTheDog::Loading {
+                telemetry,
+                seed,
+                daily,
+                practice,
+                settings,
+            } => {
+                let assets = Assets::load_manifest("assets.json").await?;
+                let sprite_sheet = Rc::new(SpriteSheet::new(
+                    assets.sheet("tiles")?.clone(),
+                    assets.image("tiles")?.clone(),
+                ));
+
+                let mut rhb =
+                    RedHatBoy::new(assets.sheet("boy")?.clone(), assets.image("boy")?.clone())?;
+                rhb.set_input_windows(settings.input_windows());
+
+                // ?level=level1.json のように指定された場合は作成済みのレベルを使う
+                let level = match browser::query_param("level")? {
+                    Some(path) => Some(WalkTheDog::load_level(&path).await?),
+                    None => None,
+                };
+
+                if let Some(next) = level.as_ref().and_then(|level| level.next.as_deref()) {
+                    Assets::prefetch(&[next]);
+                }
+
+                // 音が出せない時や曲が読めない時も、ゲームは音無しで続ける。
+                // タブが隠れている間は音楽も止める
+                let audio = Audio::new(settings.audio()).unwrap_or_else(|err| {
+                    error!("Could not start audio, playing silently {:#?}", err);
+                    Audio::silent(settings.audio())
+                });
+                if let Err(err) = audio.pause_while_hidden() {
+                    error!("{:#?}", err);
+                }
+                let music = match level.as_ref().and_then(|level| level.music.as_deref()) {
+                    Some(music) => match audio
+                        .load_sound(music)
+                        .await
+                        .and_then(|sound| audio.play_music(&sound, 1.0))
+                    {
+                        Ok(music) => Some(music),
+                        Err(err) => {
+                            error!("Could not play music '{}' {:#?}", music, err);
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                let mut themes = HashMap::new();
+                for (name, spec) in level.iter().flat_map(|level| &level.themes) {
+                    let music = match &spec.music {
+                        Some(music) => match audio.load_sound(music).await {
+                            Ok(sound) => Some(sound),
+                            Err(err) => {
+                                error!("Could not load music '{}' {:#?}", music, err);
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+                    let theme = Theme {
+                        background: assets.image(&spec.background)?.clone(),
+                        music,
+                    };
+                    themes.insert(name.clone(), theme);
+                }
+
+                let background = match &level {
+                    Some(level) => assets.image(&level.background)?.clone(),
+                    None => assets.image("background")?.clone(),
+                };
+                let parallax = level
+                    .as_ref()
+                    .map_or(default_parallax(), |level| level.background_parallax);
+                let stone = Rc::new(assets.image("stone")?.clone());
+                let heart = assets.image("heart")?.clone();
+                let spawn_config = SpawnConfig::for_width(WIDTH);
+                let palettes = Palettes::default();
+                let course = match (practice, level) {
+                    (Some(kind), _) => Course {
+                        obstacles: segments::segment(
+                            kind.name(),
+                            stone.clone(),
+                            sprite_sheet.clone(),
+                            0,
+                            &palettes,
+                            1.0,
+                        )?,
+                        source: SegmentSource::Practice(*kind),
+                        length: None,
+                    },
+                    (None, Some(level)) => Course {
+                        length: Some(authored_length(
+                            &level.segments,
+                            &stone,
+                            &sprite_sheet,
+                            spawn_config.obstacle_buffer,
+                        )?),
+                        obstacles: vec![],
+                        source: SegmentSource::Authored(level.segments.into()),
+                    },
+                    (None, None) => Course {
+                        obstacles: stone_and_platform(
+                            stone.clone(),
+                            sprite_sheet.clone(),
+                            0,
+                            palettes.for_tier(segments::difficulty("stone_and_platform")),
+                            1.0,
+                        ),
+                        source: SegmentSource::Procedural,
+                        // ゴールはLEVEL_DISTANCEを過ぎてから画面の右端の先に置かれる
+                        length: Some(LEVEL_DISTANCE + spawn_config.timeline_minimum as i32),
+                    },
+                };
+                // ?seed=1234 のように指定すると同じ障害物の並びを再現できる。
+                // その時は並びが変わらないよう、倒れた記録による調整はしない
+                let shared_seed = seed.is_some() || browser::query_param("seed")?.is_some();
+                let seed = match (seed, browser::query_param("seed")?) {
+                    (Some(seed), _) => *seed,
+                    (None, Some(seed)) => seed
+                        .parse::<u64>()
+                        .map_err(|err| anyhow!("Invalid seed '{}': {:#?}", seed, err))?,
+                    (None, None) => thread_rng().gen(),
+                };
+                info!("Using seed {}", seed);
+                let images = WalkImages {
+                    // カメラが先を映す分も背景で埋める
+                    background: TiledImage::new(background, 0, WIDTH + CAMERA_LOOK_AHEAD)
+                        .with_parallax(parallax),
+                    stone,
+                    heart,
+                    obstacle_sheet: sprite_sheet,
+                };
+                let mut walk = Walk::new(
+                    rhb,
+                    images,
+                    course,
+                    seed,
+                    settings,
+                    Rc::new(LocalStorage),
+                    audio,
+                );
+                walk.daily = *daily;
+                walk.telemetry = telemetry.clone();
+                // ?trajectory を付けるとジャンプの軌道を表示する
+                walk.show_trajectory = browser::query_param("trajectory")?.is_some();
+                // ?hitboxes を付けると少年の当たり判定を表示する
+                walk.show_hitboxes = browser::query_param("hitboxes")?.is_some();
+                if let Some(weights) = spawn_weights_from_query() {
+                    walk.set_spawn_weights(weights);
+                }
+                if let Some(config) = camera_config_from_query() {
+                    walk.set_camera_config(config);
+                }
+                walk.best_distance = load_best_distance(*daily);
+                walk.leaderboard = Leaderboard::from_query()?.map(Rc::new);
+                // ?hotreload を付けると、開発中はシートのJSONの変更を走りの途中で反映する
+                if cfg!(debug_assertions) && browser::query_param("hotreload")?.is_some() {
+                    walk.sheet_reloader = Some(assets.watch_sheets()?);
+                }
+                walk.themes = themes;
+                walk.music = music;
+                walk.deaths = DeathHistory::load(walk.store.as_ref());
+                walk.shared_seed = shared_seed;
+                // 報告された #scene=... の場面から始める
+                if let Some(scene) = browser::hash_param("scene")? {
+                    if let Err(err) = walk.load_scene(&scene) {
+                        warn!("{:#}", err);
+                    }
+                }
+                walk.emit(|| TelemetryEvent::RunStarted { seed: walk.seed });
+                Ok(Box::new(WalkTheDog::Loaded(Box::new(walk))))
+            }
+            WalkTheDog::Loaded(_) => Err(anyhow!("Error: Game is already initialized!")),
         }
     }
 
-    fn frame_name(&self) -> String {
-        format!(
-            "{} ({}).png",
-            self.state_machine.frame_name(),
-            (self.state_machine.context().frame / 3) + 1
-        )
-    }
+    fn update(&mut self, keystate: &KeyState, _tick: u64) {
+        if let WalkTheDog::Loaded(walk) = self {
+            walk.obstacles_moved = false;
+            walk.apply_reloaded_sheets();
+            if walk.settings_menu.is_some() {
+                walk.update_settings_menu(keystate);
+                return;
+            }
+            // 名前の入力中は文字のキーを操作として扱わない
+            if walk.entering_name() {
+                walk.update_name_entry(keystate);
+            } else if walk.game_over.is_some() && keystate.was_pressed(COPY_LINK_KEY) {
+                if let Err(err) = browser::set_hash_param("seed", &walk.seed.to_string()) {
+                    error!("Could not write the seed into the URL {:#?}", err);
+                }
+            } else if keystate.was_pressed(REPORT_KEY) {
+                let scene = walk.dump_scene();
+                info!("Scene: {}", scene);
+                if let Err(err) = browser::set_hash_param("scene", &scene) {
+                    error!("Could not write the scene into the URL {:#?}", err);
+                }
+                if let Err(err) = walk
+                    .thumbnail(REPORT_THUMBNAIL_WIDTH)
+                    .and_then(|canvas| browser::screenshot(&canvas))
+                    .and_then(|url| browser::download(&url, "scene.png"))
+                {
+                    error!("Could not save the scene thumbnail {:#?}", err);
+                }
+            } else if cfg!(debug_assertions) && keystate.was_pressed(SKIP_KEY) {
+                walk.skip_forward(SKIP_DISTANCE);
+            } else if keystate.was_pressed(SETTINGS_KEY) {
+                walk.settings_menu = Some(SettingsMenu::default());
+                return;
+            } else if keystate.was_pressed(MUTE_KEY) {
+                walk.change_settings(|settings| settings.muted = !settings.muted);
+            } else if keystate.was_pressed(SLOWER_KEY) {
+                walk.change_settings(|settings| {
+                    settings.game_speed = settings.game_speed().slower().value()
+                });
+            } else if keystate.was_pressed(FASTER_KEY) {
+                walk.change_settings(|settings| {
+                    settings.game_speed = settings.game_speed().faster().value()
+                });
+            }
+            // ゴールしたらスクロールを止める
+            if walk.level_complete.is_some() {
+                return;
+            }
+            let bindings = walk.settings.key_bindings();
+            // 短いタップも拾えるように、押されているかに加えて押されたかも見る
+            if bindings.is_pressed(keystate, Action::Run)
+                || bindings.was_pressed(keystate, Action::Run)
+            {
+                walk.boy.run_right();
+            }
+            // スライドとジャンプが同時に押されている時はジャンプを優先し、スライドはしない。
+            // しゃがみ歩き中なら立ち上がってから跳ぶ
+            let jump = bindings.is_pressed(keystate, Action::Jump)
+                || bindings.was_pressed(keystate, Action::Jump);
+            if bindings.is_pressed(keystate, Action::Slide) && !jump {
+                walk.boy.slide();
+            } else {
+                walk.boy.stand_up();
+            }
+            if jump {
+                walk.boy.jump();
+            }
+            walk.boy.update();
+            walk.report_state_changes();
+            walk.camera.follow(
+                walk.boy.pos_x() - red_hat_boy_states::STARTING_POINT,
+                walk.boy.pos_y() - red_hat_boy_states::FLOOR,
+                walk.boy.walk_speed(),
+                walk.camera_level_end(),
+            );
+            // 登場中は障害物も背景も動かさない
+            if walk.boy.in_intro() {
+                return;
+            }
+            walk.lose_life();
+            walk.wind.update(&mut walk.rng);
 
-    fn current_sprite(&self) -> Option<&Cell> {
-        self.sprite_sheet.frames.get(&self.frame_name())
+            let velocity = walk.velocity();
+            debug_assert!(
+                !walk.scrolling
+                    || velocity == scroll_velocity(walk.boy.walk_speed(), walk.wind_push()),
+                "the world must scroll at the boy's speed"
+            );
+            debug_assert!(
+                velocity <= 0 || walk.boy.is_wall_jumping(),
+                "the world only scrolls backwards while the boy bounces off a wall"
+            );
+            let checkpoint = walk.distance / TELEMETRY_CHECKPOINT;
+            walk.distance -= velocity as i32;
+            walk.travelled += (-velocity).max(0) as u64;
+            if walk.distance / TELEMETRY_CHECKPOINT > checkpoint {
+                walk.emit(|| TelemetryEvent::Checkpoint {
+                    seed: walk.seed,
+                    distance: walk.distance,
+                });
+            }
+            walk.elapsed_frames += 1;
+            // 条件を満たす要素のみを残す
+            walk.obstacles.retain(|obstacle| obstacle.right() > 0);
+            walk.move_obstacles(velocity);
+            walk.scroll_background(velocity);
+
+            if let Some(finish_line) = &mut walk.finish_line {
+                finish_line.move_horizontally(velocity);
+            }
+            walk.check_finish();
+            walk.check_passed_deaths();
+            walk.score_passed_obstacles();
+            walk.update_bonus_popups();
+
+            walk.advance_timeline(velocity);
+            walk.update_theme();
+        }
     }
 
     fn draw(&self, renderer: &Renderer) {
-        let sprite = self.current_sprite().expect("Cell not found");
-        renderer.draw_image(
-            &self.image,
-            &Rect::new_from_x_y(
-                sprite.frame.x.into(),
-                sprite.frame.y.into(),
-                sprite.frame.w.into(),
-                sprite.frame.h.into(),
-            ),
-            &self.destination_box(),
-        );
+        match self {
+            WalkTheDog::Loaded(walk) => walk.draw(renderer),
+            WalkTheDog::Loading { .. } => clear_screen(renderer),
+        }
     }
 
-    fn destination_box(&self) -> Rect {
-        let sprite = self.current_sprite().expect("Cell not found");
+    fn game_speed(&self) -> f32 {
+        match self {
+            WalkTheDog::Loaded(walk) => walk.settings.game_speed().value(),
+            WalkTheDog::Loading { .. } => 1.0,
+        }
+    }
+}
+impl WalkTheDog {
+    fn new(telemetry: Option<TelemetrySink>, settings: Settings) -> Self {
+        WalkTheDog::Loading {
+            telemetry,
+            seed: None,
+            daily: None,
+            practice: None,
+            settings,
+        }
+    }
 
-        Rect::new_from_x_y(
-            self.state_machine.context().position.x + sprite.sprite_source_size.x,
-            self.state_machine.context().position.y + sprite.sprite_source_size.y,
-            sprite.frame.w,
-            sprite.frame.h,
-        )
+    // 1種類のセグメントだけを繰り返し出す練習モード
+    pub fn new_practice(
+        telemetry: Option<TelemetrySink>,
+        settings: Settings,
+        segment: SegmentKind,
+    ) -> Self {
+        WalkTheDog::Loading {
+            telemetry,
+            seed: None,
+            daily: None,
+            practice: Some(segment),
+            settings,
+        }
     }
 
-    fn bounding_box(&self) -> Rect {
-        const X_OFFSET: i16 = 18;
-        const Y_OFFSET: i16 = 14;
-        const W_OFFSET: i16 = 28;
-        let mut bounding_box = self.destination_box();
-        bounding_box.set_x(bounding_box.x() + X_OFFSET);
-        bounding_box.w -= W_OFFSET;
-        bounding_box.set_y(bounding_box.y() + Y_OFFSET);
-        bounding_box.h -= Y_OFFSET;
-        bounding_box
+    // 共有されたURLの走りを再現する
+    fn new_seeded(telemetry: Option<TelemetrySink>, settings: Settings, seed: u64) -> Self {
+        WalkTheDog::Loading {
+            telemetry,
+            seed: Some(seed),
+            daily: None,
+            practice: None,
+            settings,
+        }
     }
 
-    fn pos_y(&self) -> i16 {
-        self.state_machine.context().position.y
+    // その日は誰でも同じ並びになるデイリーチャレンジ。最長距離は普段の記録とは別に残す
+    pub fn new_daily(telemetry: Option<TelemetrySink>, settings: Settings, day: u32) -> Self {
+        WalkTheDog::Loading {
+            telemetry,
+            seed: Some(daily_seed(day)),
+            daily: Some(day),
+            practice: None,
+            settings,
+        }
     }
 
-    fn velocity_y(&self) -> i16 {
-        self.state_machine.context().velocity.y
+    pub async fn load_level(path: &str) -> Result<Level> {
+        let level = engine::load_json(path).await?.into_serde::<Level>()?;
+        check_level(&level).map_err(|err| anyhow!("{}: {}", path, err))?;
+        info!(
+            "Loaded level {} with {} segments",
+            path,
+            level.segments.len()
+        );
+        Ok(level)
     }
+}
 
-    fn update(&mut self) {
-        self.state_machine = self.state_machine.update();
+pub struct FinishLine {
+    x: i16,
+}
+
+impl FinishLine {
+    fn draw(&self, renderer: &Renderer) {
+        renderer.fill_rect(
+            &Rect::new_from_x_y(self.x, 0, FINISH_LINE_WIDTH, HEIGHT),
+            "#ffffff",
+        );
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.x += x;
+    }
+
+    fn right(&self) -> i16 {
+        self.x + FINISH_LINE_WIDTH
+    }
+}
+
+pub struct LevelComplete {
+    elapsed_frames: u32,
+    distance: i32,
+}
+
+impl LevelComplete {
+    fn draw(&self, renderer: &Renderer) {
+        let seconds = self.elapsed_frames as f32 * engine::FRAME_SIZE / 1000.0;
+        renderer.fill_rect(
+            &Rect::new_from_x_y(100, 200, 400, 160),
+            "rgba(0, 0, 0, 0.6)",
+        );
+        renderer.draw_text("Level Complete!", &Point { x: 300, y: 250 });
+        renderer.draw_text(&format!("Time: {:.1}s", seconds), &Point { x: 300, y: 295 });
+        renderer.draw_text(
+            &format!("Distance: {}", self.distance),
+            &Point { x: 300, y: 335 },
+        );
+    }
+}
+
+// 名前の入力欄。表示できる文字だけを受け付け、Enterで確定する
+#[derive(Default)]
+pub struct NameEntry {
+    name: String,
+}
+
+impl NameEntry {
+    // Enterが押されたらtrue
+    fn handle_key(&mut self, key: &str) -> bool {
+        match key {
+            "Enter" => return true,
+            "Backspace" => {
+                self.name.pop();
+            }
+            _ => {
+                let mut chars = key.chars();
+                if let (Some(c), None) = (chars.next(), chars.next()) {
+                    if !c.is_control() && self.name.chars().count() < MAX_NAME_LENGTH {
+                        self.name.push(c);
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    // 空のまま確定した時は既定の名前にする
+    fn name(&self) -> &str {
+        if self.name.trim().is_empty() {
+            PLAYER_NAME
+        } else {
+            &self.name
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        renderer.draw_text("New best! Enter your name", &Point { x: 300, y: 270 });
+        renderer.draw_text(&format!("{}_", self.name), &Point { x: 300, y: 315 });
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SettingsItem {
+    Sound,
+    Volume,
+    GameSpeed,
+    Keys,
+    Hitboxes,
+    AntiFrustration,
+}
+
+const SETTINGS_ITEMS: [SettingsItem; 6] = [
+    SettingsItem::Sound,
+    SettingsItem::Volume,
+    SettingsItem::GameSpeed,
+    SettingsItem::Keys,
+    SettingsItem::Hitboxes,
+    SettingsItem::AntiFrustration,
+];
+
+impl SettingsItem {
+    fn label(&self, settings: &Settings) -> String {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        match self {
+            SettingsItem::Sound => format!("Sound: {}", on_off(!settings.muted)),
+            SettingsItem::Volume => format!("Volume: {:.0}%", settings.volume * 100.0),
+            SettingsItem::GameSpeed => format!("Game speed: {:.2}x", settings.game_speed),
+            SettingsItem::Keys => format!("Keys: {}", settings.key_preset.name()),
+            SettingsItem::Hitboxes => format!("Hitboxes: {}", on_off(settings.show_hitboxes)),
+            SettingsItem::AntiFrustration => {
+                format!("Assist: {}", on_off(settings.anti_frustration))
+            }
+        }
+    }
+
+    // 切り替えの項目は左右どちらでも反転する
+    fn adjust(&self, settings: &mut Settings, increase: bool) {
+        match self {
+            SettingsItem::Sound => settings.muted = !settings.muted,
+            SettingsItem::Volume => {
+                let step = if increase { VOLUME_STEP } else { -VOLUME_STEP };
+                settings.volume = (settings.volume + step).clamp(0.0, 1.0);
+            }
+            SettingsItem::GameSpeed => {
+                let speed = settings.game_speed();
+                let speed = if increase {
+                    speed.faster()
+                } else {
+                    speed.slower()
+                };
+                settings.game_speed = speed.value();
+            }
+            SettingsItem::Keys => {
+                settings.key_preset = if increase {
+                    settings.key_preset.next()
+                } else {
+                    settings.key_preset.previous()
+                };
+            }
+            SettingsItem::Hitboxes => settings.show_hitboxes = !settings.show_hitboxes,
+            SettingsItem::AntiFrustration => settings.anti_frustration = !settings.anti_frustration,
+        }
+    }
+}
+
+pub struct SettingsMenu {
+    selected: usize,
+    appear: Tween,
+}
+
+impl Default for SettingsMenu {
+    fn default() -> Self {
+        SettingsMenu {
+            selected: 0,
+            appear: Tween::new(0.0, 1.0, MENU_FADE_FRAMES, tween::ease_out_cubic),
+        }
+    }
+}
+
+impl SettingsMenu {
+    // 閉じる時はfalse
+    fn handle_key(&mut self, key: &str, settings: &mut Settings) -> bool {
+        let count = SETTINGS_ITEMS.len();
+        match key {
+            "Escape" => return false,
+            "ArrowUp" => self.selected = (self.selected + count - 1) % count,
+            "ArrowDown" => self.selected = (self.selected + 1) % count,
+            "ArrowLeft" => SETTINGS_ITEMS[self.selected].adjust(settings, false),
+            "ArrowRight" | "Enter" | " " => SETTINGS_ITEMS[self.selected].adjust(settings, true),
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&self, renderer: &Renderer, settings: &Settings, frame: &HtmlImageElement) {
+        let fading = !self.appear.is_complete();
+        if fading {
+            renderer.set_alpha(self.appear.value() as f64);
+        }
+        draw_panel(renderer, frame, &Rect::new_from_x_y(100, 120, 400, 360));
+        renderer.draw_text("Settings", &Point { x: 300, y: 170 });
+        SETTINGS_ITEMS.iter().enumerate().for_each(|(index, item)| {
+            let cursor = if index == self.selected { "> " } else { "" };
+            renderer.draw_text(
+                &format!("{}{}", cursor, item.label(settings)),
+                &Point {
+                    x: 300,
+                    y: 225 + index as i16 * 38,
+                },
+            );
+        });
+        renderer.draw_text("Esc: close", &Point { x: 300, y: 455 });
+        if fading {
+            renderer.set_alpha(1.0);
+        }
+    }
+}
+
+// パネルの枠の太さ。枠の画像の角はこの大きさのまま描く
+const PANEL_BORDER: i16 = 12;
+
+// 枠の画像を9分割して伸ばしたパネル。文字が読めるように内側は暗くする
+fn draw_panel(renderer: &Renderer, frame: &HtmlImageElement, panel: &Rect) {
+    let (width, height) = engine::image_size(frame);
+    renderer.draw_nine_slice(
+        frame,
+        &Rect::new_from_x_y(0, 0, width as i16, height as i16),
+        PANEL_BORDER,
+        panel,
+    );
+    renderer.fill_rect(
+        &Rect::new_from_x_y(
+            panel.x() + PANEL_BORDER,
+            panel.y() + PANEL_BORDER,
+            panel.w - PANEL_BORDER * 2,
+            panel.h - PANEL_BORDER * 2,
+        ),
+        "rgba(0, 0, 0, 0.6)",
+    );
+}
+
+pub struct GameOver {
+    distance: u64,
+    seed: u64,
+    daily: Option<u32>,
+    name_entry: Option<NameEntry>,
+    // ランキングが設定されていない時はNone
+    leaderboard: Option<Rc<RefCell<LeaderboardStatus>>>,
+}
+
+impl GameOver {
+    fn draw(&self, renderer: &Renderer, frame: &HtmlImageElement) {
+        draw_panel(renderer, frame, &Rect::new_from_x_y(100, 120, 400, 360));
+        renderer.draw_text("Game Over", &Point { x: 300, y: 170 });
+        renderer.draw_text(
+            &format!("Distance: {}", self.distance),
+            &Point { x: 300, y: 215 },
+        );
+        let run = match self.daily {
+            Some(day) => format!("Daily {}", format_day(day)),
+            None => format!("Seed: {}", self.seed),
+        };
+        renderer.draw_text(
+            &format!("{} (C: copy link)", run),
+            &Point { x: 300, y: 455 },
+        );
+        if let Some(name_entry) = &self.name_entry {
+            name_entry.draw(renderer);
+            return;
+        }
+        let status = match &self.leaderboard {
+            Some(status) => status,
+            None => return,
+        };
+        match &*status.borrow() {
+            LeaderboardStatus::Loading => renderer.draw_text("...", &Point { x: 300, y: 270 }),
+            LeaderboardStatus::Offline => renderer.draw_text("offline", &Point { x: 300, y: 270 }),
+            LeaderboardStatus::Loaded(entries) => entries
+                .iter()
+                .take(LEADERBOARD_ROWS)
+                .enumerate()
+                .for_each(|(rank, entry)| {
+                    renderer.draw_text(
+                        &format!("{}. {} {}", rank + 1, entry.name, entry.score),
+                        &Point {
+                            x: 300,
+                            y: 270 + rank as i16 * 40,
+                        },
+                    );
+                }),
+        }
+    }
+}
+
+// カメラの遊び。注視点を中心としたこの大きさの四角の中で少年が動く間は、カメラは動かない
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraConfig {
+    pub dead_zone_width: i16,
+    // 床からの普通のジャンプ(高さ210)では画面が揺れない高さにしてある
+    pub dead_zone_height: i16,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        CameraConfig {
+            dead_zone_width: 0,
+            dead_zone_height: 440,
+        }
+    }
+}
+
+// URLで指定する"0,440"のような、遊びの幅と高さ
+impl FromStr for CameraConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let sizes = value
+            .split(',')
+            .map(|size| size.trim().parse::<i16>())
+            .collect::<Result<Vec<i16>, _>>()
+            .map_err(|err| anyhow!("Invalid camera dead zone '{}': {}", value, err))?;
+        match sizes[..] {
+            [dead_zone_width, dead_zone_height]
+                if dead_zone_width >= 0 && dead_zone_height >= 0 =>
+            {
+                Ok(CameraConfig {
+                    dead_zone_width,
+                    dead_zone_height,
+                })
+            }
+            _ => Err(anyhow!(
+                "Expected a dead zone width and height like '0,440', got '{}'",
+                value
+            )),
+        }
+    }
+}
+
+// 走っている方向の少し先を映すカメラ。目標へは少しずつ近づく。
+// 縦方向は遊びを出た分だけ追いかける
+pub struct Camera {
+    x: f32,
+    y: f32,
+    look_ahead: Tween,
+    config: CameraConfig,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            x: 0.0,
+            y: 0.0,
+            look_ahead: Tween::new(0.0, 0.0, 0.0, tween::linear),
+            config: CameraConfig::default(),
+        }
+    }
+}
+
+impl Camera {
+    // boy_x はスタート位置からのずれ、lift は床に立っている時からの高さの差で、上が負。
+    // level_endがあれば、カメラの左端がそこより右へは行かない
+    fn follow(&mut self, boy_x: i16, lift: i16, speed: i16, level_end: Option<i16>) {
+        let look_ahead = (CAMERA_LOOK_AHEAD * speed.signum()) as f32;
+        if look_ahead != self.look_ahead.target() {
+            self.look_ahead = Tween::new(
+                self.look_ahead.value(),
+                look_ahead,
+                CAMERA_TURN_FRAMES,
+                tween::ease_in_out,
+            );
+        }
+        self.look_ahead.advance(1.0);
+        let target_x = dead_zone_follow(
+            self.x,
+            boy_x as f32 + self.look_ahead.value(),
+            self.config.dead_zone_width,
+        );
+        let target_y = dead_zone_follow(self.y, lift as f32, self.config.dead_zone_height);
+        let (target_x, target_y) = clamp_to_level(target_x, target_y, level_end);
+        self.x = tween::lerp(self.x, target_x, CAMERA_SMOOTHING);
+        self.y = tween::lerp(self.y, target_y, CAMERA_SMOOTHING);
+    }
+
+    fn set_config(&mut self, config: CameraConfig) {
+        self.config = config;
+    }
+
+    fn position(&self) -> Point {
+        Point {
+            x: self.x.round() as i16,
+            y: self.y.round() as i16,
+        }
+    }
+}
+
+// targetがfocusを中心とした大きさsizeの範囲からはみ出した分だけ、focusを動かした位置
+fn dead_zone_follow(focus: f32, target: f32, size: i16) -> f32 {
+    let half = size.max(0) as f32 / 2.0;
+    if target > focus + half {
+        target - half
+    } else if target < focus - half {
+        target + half
+    } else {
+        focus
+    }
+}
+
+// レベルの始まりより左と、床より下は映さない。終わりと始まりが食い違う時は始まりを優先する
+fn clamp_to_level(x: f32, y: f32, level_end: Option<i16>) -> (f32, f32) {
+    let x = match level_end {
+        Some(end) => x.min(end as f32),
+        None => x,
+    };
+    (x.max(0.0), y.min(0.0))
+}
+
+// ?practice=で指定された区間。知らない名前の時は警告を出して普段通りに遊ぶ
+fn practice_segment() -> Option<SegmentKind> {
+    let name = browser::query_param("practice").ok().flatten()?;
+    let kind = SegmentKind::from_name(&name);
+    if kind.is_none() {
+        warn!(
+            "Unknown practice segment '{}', expected one of {:?}",
+            name, SEGMENT_TYPES
+        );
+    }
+    kind
+}
+
+// ?weights=60,30,10 のように、易しい・普通・難しいセグメントの出やすさを変える。
+// 正しくない時は警告を出して既定の重みで遊ぶ
+fn spawn_weights_from_query() -> Option<SpawnWeights> {
+    let weights = browser::query_param("weights").ok().flatten()?;
+    match weights.parse() {
+        Ok(weights) => Some(weights),
+        Err(err) => {
+            warn!("Ignoring spawn weights in the URL: {:#}", err);
+            None
+        }
+    }
+}
+
+// ?deadzone=0,440 のように、カメラの遊びの幅と高さを変える
+fn camera_config_from_query() -> Option<CameraConfig> {
+    let dead_zone = browser::query_param("deadzone").ok().flatten()?;
+    match dead_zone.parse() {
+        Ok(config) => Some(config),
+        Err(err) => {
+            warn!("Ignoring the camera dead zone in the URL: {:#}", err);
+            None
+        }
+    }
+}
+
+// 無い、または数値でない時はNoneにして、ランダムなseedで始める
+fn seed_from_fragment() -> Option<u64> {
+    let seed = browser::hash_param("seed").ok().flatten()?;
+    match seed.parse() {
+        Ok(seed) => Some(seed),
+        Err(err) => {
+            warn!("Ignoring invalid seed '{}' in the URL: {}", seed, err);
+            None
+        }
+    }
+}
+
+fn load_best_distance(daily: Option<u32>) -> u64 {
+    let key = match daily {
+        Some(_) => DAILY_BEST_KEY,
+        None => BEST_DISTANCE_KEY,
+    };
+    let saved = browser::load_setting(key).ok().flatten();
+    match daily {
+        Some(day) => saved.and_then(|json| daily_best(&json, day)),
+        None => saved.and_then(|distance| distance.parse().ok()),
+    }
+    .unwrap_or(0)
+}
+
+#[derive(Serialize, Deserialize)]
+struct DailyBest {
+    day: u32,
+    distance: u64,
+}
+
+// 前の日の記録は今日の最長距離として扱わない
+fn daily_best(json: &str, day: u32) -> Option<u64> {
+    serde_json::from_str::<DailyBest>(json)
+        .ok()
+        .filter(|best| best.day == day)
+        .map(|best| best.distance)
+}
+
+// 日付を混ぜて、隣り合う日でもまったく違う並びになるseedにする(SplitMix64)
+fn daily_seed(day: u32) -> u64 {
+    let mut z = (day as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn clear_screen(renderer: &Renderer) {
+    let screen = Rect::new_from_x_y(0, 0, WIDTH, HEIGHT);
+    match CLEAR_COLOR {
+        Some(color) => renderer.clear_with_color(&screen, color),
+        None => renderer.clear(&screen),
+    }
+}
+
+// 画面と同じ縦横比で幅をwidthにした時の大きさと、ワールド座標からの倍率
+fn thumbnail_size(width: u32) -> (u32, u32, f32) {
+    let width = width.max(1);
+    let scale = width as f32 / WIDTH as f32;
+    (
+        width,
+        (HEIGHT as f32 * scale).round().max(1.0) as u32,
+        scale,
+    )
+}
+
+fn format_day(day: u32) -> String {
+    format!("{}-{:02}-{:02}", day / 10000, day / 100 % 100, day % 100)
+}
+
+// 走った距離に応じた夜の暗さ。距離0では完全に透明なのでNoneを返す
+fn day_night_tint(distance: i32) -> Option<String> {
+    let phase = distance.rem_euclid(DAY_NIGHT_CYCLE) as f32 / DAY_NIGHT_CYCLE as f32;
+    let darkness = (1.0 - (phase * std::f32::consts::TAU).cos()) / 2.0;
+    let alpha = darkness * NIGHT_ALPHA;
+    if alpha < 0.01 {
+        return None;
+    }
+    let mix = |dusk: f32, night: f32| (dusk + (night - dusk) * darkness).round() as u8;
+    Some(format!(
+        "rgba({}, {}, {}, {:.2})",
+        mix(DUSK_COLOR.0, NIGHT_COLOR.0),
+        mix(DUSK_COLOR.1, NIGHT_COLOR.1),
+        mix(DUSK_COLOR.2, NIGHT_COLOR.2),
+        alpha
+    ))
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WindDirection {
+    // 進行方向に吹く追い風
+    Tailwind,
+    Headwind,
+}
+
+pub struct Gust {
+    direction: WindDirection,
+    strength: i16,
+    duration: u16,
+    remaining: u16,
+}
+
+// 時々吹く突風。スクロールの速さと、ジャンプ中の少年の横方向の動きを変える
+pub struct Wind {
+    gust: Option<Gust>,
+    calm_ticks: u16,
+}
+
+impl Default for Wind {
+    fn default() -> Self {
+        Wind {
+            gust: None,
+            calm_ticks: GUST_INTERVAL.start,
+        }
+    }
+}
+
+impl Wind {
+    fn update(&mut self, rng: &mut impl Rng) {
+        match &mut self.gust {
+            Some(gust) => {
+                gust.remaining -= 1;
+                if gust.remaining == 0 {
+                    self.gust = None;
+                    self.calm_ticks = rng.gen_range(GUST_INTERVAL);
+                }
+            }
+            None => {
+                self.calm_ticks = self.calm_ticks.saturating_sub(1);
+                if self.calm_ticks == 0 {
+                    let duration = rng.gen_range(GUST_DURATION);
+                    self.gust = Some(Gust {
+                        direction: if rng.gen() {
+                            WindDirection::Tailwind
+                        } else {
+                            WindDirection::Headwind
+                        },
+                        strength: GUST_STRENGTH,
+                        duration,
+                        remaining: duration,
+                    });
+                }
+            }
+        }
+    }
+
+    // 向かい風は走る速さだけを落とし、ジャンプの飛距離は縮めない。
+    // こうしておけば風が吹いていても越えられないセグメントはできない
+    fn push(&self, airborne: bool) -> i16 {
+        match &self.gust {
+            Some(gust) => match gust.direction {
+                WindDirection::Tailwind if airborne => gust.strength * 2,
+                WindDirection::Tailwind => gust.strength,
+                WindDirection::Headwind if airborne => 0,
+                WindDirection::Headwind => -gust.strength,
+            },
+            None => 0,
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        if let Some(gust) = &self.gust {
+            let age = (gust.duration - gust.remaining) as i16;
+            let span = WIDTH + GUST_STREAK_LENGTH;
+            (0..GUST_STREAKS).for_each(|streak| {
+                let travel = (streak * 137 + age * 12).rem_euclid(span);
+                let x = match gust.direction {
+                    WindDirection::Tailwind => WIDTH - travel,
+                    WindDirection::Headwind => travel - GUST_STREAK_LENGTH,
+                };
+                let y = 60 + (streak * 71).rem_euclid(HEIGHT - 120);
+                renderer.fill_rect(
+                    &Rect::new_from_x_y(x, y, GUST_STREAK_LENGTH, 1),
+                    "rgba(255, 255, 255, 0.35)",
+                );
+            });
+        }
+    }
+}
+
+// 当たり判定で少年に何が起きたか
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Contact {
+    Miss,
+    // 上に着地した。足場の上にいる間は毎フレーム着地し直す
+    Land,
+    // 倒れたか、壁に当たった
+    Hit,
+}
+
+pub trait Obstacle {
+    fn check_intersection(&self, boy: &mut RedHatBoy) -> Contact;
+    // 毎フレーム当たり判定の後に、画面外のものも含めて呼ぶ。
+    // landedはこのフレームに少年がこの障害物に着地したか
+    fn update(&mut self, _landed: bool) {}
+    fn draw(&self, renderer: &Renderer);
+    fn move_horizontally(&mut self, x: i16);
+    fn left(&self) -> i16;
+    fn right(&self) -> i16;
+    // 危険な障害物なら、その上端と少年の足元との距離。ニアミスの判定に使う
+    fn clearance(&self, _boy: &Rect) -> Option<i16> {
+        None
+    }
+    // 場面の保存用。保存できない障害物はNone
+    fn snapshot(&self) -> Option<ObstacleSnapshot> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+// x, yは拡大縮小した後の左上。倍率が1の時はscaleを書き出さない
+pub enum ObstacleSnapshot {
+    Stone {
+        x: i16,
+        y: i16,
+        filter: Option<String>,
+        #[serde(default = "default_scale", skip_serializing_if = "is_unscaled")]
+        scale: f32,
+    },
+    Platform {
+        x: i16,
+        y: i16,
+        sprites: Vec<String>,
+        filter: Option<String>,
+        #[serde(default = "default_scale", skip_serializing_if = "is_unscaled")]
+        scale: f32,
+    },
+    // 崩れ始めていても、崩れる前の足場として保存する
+    CrumblingPlatform {
+        x: i16,
+        y: i16,
+        sprites: Vec<String>,
+        filter: Option<String>,
+        #[serde(default = "default_scale", skip_serializing_if = "is_unscaled")]
+        scale: f32,
+    },
+}
+
+// 不公平な並びの報告用に、今の障害物の配置をそのまま書き出したもの
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Scene {
+    pub seed: u64,
+    pub distance: i32,
+    pub obstacles: Vec<ObstacleSnapshot>,
+}
+
+// 最初のセグメントが出発点に近すぎたら、全体を右にずらす。2つ目以降には使わない
+fn keep_opening_clear(obstacles: &mut [Box<dyn Obstacle>], lead: i16) {
+    let first = match obstacles.iter().map(|obstacle| obstacle.left()).min() {
+        Some(first) => first,
+        None => return,
+    };
+    let safe = red_hat_boy_states::STARTING_POINT + lead;
+    if first < safe {
+        obstacles
+            .iter_mut()
+            .for_each(|obstacle| obstacle.move_horizontally(safe - first));
+    }
+}
+
+fn check_level(level: &Level) -> Result<()> {
+    for spec in &level.segments {
+        if !SEGMENT_TYPES.contains(&spec.kind.as_str()) {
+            return Err(anyhow!(
+                "unknown segment type '{}', expected one of {:?}",
+                spec.kind,
+                SEGMENT_TYPES
+            ));
+        }
+        if let Some(theme) = &spec.theme {
+            if !level.themes.contains_key(theme) {
+                return Err(anyhow!("unknown theme '{}'", theme));
+            }
+        }
+    }
+    Ok(())
+}
+
+// lengthだけ流れる間に0から1まで進む
+fn fade_progress(scrolled: i32, length: i32) -> f32 {
+    (scrolled as f32 / length.max(1) as f32).clamp(0.0, 1.0)
+}
+
+// 作成済みレベルを最後まで並べた時のおおよその長さ
+fn authored_length(
+    specs: &[SegmentSpec],
+    stone: &Rc<HtmlImageElement>,
+    sprite_sheet: &Rc<SpriteSheet>,
+    obstacle_buffer: i16,
+) -> Result<i32> {
+    specs
+        .iter()
+        .try_fold(obstacle_buffer as i32, |length, spec| {
+            let obstacles = segments::segment(
+                &spec.kind,
+                stone.clone(),
+                sprite_sheet.clone(),
+                0,
+                &Palettes::default(),
+                spec.scale,
+            )?;
+            Ok(length + spec.offset as i32 + rightmost(&obstacles) as i32)
+        })
+}
+
+// 世界の流れる速さ。少年が右へ進む速さと大きさが同じで、向きが逆になる
+fn scroll_velocity(walk_speed: i16, push: i16) -> i16 {
+    -(walk_speed + push)
+}
+
+// distanceをstep以下の幅に分ける。負のdistanceでは何も返さない
+fn skip_steps(distance: i16, step: i16) -> impl Iterator<Item = i16> {
+    let distance = distance.max(0);
+    let step = step.max(1);
+    (0..distance)
+        .step_by(step as usize)
+        .map(move |start| step.min(distance - start))
+}
+
+fn scroll_obstacles(obstacles: &mut [Box<dyn Obstacle>], velocity: i16) {
+    obstacles.iter_mut().for_each(|obstacle| {
+        obstacle.move_horizontally(velocity);
+    });
+}
+
+// leftの昇順に並んだobstaclesから、x方向にleft..rightと重なりうるものを返す。
+// leftがrightを超えたらそれ以降は調べない
+fn obstacles_in_range(
+    obstacles: &[Box<dyn Obstacle>],
+    left: i16,
+    right: i16,
+) -> impl Iterator<Item = (usize, &Box<dyn Obstacle>)> {
+    obstacles
+        .iter()
+        .enumerate()
+        .take_while(move |(_, obstacle)| obstacle.left() <= right)
+        .filter(move |(_, obstacle)| obstacle.right() >= left)
+}
+
+fn rightmost(obstacle_list: &Vec<Box<dyn Obstacle>>) -> i16 {
+    obstacle_list
+        .iter()
+        .map(|obstacle| obstacle.right())
+        .max_by(|x, y| x.cmp(&y))
+        .unwrap_or(0)
+}
+
+pub struct Platform {
+    sheet: Rc<SpriteSheet>,
+    position: Point,
+    // 場面を保存する時のために名前も持っておく
+    sprite_names: Vec<String>,
+    sprites: Vec<Cell>,
+    bounding_boxes: Vec<Rect>,
+    scale: f32,
+    // 直前のフレームで縦に動いた量。下向きが正
+    velocity_y: i16,
+    filter: Option<String>,
+}
+
+impl Obstacle for Platform {
+    fn check_intersection(&self, boy: &mut RedHatBoy) -> Contact {
+        if let Some(box_to_land_on) = self
+            .bounding_boxes()
+            .iter()
+            .find(|&bounding_box| boy.bounding_box().intersects(bounding_box))
+        {
+            let side = boy.bounding_box().collision_side(box_to_land_on);
+            // 速く落ちていると1フレームで深くめり込むので、前のフレームで上にいたなら着地とする
+            if boy.velocity_y() > 0
+                && (side == Some(Side::Bottom) || boy.came_from_above(box_to_land_on.y()))
+            {
+                boy.land_on(box_to_land_on.y(), self.velocity_y);
+                return Contact::Land;
+            } else if boy.is_jumping() && side == Some(Side::Right) {
+                boy.hit_wall();
+                return Contact::Hit;
+            } else if boy.hazard_box().intersects(box_to_land_on) {
+                boy.knock_out(KnockOutCause::PlatformSide);
+                return Contact::Hit;
+            }
+        }
+        Contact::Miss
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        draw_filtered(renderer, self.filter.as_deref(), || {
+            self.draw_sprites(renderer, 0)
+        });
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.position.x += x;
+        self.bounding_boxes.iter_mut().for_each(|bounding_box| {
+            bounding_box.set_x(bounding_box.position.x + x);
+        })
+    }
+
+    fn left(&self) -> i16 {
+        self.bounding_boxes()
+            .first()
+            .unwrap_or(&Rect::default())
+            .x()
+    }
+
+    fn right(&self) -> i16 {
+        self.bounding_boxes()
+            .last()
+            .unwrap_or(&Rect::default())
+            .right()
+    }
+
+    fn snapshot(&self) -> Option<ObstacleSnapshot> {
+        Some(ObstacleSnapshot::Platform {
+            x: self.position.x,
+            y: self.position.y,
+            sprites: self.sprite_names.clone(),
+            filter: self.filter.clone(),
+            scale: self.scale,
+        })
+    }
+}
+
+// filterがある時だけ、drawの前後でfilterをかけて外す
+fn draw_filtered(renderer: &Renderer, filter: Option<&str>, draw: impl FnOnce()) {
+    match filter {
+        Some(filter) => {
+            renderer.begin_filter(filter);
+            draw();
+            renderer.end_filter();
+        }
+        None => draw(),
+    }
+}
+
+// 少年が乗るとCRUMBLE_FRAMESの間揺れながら薄くなり、その後は当たり判定が無くなって
+// 乗っていた少年は下に落ちる。消える前なら普通の足場と同じように跳んで降りられる
+pub struct CrumblingPlatform {
+    platform: Platform,
+    crumble: Crumble,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Crumble {
+    Solid,
+    // 消えるまでの残りフレーム数
+    Crumbling(u8),
+    Gone,
+}
+
+impl Crumble {
+    fn update(self, landed: bool) -> Self {
+        match self {
+            Crumble::Solid if landed => Crumble::Crumbling(CRUMBLE_FRAMES),
+            Crumble::Crumbling(frames) if frames <= 1 => Crumble::Gone,
+            Crumble::Crumbling(frames) => Crumble::Crumbling(frames - 1),
+            crumble => crumble,
+        }
+    }
+
+    fn alpha(&self) -> f64 {
+        match self {
+            Crumble::Solid => 1.0,
+            Crumble::Crumbling(frames) => *frames as f64 / CRUMBLE_FRAMES as f64,
+            Crumble::Gone => 0.0,
+        }
+    }
+
+    // 2フレームごとに左右を入れ替える
+    fn shake(&self) -> i16 {
+        match self {
+            Crumble::Crumbling(frames) if frames % 4 < 2 => CRUMBLE_SHAKE,
+            Crumble::Crumbling(_) => -CRUMBLE_SHAKE,
+            _ => 0,
+        }
+    }
+}
+
+impl CrumblingPlatform {
+    pub fn new(platform: Platform) -> Self {
+        CrumblingPlatform {
+            platform,
+            crumble: Crumble::Solid,
+        }
+    }
+}
+
+impl Obstacle for CrumblingPlatform {
+    fn check_intersection(&self, boy: &mut RedHatBoy) -> Contact {
+        if self.crumble == Crumble::Gone {
+            return Contact::Miss;
+        }
+        self.platform.check_intersection(boy)
+    }
+
+    fn update(&mut self, landed: bool) {
+        self.crumble = self.crumble.update(landed);
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        match self.crumble {
+            Crumble::Solid => self.platform.draw(renderer),
+            Crumble::Crumbling(_) => {
+                renderer.set_alpha(self.crumble.alpha());
+                draw_filtered(renderer, self.platform.filter.as_deref(), || {
+                    self.platform.draw_sprites(renderer, self.crumble.shake())
+                });
+                renderer.set_alpha(1.0);
+            }
+            Crumble::Gone => {}
+        }
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.platform.move_horizontally(x);
+    }
+
+    // 崩れた後も、画面外に出て取り除かれるまでは元の場所を占める
+    fn left(&self) -> i16 {
+        self.platform.left()
+    }
+
+    fn right(&self) -> i16 {
+        self.platform.right()
+    }
+
+    fn snapshot(&self) -> Option<ObstacleSnapshot> {
+        Some(ObstacleSnapshot::CrumblingPlatform {
+            x: self.platform.position.x,
+            y: self.platform.position.y,
+            sprites: self.platform.sprite_names.clone(),
+            filter: self.platform.filter.clone(),
+            scale: self.platform.scale,
+        })
+    }
+}
+
+impl Platform {
+    fn draw_sprites(&self, renderer: &Renderer, shake: i16) {
+        let mut x = 0;
+        let scaled = |length: i16| (length as f32 * self.scale).round() as i16;
+        self.sprites.iter().for_each(|sprite| {
+            self.sheet.draw(
+                renderer,
+                &Rect::new_from_x_y(
+                    sprite.frame.x,
+                    sprite.frame.y,
+                    sprite.frame.w,
+                    sprite.frame.h,
+                ),
+                &Rect::new_from_x_y(
+                    self.position.x + x + shake,
+                    self.position.y,
+                    scaled(sprite.frame.w),
+                    scaled(sprite.frame.h),
+                ),
+            );
+            x += scaled(sprite.frame.w);
+        });
+    }
+
+    pub fn new(
+        sheet: Rc<SpriteSheet>,
+        position: Point,
+        sprite_names: &[&str],
+        bounding_boxes: &[Rect],
+    ) -> Self {
+        let sprites = sprite_names
+            .iter()
+            .filter_map(|sprite_name| sheet.cell(&sprite_name).cloned())
+            .collect();
+        let bounding_boxes = bounding_boxes
+            .iter()
+            .map(|bounding_box| {
+                Rect::new_from_x_y(
+                    bounding_box.x() + position.x,
+                    bounding_box.y() + position.y,
+                    bounding_box.w,
+                    bounding_box.h,
+                )
+            })
+            .collect();
+
+        Platform {
+            sheet,
+            position,
+            sprite_names: sprite_names.iter().map(|name| name.to_string()).collect(),
+            sprites,
+            bounding_boxes,
+            scale: 1.0,
+            velocity_y: 0,
+            filter: None,
+        }
+    }
+
+    // 見た目だけを変える。当たり判定はそのまま
+    pub fn with_filter(mut self, filter: Option<&str>) -> Self {
+        self.filter = filter.map(String::from);
+        self
+    }
+
+    // 一番背の高いスプライトの下端を基準に拡大縮小する
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        let height = self
+            .sprites
+            .iter()
+            .map(|sprite| sprite.frame.h)
+            .max()
+            .unwrap_or(0);
+        let anchor = Point {
+            x: self.position.x,
+            y: self.position.y + (height as f32 * self.scale).round() as i16,
+        };
+        let factor = scale / self.scale;
+        self.bounding_boxes = self
+            .bounding_boxes
+            .iter()
+            .map(|bounding_box| bounding_box.scale_from(&anchor, factor))
+            .collect();
+        self.position.y = anchor.y - (height as f32 * scale).round() as i16;
+        self.scale = scale;
+        self
+    }
+
+    // 拡大縮小した後の上端をyに合わせる。保存した場面から置き直す時に使う
+    pub fn with_top(mut self, y: i16) -> Self {
+        let distance = y - self.position.y;
+        self.position.y = y;
+        self.bounding_boxes.iter_mut().for_each(|bounding_box| {
+            bounding_box.set_y(bounding_box.y() + distance);
+        });
+        self
+    }
+
+    // 動く足場はフレームごとにこれを呼ぶ。動いた量は乗っている少年に引き継がれる。
+    // まだ縦に動く足場は無いので、テストだけが使う
+    #[cfg(test)]
+    pub fn move_vertically(&mut self, y: i16) {
+        self.position.y += y;
+        self.bounding_boxes.iter_mut().for_each(|bounding_box| {
+            bounding_box.set_y(bounding_box.y() + y);
+        });
+        self.velocity_y = y;
+    }
+
+    fn bounding_boxes(&self) -> &Vec<Rect> {
+        &self.bounding_boxes
+    }
+}
+
+pub struct Barrier {
+    image: Image,
+    filter: Option<String>,
+}
+
+impl Obstacle for Barrier {
+    fn check_intersection(&self, boy: &mut RedHatBoy) -> Contact {
+        if boy.hazard_box().intersects(self.image.bounding_box()) {
+            boy.knock_out(KnockOutCause::Barrier);
+            Contact::Hit
+        } else {
+            Contact::Miss
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        draw_filtered(renderer, self.filter.as_deref(), || {
+            self.image.draw(renderer)
+        });
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.image.move_horizontally(x);
+    }
+
+    fn left(&self) -> i16 {
+        self.image.bounding_box().x()
+    }
+
+    fn right(&self) -> i16 {
+        self.image.right()
+    }
+
+    fn clearance(&self, boy: &Rect) -> Option<i16> {
+        Some(self.image.bounding_box().y() - boy.bottom())
+    }
+
+    fn snapshot(&self) -> Option<ObstacleSnapshot> {
+        Some(ObstacleSnapshot::Stone {
+            x: self.image.bounding_box().x(),
+            y: self.image.bounding_box().y(),
+            filter: self.filter.clone(),
+            scale: self.image.scale(),
+        })
+    }
+}
+
+impl Barrier {
+    pub fn new(image: Image) -> Self {
+        Barrier {
+            image,
+            filter: None,
+        }
+    }
+
+    // 見た目だけを変える。当たり判定はそのまま
+    pub fn with_filter(mut self, filter: Option<&str>) -> Self {
+        self.filter = filter.map(String::from);
+        self
+    }
+}
+
+#[derive(Copy, Clone)]
+enum RedHatBoyStateMachine {
+    Intro(RedHatBoyState<Intro>),
+    Idle(RedHatBoyState<Idle>),
+    Running(RedHatBoyState<Running>),
+    Sliding(RedHatBoyState<Sliding>),
+    Crouching(RedHatBoyState<Crouching>),
+    Jumping(RedHatBoyState<Jumping>),
+    Falling(RedHatBoyState<Falling>),
+    KnockedOut(RedHatBoyState<KnockedOut>),
+}
+impl From<RedHatBoyState<Intro>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<Intro>) -> Self {
+        RedHatBoyStateMachine::Intro(state)
+    }
+}
+impl From<IntroEndState> for RedHatBoyStateMachine {
+    fn from(end_state: IntroEndState) -> Self {
+        match end_state {
+            IntroEndState::Complete(idle_state) => idle_state.into(),
+            IntroEndState::Intro(intro_state) => intro_state.into(),
+        }
+    }
+}
+impl From<RedHatBoyState<Idle>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<Idle>) -> Self {
+        RedHatBoyStateMachine::Idle(state)
+    }
+}
+impl From<RedHatBoyState<Running>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<Running>) -> Self {
+        RedHatBoyStateMachine::Running(state)
+    }
+}
+impl From<RedHatBoyState<Sliding>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<Sliding>) -> Self {
+        RedHatBoyStateMachine::Sliding(state)
+    }
+}
+impl From<RedHatBoyState<Crouching>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<Crouching>) -> Self {
+        RedHatBoyStateMachine::Crouching(state)
+    }
+}
+impl From<RedHatBoyState<Jumping>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<Jumping>) -> Self {
+        RedHatBoyStateMachine::Jumping(state)
+    }
+}
+impl From<RunningEndState> for RedHatBoyStateMachine {
+    fn from(end_state: RunningEndState) -> Self {
+        match end_state {
+            RunningEndState::Running(running_state) => running_state.into(),
+            RunningEndState::Jumping(jumping_state) => jumping_state.into(),
+        }
+    }
+}
+impl From<SlidingEndState> for RedHatBoyStateMachine {
+    fn from(end_state: SlidingEndState) -> Self {
+        match end_state {
+            SlidingEndState::Complete(running_state) => running_state.into(),
+            SlidingEndState::Sliding(sliding_state) => sliding_state.into(),
+            SlidingEndState::Jumping(jumping_state) => jumping_state.into(),
+        }
+    }
+}
+impl From<JumpingEndState> for RedHatBoyStateMachine {
+    fn from(end_state: JumpingEndState) -> Self {
+        match end_state {
+            JumpingEndState::Landing(running_state) => running_state.into(),
+            JumpingEndState::Jumping(jumping_state) => jumping_state.into(),
+        }
+    }
+}
+impl From<RedHatBoyState<Falling>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<Falling>) -> Self {
+        RedHatBoyStateMachine::Falling(state)
+    }
+}
+impl From<RedHatBoyState<KnockedOut>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<KnockedOut>) -> Self {
+        RedHatBoyStateMachine::KnockedOut(state)
+    }
+}
+impl From<FallingEndState> for RedHatBoyStateMachine {
+    fn from(end_state: FallingEndState) -> Self {
+        match end_state {
+            FallingEndState::Complete(knocked_out_state) => knocked_out_state.into(),
+            FallingEndState::Falling(falling_state) => falling_state.into(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedHatBoyStateTag {
+    Intro,
+    Idle,
+    Running,
+    Sliding,
+    Crouching,
+    Jumping,
+    Falling,
+    KnockedOut,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct StateChanged {
+    pub from: RedHatBoyStateTag,
+    pub to: RedHatBoyStateTag,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    Run,
+    Slide,
+    Crouch,
+    StandUp,
+    Jump,
+    KnockOut,
+    WallJump,
+    FastFall,
+    Respawn,
+    // 着地する高さと、足場の縦方向の速度
+    Land(i16, i16),
+    Update,
+}
+
+// 今の状態が受け付けずに捨てたイベント。入力のたびに文字列を作らないよう、状態とイベントだけを持つ
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DroppedEvent {
+    pub state: RedHatBoyStateTag,
+    pub event: Event,
+}
+
+impl std::fmt::Display for DroppedEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Dropped transition: {:?} does not handle {:?}",
+            self.state, self.event
+        )
+    }
+}
+
+impl std::error::Error for DroppedEvent {}
+
+impl RedHatBoyStateMachine {
+    #[cfg(test)]
+    fn new_in_state(tag: RedHatBoyStateTag, context: RedHatBoyContext) -> Self {
+        match tag {
+            RedHatBoyStateTag::Intro => RedHatBoyState::from_context(context, Intro).into(),
+            RedHatBoyStateTag::Idle => RedHatBoyState::from_context(context, Idle).into(),
+            RedHatBoyStateTag::Running => RedHatBoyState::from_context(context, Running).into(),
+            RedHatBoyStateTag::Sliding => RedHatBoyState::from_context(context, Sliding).into(),
+            RedHatBoyStateTag::Crouching => RedHatBoyState::from_context(context, Crouching).into(),
+            RedHatBoyStateTag::Jumping => RedHatBoyState::from_context(context, Jumping).into(),
+            RedHatBoyStateTag::Falling => RedHatBoyState::from_context(context, Falling).into(),
+            RedHatBoyStateTag::KnockedOut => {
+                RedHatBoyState::from_context(context, KnockedOut).into()
+            }
+        }
+    }
+
+    fn try_transition(self, event: Event) -> Result<Self, DroppedEvent> {
+        let next = match (self, event) {
+            (RedHatBoyStateMachine::Idle(state), Event::Run) => state.run().into(),
+            (RedHatBoyStateMachine::Running(state), Event::Slide) => state.slide().into(),
+            (RedHatBoyStateMachine::Running(state), Event::Jump) if state.context.can_jump() => {
+                state.jump().into()
+            }
+            (RedHatBoyStateMachine::Sliding(state), Event::Jump) if state.context.can_jump() => {
+                state.jump().into()
+            }
+            // 足場から落ちている途中でも覚えておき、着地したフレームで跳ぶ。
+            // しゃがみ中は床の上でも跳べないので覚えない
+            (RedHatBoyStateMachine::Running(state), Event::Jump)
+                if state.context.input_windows.jump_buffer > 0 =>
+            {
+                state.buffer_jump().into()
+            }
+            (RedHatBoyStateMachine::Sliding(state), Event::Jump)
+                if state.context.input_windows.jump_buffer > 0 =>
+            {
+                state.buffer_jump().into()
+            }
+            (RedHatBoyStateMachine::Jumping(state), Event::Jump)
+                if state.context.input_windows.jump_buffer > 0 =>
+            {
+                state.buffer_jump().into()
+            }
+            (RedHatBoyStateMachine::Running(state), Event::Crouch) => state.crouch().into(),
+            (RedHatBoyStateMachine::Sliding(state), Event::Crouch) => state.crouch().into(),
+            (RedHatBoyStateMachine::Crouching(_), Event::Crouch) => self,
+            (RedHatBoyStateMachine::Crouching(state), Event::StandUp) => state.stand().into(),
+            (RedHatBoyStateMachine::Crouching(state), Event::KnockOut) => state.knock_out().into(),
+            (RedHatBoyStateMachine::Running(state), Event::KnockOut) => state.knock_out().into(),
+            (RedHatBoyStateMachine::Sliding(state), Event::KnockOut) => state.knock_out().into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::KnockOut) => state.knock_out().into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::WallJump)
+                if state.context.wall_jump_lockout == 0 =>
+            {
+                state.wall_jump().into()
+            }
+            (RedHatBoyStateMachine::Jumping(state), Event::FastFall) => state.fast_fall().into(),
+            (RedHatBoyStateMachine::Intro(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Idle(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Running(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Sliding(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Crouching(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Falling(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::Land(position, velocity)) => {
+                state.land_on(position, velocity).into()
+            }
+            (RedHatBoyStateMachine::Running(state), Event::Land(position, velocity)) => state
+                .land_on(position, velocity)
+                .release_buffered_jump()
+                .into(),
+            (RedHatBoyStateMachine::Sliding(state), Event::Land(position, velocity)) => state
+                .land_on(position, velocity)
+                .release_buffered_jump()
+                .into(),
+            (RedHatBoyStateMachine::Crouching(state), Event::Land(position, velocity)) => {
+                state.land_on(position, velocity).into()
+            }
+            (RedHatBoyStateMachine::KnockedOut(_), Event::Update) => self,
+            (RedHatBoyStateMachine::KnockedOut(state), Event::Respawn) => state.respawn().into(),
+            _ => {
+                return Err(DroppedEvent {
+                    state: self.tag(),
+                    event,
+                })
+            }
+        };
+        Ok(next)
+    }
+
+    // 床の高さにいるか、足場に着地している。足場の上では毎フレーム重力で少し沈んでから
+    // 着地し直すので、その間の1フレームの途中では足場の上でもfalseになる。当たり判定の後に見ること
+    fn is_grounded(&self) -> bool {
+        let context = self.context();
+        context.position.y >= FLOOR
+            || (context.velocity.y == 0
+                && matches!(
+                    self.tag(),
+                    RedHatBoyStateTag::Running
+                        | RedHatBoyStateTag::Sliding
+                        | RedHatBoyStateTag::Crouching
+                ))
+    }
+
+    // 状態の種類だけを返す。中のRedHatBoyStateには触れない
+    pub fn tag(&self) -> RedHatBoyStateTag {
+        match self {
+            RedHatBoyStateMachine::Intro(_) => RedHatBoyStateTag::Intro,
+            RedHatBoyStateMachine::Idle(_) => RedHatBoyStateTag::Idle,
+            RedHatBoyStateMachine::Running(_) => RedHatBoyStateTag::Running,
+            RedHatBoyStateMachine::Sliding(_) => RedHatBoyStateTag::Sliding,
+            RedHatBoyStateMachine::Crouching(_) => RedHatBoyStateTag::Crouching,
+            RedHatBoyStateMachine::Jumping(_) => RedHatBoyStateTag::Jumping,
+            RedHatBoyStateMachine::Falling(_) => RedHatBoyStateTag::Falling,
+            RedHatBoyStateMachine::KnockedOut(_) => RedHatBoyStateTag::KnockedOut,
+        }
+    }
+
+    fn frame_name(&self) -> &str {
+        match self {
+            RedHatBoyStateMachine::Intro(state) => state.frame_name(),
+            RedHatBoyStateMachine::Idle(state) => state.frame_name(),
+            RedHatBoyStateMachine::Running(state) => state.frame_name(),
+            RedHatBoyStateMachine::Sliding(state) => state.frame_name(),
+            RedHatBoyStateMachine::Crouching(state) => state.frame_name(),
+            RedHatBoyStateMachine::Jumping(state) => state.frame_name(),
+            RedHatBoyStateMachine::Falling(state) => state.frame_name(),
+            RedHatBoyStateMachine::KnockedOut(state) => state.frame_name(),
+        }
+    }
+
+    fn context(&self) -> &RedHatBoyContext {
+        match self {
+            RedHatBoyStateMachine::Intro(state) => &state.context(),
+            RedHatBoyStateMachine::Idle(state) => &state.context(),
+            RedHatBoyStateMachine::Running(state) => &state.context(),
+            RedHatBoyStateMachine::Sliding(state) => &state.context(),
+            RedHatBoyStateMachine::Crouching(state) => &state.context(),
+            RedHatBoyStateMachine::Jumping(state) => &state.context(),
+            RedHatBoyStateMachine::Falling(state) => &state.context(),
+            RedHatBoyStateMachine::KnockedOut(state) => &state.context(),
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut RedHatBoyContext {
+        match self {
+            RedHatBoyStateMachine::Intro(state) => state.context_mut(),
+            RedHatBoyStateMachine::Idle(state) => state.context_mut(),
+            RedHatBoyStateMachine::Running(state) => state.context_mut(),
+            RedHatBoyStateMachine::Sliding(state) => state.context_mut(),
+            RedHatBoyStateMachine::Crouching(state) => state.context_mut(),
+            RedHatBoyStateMachine::Jumping(state) => state.context_mut(),
+            RedHatBoyStateMachine::Falling(state) => state.context_mut(),
+            RedHatBoyStateMachine::KnockedOut(state) => state.context_mut(),
+        }
+    }
+}
+
+type StateListener = Box<dyn FnMut(&StateChanged)>;
+
+// 何に当たって倒れたか
+#[derive(Copy, Clone, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KnockOutCause {
+    Barrier,
+    // 足場に横や下からぶつかった
+    PlatformSide,
+    // 壁に張り付いたまま壁ジャンプしなかった
+    Wall,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PhysicsConfig {
+    // 倒れる判定にだけ使う当たり判定の縮め幅。着地の判定には使わない
+    pub hazard_margin: i16,
+    // 倒れるアニメーションのフレーム数。過ぎるとKnockedOutになる
+    pub falling_frames: u8,
+    pub knockout_animation: KnockoutAnimation,
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        PhysicsConfig {
+            hazard_margin: HAZARD_MARGIN,
+            falling_frames: red_hat_boy_states::FALLING_FRAMES,
+            knockout_animation: KnockoutAnimation::default(),
+        }
+    }
+}
+
+// 倒れるアニメーションが絵の枚数より長い時に、最後の絵で止めるか繰り返すか。
+// KnockedOutになった後はどちらでも最後の絵を出す
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KnockoutAnimation {
+    #[default]
+    Hold,
+    Loop,
+}
+
+fn knockout_index(
+    tag: RedHatBoyStateTag,
+    index: u16,
+    cells: u16,
+    animation: KnockoutAnimation,
+) -> u16 {
+    let last = cells.saturating_sub(1);
+    match (tag, animation) {
+        (RedHatBoyStateTag::KnockedOut, _) => last,
+        (_, KnockoutAnimation::Hold) => index.min(last),
+        (_, KnockoutAnimation::Loop) => index % cells.max(1),
+    }
+}
+
+pub struct RedHatBoy {
+    state_machine: RedHatBoyStateMachine,
+    sprite_sheet: Sheet,
+    image: HtmlImageElement,
+    state_listeners: Vec<StateListener>,
+    // 出力済みの捨てたイベント。状態が変わるまでは同じ組を出力しない
+    logged_drops: HashSet<(RedHatBoyStateTag, Discriminant<Event>)>,
+    wall_contact: u8,
+    touching_wall: bool,
+    crouch_held: u8,
+    physics: PhysicsConfig,
+    last_knockout_cause: Option<KnockOutCause>,
+    frame_names: FrameNames,
+    timings: HashMap<&'static str, AnimationTiming>,
+}
+
+// シートを読んだ時に数えておく、アニメーションごとのセルの枚数と各セルの表示時間(ミリ秒)。
+// 全部のセルに表示時間がある時だけdurationsを使い、それ以外は3フレームごとに切り替える
+#[derive(Clone, Debug, PartialEq)]
+struct AnimationTiming {
+    cells: u16,
+    durations: Option<Vec<u32>>,
+}
+
+impl AnimationTiming {
+    fn index(&self, frame: u8) -> u16 {
+        match &self.durations {
+            Some(durations) => {
+                let total: u32 = durations.iter().sum();
+                let mut elapsed = (frame as f32 * engine::FRAME_SIZE) as u32 % total;
+                let mut index = 0;
+                for duration in durations {
+                    if elapsed < *duration {
+                        break;
+                    }
+                    elapsed -= duration;
+                    index += 1;
+                }
+                index
+            }
+            None => (frame / 3) as u16,
+        }
+    }
+}
+
+// アニメーションごとのフレーム名の書式。指定が無いアニメーションは既定の書式を使う
+#[derive(Clone, Default)]
+pub struct FrameNames {
+    default: FrameNameFormat,
+    animations: HashMap<String, FrameNameFormat>,
+}
+
+impl FrameNames {
+    pub fn new(default: FrameNameFormat) -> Self {
+        FrameNames {
+            default,
+            animations: HashMap::new(),
+        }
+    }
+
+    pub fn with_animation(mut self, animation: &str, format: FrameNameFormat) -> Self {
+        self.animations.insert(animation.to_string(), format);
+        self
+    }
+
+    // シートのmetaに書式が書いてあればそれを使う
+    fn from_sheet(sheet: &Sheet) -> Self {
+        let meta = match sheet
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.frame_names.as_ref())
+        {
+            Some(meta) => meta,
+            None => return FrameNames::default(),
+        };
+        let default = meta
+            .default
+            .as_ref()
+            .map(|spec| spec.format())
+            .unwrap_or_default();
+        meta.animations
+            .iter()
+            .fold(FrameNames::new(default), |names, (animation, spec)| {
+                names.with_animation(animation, spec.format())
+            })
+    }
+
+    fn format(&self, animation: &str, index: u16) -> String {
+        self.animations
+            .get(animation)
+            .unwrap_or(&self.default)
+            .format(animation, index)
+    }
+}
+
+// 影は常に地面の上に置き、高く跳ぶほど小さくする
+fn shadow_rect(bounding_box: &Rect, height_above_floor: i16) -> Rect {
+    let height_above_floor = height_above_floor.max(0) as f32;
+    let scale = (1.0 - height_above_floor / SHADOW_FADE_HEIGHT).max(SHADOW_MIN_SCALE);
+    let w = (bounding_box.w as f32 * scale).round() as i16;
+    let h = (SHADOW_HEIGHT as f32 * scale).round().max(1.0) as i16;
+    let center_x = bounding_box.x() + bounding_box.w / 2;
+    let feet = bounding_box.bottom() + height_above_floor as i16;
+    Rect::new_from_x_y(center_x - w / 2, feet - h / 2, w, h)
+}
+
+// STARTING_POINTは少年が画面の真ん中に立つようにPLAYER_WIDTHから決めているので、
+// シートの絵の幅が変わったら定数も直す。切り詰めたセルは切り詰める前の幅で比べる
+fn check_player_width(sheet: &Sheet) -> Result<()> {
+    let mismatch = sheet
+        .frames
+        .iter()
+        .map(|(name, cell)| {
+            let width = cell
+                .source_size
+                .as_ref()
+                .map_or(cell.frame.w as u32, |size| size.w);
+            (name, width)
+        })
+        .filter(|(_, width)| *width != PLAYER_WIDTH as u32)
+        .min_by_key(|(name, _)| *name);
+    match mismatch {
+        Some((name, width)) => Err(anyhow!(
+            "Cell '{}' is {} pixels wide in the sheet, but PLAYER_WIDTH is {}",
+            name,
+            width,
+            PLAYER_WIDTH
+        )),
+        None => Ok(()),
+    }
+}
+
+// 各アニメーションのフレーム数の定数が、シートにあるセルの枚数と合っているかを確かめる。
+// PNGにフレームを足したのに定数を直し忘れると、走っている途中でセルが見つからずに落ちるため
+fn check_frame_counts(
+    sheet: &Sheet,
+    frame_names: &FrameNames,
+) -> Result<HashMap<&'static str, AnimationTiming>> {
+    let mut timings = HashMap::new();
+    for (animation, frames) in ANIMATION_FRAMES {
+        let durations: Vec<Option<u32>> = (0..)
+            .map_while(|index| sheet.frames.get(&frame_names.format(animation, index)))
+            .map(|cell| cell.duration_ms)
+            .collect();
+        let cells = durations.len();
+        let expected = (frames as usize).div_ceil(3);
+        if cells != expected {
+            return Err(anyhow!(
+                "Animation '{}' has {} cells in the sheet (first is '{}'), but its frame count {} expects {}",
+                animation,
+                cells,
+                frame_names.format(animation, 0),
+                frames,
+                expected
+            ));
+        }
+
+        // 状態はframesフレームで頭に戻るので、それより長い表示時間の後ろのセルは出せない
+        let durations: Option<Vec<u32>> = durations.into_iter().collect();
+        let total = durations.as_ref().map_or(0, |d| d.iter().sum::<u32>());
+        let loop_ms = (frames as f32 * engine::FRAME_SIZE) as u32;
+        if total > loop_ms {
+            return Err(anyhow!(
+                "Animation '{}' lasts {} ms in the sheet, but its frame count {} loops every {} ms",
+                animation,
+                total,
+                frames,
+                loop_ms
+            ));
+        }
+        timings.insert(
+            animation,
+            AnimationTiming {
+                cells: cells as u16,
+                durations: durations.filter(|_| total > 0),
+            },
+        );
+    }
+    Ok(timings)
+}
+
+impl RedHatBoy {
+    fn new(sheet: Sheet, image: HtmlImageElement) -> Result<Self> {
+        sheet.check_image_size(&image);
+        Self::with_state_machine(
+            RedHatBoyStateMachine::Intro(RedHatBoyState::intro()),
+            sheet,
+            image,
+        )
+    }
+
+    // 不具合の再現用に、任意の状態・位置から開始する
+    #[cfg(test)]
+    pub fn new_in_state(
+        sheet: Sheet,
+        image: HtmlImageElement,
+        tag: RedHatBoyStateTag,
+        context: RedHatBoyContext,
+    ) -> Result<Self> {
+        Self::with_state_machine(
+            RedHatBoyStateMachine::new_in_state(tag, context),
+            sheet,
+            image,
+        )
+    }
+
+    fn with_state_machine(
+        state_machine: RedHatBoyStateMachine,
+        sheet: Sheet,
+        image: HtmlImageElement,
+    ) -> Result<Self> {
+        let frame_names = FrameNames::from_sheet(&sheet);
+        let timings = check_frame_counts(&sheet, &frame_names)?;
+        check_player_width(&sheet)?;
+        Ok(RedHatBoy {
+            state_machine,
+            sprite_sheet: sheet,
+            image,
+            state_listeners: vec![],
+            logged_drops: HashSet::new(),
+            wall_contact: 0,
+            touching_wall: false,
+            crouch_held: 0,
+            physics: PhysicsConfig::default(),
+            last_knockout_cause: None,
+            frame_names,
+            timings,
+        })
+    }
+
+    pub fn set_physics(&mut self, physics: PhysicsConfig) {
+        self.state_machine.context_mut().falling_frames = physics.falling_frames;
+        self.physics = physics;
+    }
+
+    pub fn set_input_windows(&mut self, input_windows: InputWindows) {
+        self.state_machine.context_mut().input_windows = input_windows;
+    }
+
+    // 読み直したシートに今のアニメーションのセルが欠けていたら、前のシートのまま描き続ける
+    fn set_sheet(&mut self, sheet: Sheet) -> Result<()> {
+        let frame_names = FrameNames::from_sheet(&sheet);
+        let timings = check_frame_counts(&sheet, &frame_names)?;
+        check_player_width(&sheet)?;
+        let animation = self.state_machine.frame_name();
+        let frame_name = frame_names.format(animation, self.animation_index(animation));
+        if !sheet.frames.contains_key(&frame_name) {
+            return Err(anyhow!("The new sheet has no cell '{}'", frame_name));
+        }
+        self.sprite_sheet = sheet;
+        self.frame_names = frame_names;
+        self.timings = timings;
+        Ok(())
+    }
+
+    fn frame_name(&self) -> String {
+        let animation = self.state_machine.frame_name();
+        self.frame_names
+            .format(animation, self.animation_index(animation))
+    }
+
+    // 何枚目のフレームか(0始まり)
+    fn animation_index(&self, animation: &str) -> u16 {
+        let frame = self.state_machine.context().frame;
+        let timing = self
+            .timings
+            .get(animation)
+            .expect("Animation was checked when the sheet loaded");
+        let index = timing.index(frame);
+        let tag = self.state_machine.tag();
+        if matches!(
+            tag,
+            RedHatBoyStateTag::Falling | RedHatBoyStateTag::KnockedOut
+        ) {
+            knockout_index(tag, index, timing.cells, self.physics.knockout_animation)
+        } else {
+            index
+        }
+    }
+
+    fn current_sprite(&self) -> Option<&Cell> {
+        self.sprite_sheet.frames.get(&self.frame_name())
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        let sprite = self.current_sprite().expect("Cell not found");
+        // 無敵時間中は点滅させる
+        let blinking = self.is_invincible()
+            && (self.state_machine.context().invincible_ticks / 4).is_multiple_of(2);
+        if blinking {
+            renderer.set_alpha(INVINCIBLE_ALPHA);
+        }
+        renderer.draw_image_sub(
+            &self.image,
+            &Rect::new_from_x_y(
+                sprite.frame.x.into(),
+                sprite.frame.y.into(),
+                sprite.frame.w.into(),
+                sprite.frame.h.into(),
+            ),
+            &self.destination_box(),
+        );
+        if blinking {
+            renderer.set_alpha(1.0);
+        }
+    }
+
+    fn destination_box(&self) -> Rect {
+        let sprite = self.current_sprite().expect("Cell not found");
+
+        Rect::new_from_x_y(
+            self.state_machine.context().position.x + sprite.sprite_source_size.x,
+            self.state_machine.context().position.y + sprite.sprite_source_size.y,
+            sprite.frame.w,
+            sprite.frame.h,
+        )
+    }
+
+    fn bounding_box(&self) -> Rect {
+        const X_OFFSET: i16 = 18;
+        const Y_OFFSET: i16 = 14;
+        const W_OFFSET: i16 = 28;
+        let mut bounding_box = self.destination_box();
+        bounding_box.set_x(bounding_box.x() + X_OFFSET);
+        bounding_box.w -= W_OFFSET;
+        bounding_box.set_y(bounding_box.y() + Y_OFFSET);
+        bounding_box.h -= Y_OFFSET;
+        bounding_box
+    }
+
+    // 1pxかすっただけで倒れないように、四方をhazard_marginだけ縮めた当たり判定
+    fn hazard_box(&self) -> Rect {
+        let margin = self.physics.hazard_margin;
+        let bounding_box = self.bounding_box();
+        Rect::new_from_x_y(
+            bounding_box.x() + margin,
+            bounding_box.y() + margin,
+            (bounding_box.w - margin * 2).max(0),
+            (bounding_box.h - margin * 2).max(0),
+        )
+    }
+
+    // 倒れている間は地面に寝ているので影を出さない
+    fn draw_shadow(&self, renderer: &Renderer) {
+        if self.is_down() {
+            return;
+        }
+        renderer.fill_rect(
+            &shadow_rect(
+                &self.bounding_box(),
+                red_hat_boy_states::FLOOR - self.pos_y(),
+            ),
+            SHADOW_COLOR,
+        );
+    }
+
+    fn draw_hitboxes(&self, renderer: &Renderer) {
+        renderer.fill_rect(&self.bounding_box(), "rgba(0, 128, 255, 0.3)");
+        renderer.fill_rect(&self.hazard_box(), "rgba(255, 0, 0, 0.4)");
+    }
+
+    // 実際のupdateと同じ計算で、この先の少年の中心の位置を予測する。
+    // 世界が動く分はx座標に足していく。足場への着地は考慮しない
+    pub fn predicted_arc(&self, ticks: u16) -> Vec<Point> {
+        let mut context = *self.state_machine.context();
+        let destination = self.destination_box();
+        let center = Point {
+            x: destination.x() + destination.w / 2 - context.position.x,
+            y: destination.y() + destination.h / 2 - context.position.y,
+        };
+        let mut x = context.position.x;
+        (0..ticks)
+            .map(|_| {
+                context = context.update(1);
+                x += context.velocity.x;
+                Point {
+                    x: x + center.x,
+                    y: context.position.y + center.y,
+                }
+            })
+            .collect()
+    }
+
+    fn pos_x(&self) -> i16 {
+        self.state_machine.context().position.x
+    }
+
+    fn pos_y(&self) -> i16 {
+        self.state_machine.context().position.y
+    }
+
+    fn velocity_y(&self) -> i16 {
+        self.state_machine.context().velocity.y
+    }
+
+    // 前のフレームでは足元がtopより上にあった
+    fn came_from_above(&self, top: i16) -> bool {
+        self.bounding_box().bottom() - self.velocity_y() <= top
+    }
+
+    pub fn on_state_change(&mut self, listener: impl FnMut(&StateChanged) + 'static) {
+        self.state_listeners.push(Box::new(listener));
+    }
+
+    // 受け付けられたかを返す
+    fn transition(&mut self, event: Event) -> bool {
+        match self.try_transition(event) {
+            Ok(()) => true,
+            Err(dropped) => {
+                if self.first_drop_in_state(dropped) {
+                    debug!("{}", dropped);
+                }
+                false
+            }
+        }
+    }
+
+    // 押しっぱなしの入力で毎フレーム出力しないよう、(状態, イベント)の組ごとに
+    // その状態にいる間は1度だけ出力する。着地の高さなどイベントの値は区別しない
+    fn first_drop_in_state(&mut self, dropped: DroppedEvent) -> bool {
+        self.logged_drops
+            .insert((dropped.state, mem::discriminant(&dropped.event)))
+    }
+
+    pub fn state_tag(&self) -> RedHatBoyStateTag {
+        self.state_machine.tag()
+    }
+
+    pub fn try_transition(&mut self, event: Event) -> Result<(), DroppedEvent> {
+        let from = self.state_machine.tag();
+        self.state_machine = self.state_machine.try_transition(event)?;
+        let to = self.state_machine.tag();
+        // フレームが進んだだけの場合は通知しない
+        if from != to {
+            self.logged_drops.clear();
+            let change = StateChanged { from, to };
+            self.state_listeners
+                .iter_mut()
+                .for_each(|listener| listener(&change));
+        }
+        Ok(())
+    }
+
+    fn update(&mut self) {
+        if !self.touching_wall {
+            self.wall_contact = 0;
+        }
+        self.touching_wall = false;
+        self.transition(Event::Update);
+    }
+
+    fn run_right(&mut self) {
+        self.transition(Event::Run);
+    }
+
+    // 短く押すとスライド、押し続けるとしゃがみ歩き。空中では急降下する
+    fn slide(&mut self) {
+        if self.is_jumping() {
+            self.transition(Event::FastFall);
+            return;
+        }
+        self.crouch_held = self.crouch_held.saturating_add(1);
+        if self.crouch_held > CROUCH_HOLD_FRAMES {
+            self.transition(Event::Crouch);
+        } else {
+            self.transition(Event::Slide);
+        }
+    }
+
+    fn stand_up(&mut self) {
+        self.crouch_held = 0;
+        if self.state_tag() == RedHatBoyStateTag::Crouching {
+            self.transition(Event::StandUp);
+        }
+    }
+
+    fn jump(&mut self) {
+        if self.wall_contact > 0 {
+            // ロックアウト中で跳べなかった時は、壁に触れたままなので数え続ける
+            if self.transition(Event::WallJump) {
+                self.wall_contact = 0;
+            }
+        } else {
+            self.transition(Event::Jump);
+        }
+    }
+
+    fn hit_wall(&mut self) {
+        self.touching_wall = true;
+        self.wall_contact += 1;
+        if self.wall_contact > WALL_CONTACT_GRACE {
+            self.knock_out(KnockOutCause::Wall);
+        }
+    }
+
+    // 倒れている途中か、倒れきった
+    fn is_down(&self) -> bool {
+        matches!(
+            self.state_tag(),
+            RedHatBoyStateTag::Falling | RedHatBoyStateTag::KnockedOut
+        )
+    }
+
+    fn is_jumping(&self) -> bool {
+        self.state_tag() == RedHatBoyStateTag::Jumping
+    }
+
+    // 床か足場の上に立っていて、宙に浮いていない
+    pub fn is_grounded(&self) -> bool {
+        self.state_machine.is_grounded()
+    }
+
+    // 自分で走っている間だけ風の影響を受ける
+    fn feels_wind(&self) -> bool {
+        matches!(
+            self.state_tag(),
+            RedHatBoyStateTag::Running
+                | RedHatBoyStateTag::Sliding
+                | RedHatBoyStateTag::Crouching
+                | RedHatBoyStateTag::Jumping
+        )
+    }
+
+    fn knock_out(&mut self, cause: KnockOutCause) {
+        if !self.is_invincible() && self.try_transition(Event::KnockOut).is_ok() {
+            self.last_knockout_cause = Some(cause);
+        }
+    }
+
+    // まだ倒れていない、または復帰した後はNone
+    pub fn last_knockout_cause(&self) -> Option<KnockOutCause> {
+        self.last_knockout_cause
+    }
+
+    fn in_intro(&self) -> bool {
+        self.state_tag() == RedHatBoyStateTag::Intro
+    }
+
+    fn knocked_out(&self) -> bool {
+        self.state_tag() == RedHatBoyStateTag::KnockedOut
+    }
+
+    fn respawn(&mut self) {
+        self.transition(Event::Respawn);
+        self.last_knockout_cause = None;
+        self.grant_invincibility();
+    }
+
+    pub fn grant_invincibility(&mut self) {
+        self.state_machine.context_mut().invincible_ticks = RESPAWN_INVINCIBLE_FRAMES;
+    }
+
+    fn is_invincible(&self) -> bool {
+        self.state_machine.context().invincible_ticks > 0
+    }
+
+    fn land_on(&mut self, position: i16, platform_velocity: i16) {
+        self.transition(Event::Land(position, platform_velocity));
+    }
+
+    fn walk_speed(&self) -> i16 {
+        self.state_machine.context().velocity.x
+    }
+
+    // 壁から跳ね返っている間だけ、少年は左へ進む
+    fn is_wall_jumping(&self) -> bool {
+        self.state_machine.context().wall_jump_lockout > 0
+    }
+}
+
+use red_hat_boy_states::*;
+
+mod red_hat_boy_states {
+    use super::{HEIGHT, WIDTH};
+    use crate::{engine::Point, settings::InputWindows};
+    // 地面の高さ
+    pub const FLOOR: i16 = 479;
+    const PLAYER_HEIGHT: i16 = HEIGHT - FLOOR;
+    // rhb.jsonの1枚の絵の幅。シートと合っているかは読み込む時に確かめる
+    pub const PLAYER_WIDTH: i16 = 160;
+    // 操作が始まる時の位置。画面の真ん中に立つ
+    pub const STARTING_POINT: i16 = (WIDTH - PLAYER_WIDTH) / 2;
+    // 画面の左外からSTARTING_POINTまで、このフレーム数(1秒)をかけて走ってくる
+    pub const INTRO_FRAMES: i16 = 60;
+    const INTRO_SPEED: i16 = 7;
+    // rhb.jsonにおけるフレームの名前
+    const IDLE_FRAME_NAME: &str = "Idle";
+    const RUN_FRAME_NAME: &str = "Run";
+    const SLIDING_FRAME_NAME: &str = "Slide";
+    const CROUCHING_FRAME_NAME: &str = "Slide";
+    const JUMPING_FRAME_NAME: &str = "Jump";
+    const FALLING_FRAME_NAME: &str = "Dead";
+    // rhb.jsonにおけるフレームの枚数*3
+    const IDLE_FRAMES: u8 = 30;
+    const RUNNING_FRAMES: u8 = 24;
+    const SLIDING_FRAMES: u8 = 15;
+    const JUMPING_FRAMES: u8 = 36;
+    pub const FALLING_FRAMES: u8 = 30;
+    // しゃがみ歩きはSlideで一番低い姿勢の3枚目で止める
+    const CROUCHING_FRAME: u8 = 6;
+    // 読み込み時に枚数を確かめるアニメーション。
+    // しゃがみはSlideの1枚だけを使うので含めない
+    pub const ANIMATION_FRAMES: [(&str, u8); 5] = [
+        (IDLE_FRAME_NAME, IDLE_FRAMES),
+        (RUN_FRAME_NAME, RUNNING_FRAMES),
+        (SLIDING_FRAME_NAME, SLIDING_FRAMES),
+        (JUMPING_FRAME_NAME, JUMPING_FRAMES),
+        (FALLING_FRAME_NAME, FALLING_FRAMES),
+    ];
+
+    pub const RUNNING_SPEED: i16 = 3;
+    const CROUCHING_SPEED: i16 = 2;
+    const JUMP_SPEED: i16 = -20;
+    const GRAVITY: i16 = 1;
+    const TERMINAL_VELOCITY: i16 = 20;
+    // 急降下を始めた時の下向きの速さと、急降下中の重力・最高速度
+    const FAST_FALL_SPEED: i16 = 12;
+    const FAST_FALL_GRAVITY: i16 = 3;
+    const FAST_FALL_TERMINAL_VELOCITY: i16 = 30;
+    // 倒れた後、このフレーム数ごとに横方向の速度を1ずつ落とす
+    pub const DECELERATION_INTERVAL: u8 = 4;
+    const WALL_JUMP_SPEED: i16 = -15;
+    // 上昇中の足場から跳んだ時に勢いを上乗せできるフレーム数
+    const PLATFORM_LIFT_FRAMES: u8 = 2;
+    // 壁ジャンプ後、次の壁ジャンプができるまでのフレーム数
+    const WALL_JUMP_LOCKOUT: u8 = 30;
+
+    #[derive(Copy, Clone)]
+    pub struct RedHatBoyContext {
+        pub frame: u8,
+        pub position: Point,
+        pub velocity: Point,
+        pub wall_jump_lockout: u8,
+        pub invincible_ticks: u16,
+        pub fast_falling: bool,
+        // 上昇中の足場に着地した時の上向きの速度。跳ぶと上乗せされる
+        pub platform_lift: i16,
+        pub platform_lift_frames: u8,
+        // 倒れ始めてからKnockedOutになるまでのフレーム数。PhysicsConfigから設定する
+        pub falling_frames: u8,
+        // 設定から読むジャンプの入力の猶予
+        pub input_windows: InputWindows,
+        // 最後に床か足場に立っていてから経ったフレーム数
+        pub airborne_frames: u8,
+        // 空中で押したジャンプから経ったフレーム数。押していなければNone
+        pub buffered_jump: Option<u8>,
+    }
+
+    impl RedHatBoyContext {
+        pub fn update(mut self, frame_count: u8) -> Self {
+            if self.fast_falling {
+                self.velocity.y =
+                    (self.velocity.y + FAST_FALL_GRAVITY).min(FAST_FALL_TERMINAL_VELOCITY);
+            } else if self.velocity.y < TERMINAL_VELOCITY {
+                self.velocity.y += GRAVITY;
+            }
+            self.frame = (self.frame + 1) % frame_count;
+            if self.platform_lift_frames > 0 {
+                self.platform_lift_frames -= 1;
+                if self.platform_lift_frames == 0 {
+                    self.platform_lift = 0;
+                }
+            }
+            self.invincible_ticks = self.invincible_ticks.saturating_sub(1);
+            if self.wall_jump_lockout > 0 {
+                self.wall_jump_lockout -= 1;
+                if self.wall_jump_lockout == 0 {
+                    self.velocity.x = self.velocity.x.abs();
+                }
+            }
+            self.position.y += self.velocity.y;
+            self.position.y = self.position.y.min(FLOOR);
+            self.airborne_frames = if self.position.y >= FLOOR {
+                0
+            } else {
+                self.airborne_frames.saturating_add(1)
+            };
+            self.buffered_jump = self.buffered_jump.map(|frames| frames.saturating_add(1));
+            self
+        }
+
+        // 足場から走り落ちてもcoyote_timeの間は跳べる
+        pub fn can_jump(&self) -> bool {
+            self.airborne_frames <= self.input_windows.coyote_time
+        }
+
+        // 押してから着地するまでのupdateの回数がjump_buffer以内なら跳ぶ
+        // 着地したときに呼ぶ。猶予を過ぎていても押した記録は消す
+        fn take_buffered_jump(&mut self) -> bool {
+            self.buffered_jump
+                .take()
+                .is_some_and(|frames| frames <= self.input_windows.jump_buffer)
+        }
+
+        fn reset_frame(mut self) -> Self {
+            self.frame = 0;
+            self
+        }
+
+        fn set_frame(mut self, frame: u8) -> Self {
+            self.frame = frame;
+            self
+        }
+
+        fn run_right(mut self) -> Self {
+            self.velocity.x += RUNNING_SPEED;
+            self
+        }
+
+        fn set_horizontal_velocity(mut self, x: i16) -> Self {
+            self.velocity.x = x;
+            self
+        }
+
+        // 上昇中の足場の勢いを上乗せして跳ぶ
+        fn jump(mut self) -> Self {
+            self.velocity.y = JUMP_SPEED + self.platform_lift;
+            self.platform_lift = 0;
+            self.platform_lift_frames = 0;
+            self.buffered_jump = None;
+            self
+        }
+
+        // 下降中の足場ではその速さで一緒に下がり、次のフレームでも足場に触れたままにする。
+        // 上昇中の足場では少しの間だけ跳ぶ勢いとして覚えておく
+        fn carry(mut self, platform_velocity: i16) -> Self {
+            if platform_velocity > 0 {
+                self.velocity.y = platform_velocity;
+            } else if platform_velocity < 0 {
+                self.platform_lift = platform_velocity;
+                self.platform_lift_frames = PLATFORM_LIFT_FRAMES;
+            }
+            self
+        }
+
+        // 壁を蹴って逆向きに跳ね返る
+        fn push_off_wall(mut self) -> Self {
+            self.velocity.x = -self.velocity.x.abs();
+            self.velocity.y = WALL_JUMP_SPEED;
+            self.wall_jump_lockout = WALL_JUMP_LOCKOUT;
+            self.fast_falling = false;
+            self
+        }
+
+        fn fast_fall(mut self) -> Self {
+            self.velocity.y = self.velocity.y.max(FAST_FALL_SPEED);
+            self.fast_falling = true;
+            self
+        }
+
+        // 横方向の速度はFallingの間に徐々に落とす
+        fn fall(mut self) -> Self {
+            self.velocity.y = GRAVITY;
+            self.fast_falling = false;
+            self
+        }
+
+        fn decelerate(mut self) -> Self {
+            if self.frame.is_multiple_of(DECELERATION_INTERVAL) {
+                self.velocity.x -= self.velocity.x.signum();
+            }
+            self
+        }
+
+        fn stop(mut self) -> Self {
+            self.velocity.x = 0;
+            self.velocity.y = GRAVITY;
+            self
+        }
+
+        // 着地したら落下の速度は0に戻す
+        fn set_on(mut self, position: i16) -> Self {
+            let position = position - PLAYER_HEIGHT;
+            self.position.y = position;
+            self.velocity.y = 0;
+            self.fast_falling = false;
+            self.airborne_frames = 0;
+            self
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct RedHatBoyState<S> {
+        pub context: RedHatBoyContext,
+        _state: S,
+    }
+    impl<S> RedHatBoyState<S> {
+        #[cfg(test)]
+        pub fn from_context(context: RedHatBoyContext, state: S) -> Self {
+            RedHatBoyState {
+                context,
+                _state: state,
+            }
+        }
+
+        pub fn context(&self) -> &RedHatBoyContext {
+            &self.context
+        }
+
+        pub fn context_mut(&mut self) -> &mut RedHatBoyContext {
+            &mut self.context
+        }
+
+        // 空中で押されたジャンプを覚えておく。押し直すと数え直す
+        pub fn buffer_jump(mut self) -> Self {
+            self.context.buffered_jump = Some(0);
+            self
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Intro;
+    impl RedHatBoyState<Intro> {
+        pub fn intro() -> Self {
+            RedHatBoyState {
+                context: RedHatBoyContext {
+                    frame: 0,
+                    position: Point {
+                        x: STARTING_POINT - INTRO_FRAMES * INTRO_SPEED,
+                        y: FLOOR,
+                    },
+                    velocity: Point { x: 0, y: 0 },
+                    wall_jump_lockout: 0,
+                    invincible_ticks: 0,
+                    fast_falling: false,
+                    platform_lift: 0,
+                    platform_lift_frames: 0,
+                    falling_frames: FALLING_FRAMES,
+                    input_windows: InputWindows::default(),
+                    airborne_frames: 0,
+                    buffered_jump: None,
+                },
+                _state: Intro {},
+            }
+        }
+
+        pub fn frame_name(&self) -> &str {
+            RUN_FRAME_NAME
+        }
+
+        // 世界は止めたまま、少年自身のx座標を進める
+        pub fn update(mut self) -> IntroEndState {
+            self.context = self.context.update(RUNNING_FRAMES);
+            self.context.position.x = (self.context.position.x + INTRO_SPEED).min(STARTING_POINT);
+            if self.context.position.x >= STARTING_POINT {
+                IntroEndState::Complete(self.finish())
+            } else {
+                IntroEndState::Intro(self)
+            }
+        }
+
+        pub fn finish(self) -> RedHatBoyState<Idle> {
+            RedHatBoyState {
+                context: self.context.reset_frame(),
+                _state: Idle {},
+            }
+        }
+    }
+    pub enum IntroEndState {
+        Complete(RedHatBoyState<Idle>),
+        Intro(RedHatBoyState<Intro>),
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Idle;
+    impl RedHatBoyState<Idle> {
+        pub fn new() -> Self {
+            RedHatBoyState {
+                context: RedHatBoyContext {
+                    frame: 0,
+                    position: Point {
+                        x: STARTING_POINT,
+                        y: FLOOR,
+                    },
+                    velocity: Point { x: 0, y: 0 },
+                    wall_jump_lockout: 0,
+                    invincible_ticks: 0,
+                    fast_falling: false,
+                    platform_lift: 0,
+                    platform_lift_frames: 0,
+                    falling_frames: FALLING_FRAMES,
+                    input_windows: InputWindows::default(),
+                    airborne_frames: 0,
+                    buffered_jump: None,
+                },
+                _state: Idle {},
+            }
+        }
+
+        pub fn run(self) -> RedHatBoyState<Running> {
+            RedHatBoyState {
+                context: self.context.reset_frame().run_right(),
+                _state: Running {},
+            }
+        }
+
+        pub fn frame_name(&self) -> &str {
+            IDLE_FRAME_NAME
+        }
+
+        pub fn update(mut self) -> Self {
+            self.context = self.context.update(IDLE_FRAMES);
+            self
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Running;
+    impl RedHatBoyState<Running> {
+        pub fn frame_name(&self) -> &str {
+            RUN_FRAME_NAME
+        }
+
+        pub fn update(mut self) -> RunningEndState {
+            self.context = self.context.update(RUNNING_FRAMES);
+            if self.context.airborne_frames == 0 {
+                self.release_buffered_jump()
+            } else {
+                RunningEndState::Running(self)
+            }
+        }
+
+        // 床か足場に降りたフレームで、落ちている間に押されていたジャンプを跳ぶ
+        pub fn release_buffered_jump(mut self) -> RunningEndState {
+            if self.context.take_buffered_jump() {
+                RunningEndState::Jumping(self.jump())
+            } else {
+                RunningEndState::Running(self)
+            }
+        }
+
+        pub fn slide(self) -> RedHatBoyState<Sliding> {
+            RedHatBoyState {
+                context: self.context.reset_frame(),
+                _state: Sliding {},
+            }
+        }
+
+        pub fn jump(self) -> RedHatBoyState<Jumping> {
+            RedHatBoyState {
+                context: self.context.jump().reset_frame(),
+                _state: Jumping {},
+            }
+        }
+
+        pub fn crouch(self) -> RedHatBoyState<Crouching> {
+            RedHatBoyState {
+                context: self
+                    .context
+                    .set_frame(CROUCHING_FRAME)
+                    .set_horizontal_velocity(CROUCHING_SPEED),
+                _state: Crouching {},
+            }
+        }
+
+        pub fn knock_out(self) -> RedHatBoyState<Falling> {
+            RedHatBoyState {
+                context: self.context.reset_frame().fall(),
+                _state: Falling {},
+            }
+        }
+
+        pub fn land_on(self, position: i16, platform_velocity: i16) -> RedHatBoyState<Running> {
+            RedHatBoyState {
+                context: self.context.set_on(position).carry(platform_velocity),
+                _state: Running {},
+            }
+        }
+    }
+
+    pub enum RunningEndState {
+        Running(RedHatBoyState<Running>),
+        Jumping(RedHatBoyState<Jumping>),
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Sliding;
+    impl RedHatBoyState<Sliding> {
+        pub fn frame_name(&self) -> &str {
+            SLIDING_FRAME_NAME
+        }
+
+        pub fn update(mut self) -> SlidingEndState {
+            self.context = self.context.update(SLIDING_FRAMES);
+            if self.context.airborne_frames == 0 && self.context.take_buffered_jump() {
+                SlidingEndState::Jumping(self.jump())
+            } else if self.context.frame + 1 >= SLIDING_FRAMES {
+                SlidingEndState::Complete(self.stand())
+            } else {
+                SlidingEndState::Sliding(self)
+            }
+        }
+
+        pub fn release_buffered_jump(mut self) -> SlidingEndState {
+            if self.context.take_buffered_jump() {
+                SlidingEndState::Jumping(self.jump())
+            } else {
+                SlidingEndState::Sliding(self)
+            }
+        }
+
+        pub fn stand(self) -> RedHatBoyState<Running> {
+            RedHatBoyState {
+                context: self.context.reset_frame(),
+                _state: Running,
+            }
+        }
+
+        // スライドを途中でやめてジャンプする。高さはスライド中の位置のまま
+        pub fn jump(self) -> RedHatBoyState<Jumping> {
+            RedHatBoyState {
+                context: self.context.jump().reset_frame(),
+                _state: Jumping {},
+            }
+        }
+
+        pub fn crouch(self) -> RedHatBoyState<Crouching> {
+            RedHatBoyState {
+                context: self
+                    .context
+                    .set_frame(CROUCHING_FRAME)
+                    .set_horizontal_velocity(CROUCHING_SPEED),
+                _state: Crouching {},
+            }
+        }
+
+        pub fn land_on(self, position: i16, platform_velocity: i16) -> RedHatBoyState<Sliding> {
+            RedHatBoyState {
+                context: self.context.set_on(position).carry(platform_velocity),
+                _state: Sliding {},
+            }
+        }
+
+        pub fn knock_out(self) -> RedHatBoyState<Falling> {
+            RedHatBoyState {
+                context: self.context.reset_frame().fall(),
+                _state: Falling {},
+            }
+        }
+    }
+    pub enum SlidingEndState {
+        Complete(RedHatBoyState<Running>),
+        Sliding(RedHatBoyState<Sliding>),
+        Jumping(RedHatBoyState<Jumping>),
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Crouching;
+    impl RedHatBoyState<Crouching> {
+        pub fn frame_name(&self) -> &str {
+            CROUCHING_FRAME_NAME
+        }
+
+        pub fn update(mut self) -> Self {
+            self.context = self
+                .context
+                .update(SLIDING_FRAMES)
+                .set_frame(CROUCHING_FRAME);
+            self
+        }
+
+        pub fn stand(self) -> RedHatBoyState<Running> {
+            RedHatBoyState {
+                context: self
+                    .context
+                    .reset_frame()
+                    .set_horizontal_velocity(RUNNING_SPEED),
+                _state: Running {},
+            }
+        }
+
+        pub fn land_on(self, position: i16, platform_velocity: i16) -> RedHatBoyState<Crouching> {
+            RedHatBoyState {
+                context: self.context.set_on(position).carry(platform_velocity),
+                _state: Crouching {},
+            }
+        }
+
+        pub fn knock_out(self) -> RedHatBoyState<Falling> {
+            RedHatBoyState {
+                context: self.context.reset_frame().fall(),
+                _state: Falling {},
+            }
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Jumping;
+    impl RedHatBoyState<Jumping> {
+        pub fn frame_name(&self) -> &str {
+            JUMPING_FRAME_NAME
+        }
+
+        pub fn update(mut self) -> JumpingEndState {
+            self.context = self.context.update(JUMPING_FRAMES);
+            if self.context.position.y >= FLOOR {
+                self.land_on(HEIGHT.into(), 0)
+            } else {
+                JumpingEndState::Jumping(self)
+            }
+        }
+
+        // 着地の前に押されていたジャンプは、着地したフレームで跳び直す
+        pub fn land_on(self, position: i16, platform_velocity: i16) -> JumpingEndState {
+            let running = RedHatBoyState {
+                context: self
+                    .context
+                    .reset_frame()
+                    .set_on(position)
+                    .carry(platform_velocity),
+                _state: Running {},
+            };
+            match running.release_buffered_jump() {
+                RunningEndState::Running(running) => JumpingEndState::Landing(running),
+                RunningEndState::Jumping(jumping) => JumpingEndState::Jumping(jumping),
+            }
+        }
+
+        pub fn knock_out(self) -> RedHatBoyState<Falling> {
+            RedHatBoyState {
+                context: self.context.reset_frame().fall(),
+                _state: Falling {},
+            }
+        }
+
+        pub fn fast_fall(self) -> RedHatBoyState<Jumping> {
+            RedHatBoyState {
+                context: self.context.fast_fall(),
+                _state: Jumping {},
+            }
+        }
+
+        pub fn wall_jump(self) -> RedHatBoyState<Jumping> {
+            RedHatBoyState {
+                context: self.context.push_off_wall().reset_frame(),
+                _state: Jumping {},
+            }
+        }
+    }
+    pub enum JumpingEndState {
+        Landing(RedHatBoyState<Running>),
+        Jumping(RedHatBoyState<Jumping>),
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Falling;
+
+    impl RedHatBoyState<Falling> {
+        pub fn frame_name(&self) -> &str {
+            FALLING_FRAME_NAME
+        }
+
+        pub fn update(mut self) -> FallingEndState {
+            let falling_frames = self.context.falling_frames.max(1);
+            self.context = self.context.update(falling_frames).decelerate();
+            if self.context.frame + 1 >= falling_frames {
+                FallingEndState::Complete(self.knock_out())
+            } else {
+                FallingEndState::Falling(self)
+            }
+        }
+
+        pub fn knock_out(self) -> RedHatBoyState<KnockedOut> {
+            RedHatBoyState {
+                context: self.context.stop(),
+                _state: KnockedOut {},
+            }
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct KnockedOut;
+    impl RedHatBoyState<KnockedOut> {
+        pub fn frame_name(&self) -> &str {
+            FALLING_FRAME_NAME
+        }
+
+        // 設定された倒れる長さと入力の猶予は生き返っても引き継ぐ
+        pub fn respawn(self) -> RedHatBoyState<Running> {
+            let mut running = RedHatBoyState::new().run();
+            running.context.falling_frames = self.context.falling_frames;
+            running.context.input_windows = self.context.input_windows;
+            running
+        }
+    }
+
+    pub enum FallingEndState {
+        Complete(RedHatBoyState<KnockedOut>),
+        Falling(RedHatBoyState<Falling>),
+    }
+}
+
+#[cfg(feature = "auto-start")]
+#[wasm_bindgen(start)]
+pub fn main_js() -> Result<(), JsValue> {
+    start_game(CANVAS_ID, None)
+}
+
+// ページ側のJSから、ゲームを描くcanvasのidを指定して起動する。
+// 状態はcanvasごとに別々に持つので、複数のcanvasで同時に動かせる
+// telemetryを渡すと、走り始め・チェックポイント・倒れた時・ゲームオーバーの時にイベントを受け取れる
+#[wasm_bindgen]
+pub fn start_game(canvas_id: &str, telemetry: Option<js_sys::Function>) -> Result<(), JsValue> {
+    console_error_panic_hook::set_once();
+    // ?log=warn のようにしてログの出力レベルを変えられる
+    if let Some(level) = browser::query_param("log")
+        .ok()
+        .flatten()
+        .and_then(|name| browser::LogLevel::from_name(&name))
+    {
+        browser::set_log_level(level);
+    }
+
+    // canvasが見つからない場合はページ側で表示できるようにJSにエラーを返す
+    let context =
+        browser::context(canvas_id).map_err(|err| JsValue::from_str(&format!("{:#}", err)))?;
+
+    browser::spawn_local(async move {
+        // 読み込みに失敗した時にエラーを表示できるよう、別にRendererを作っておく
+        let renderer = Renderer::new(context.clone());
+        let telemetry = telemetry.map(|callback| {
+            Rc::new(
+                move |event: TelemetryEvent| match JsValue::from_serde(&event) {
+                    Ok(event) => {
+                        if let Err(err) = callback.call1(&JsValue::NULL, &event) {
+                            error!("Telemetry callback failed {:#?}", err);
+                        }
+                    }
+                    Err(err) => error!("Could not serialize {:?}: {:#?}", event, err),
+                },
+            ) as TelemetrySink
+        });
+        // #seed=1234 で共有された走りを再現する。読むのは起動時だけなので、
+        // 途中でフラグメントが変わっても今の走りには影響しない
+        // ?practice=two_stones のように指定すると、そのセグメントだけを繰り返す
+        // ミュートなどが最初のフレームから効くように、アセットより先に読む
+        let settings = Settings::load(&LocalStorage);
+        // ?daily を付けるとデイリーチャレンジになる。日付は起動した時のUTCで決める
+        let daily = browser::query_param("daily").ok().flatten().is_some();
+        let game = match (practice_segment(), seed_from_fragment()) {
+            (Some(kind), _) => WalkTheDog::new_practice(telemetry, settings, kind),
+            (None, _) if daily => WalkTheDog::new_daily(telemetry, settings, browser::utc_day()),
+            (None, Some(seed)) => WalkTheDog::new_seeded(telemetry, settings, seed),
+            (None, None) => WalkTheDog::new(telemetry, settings),
+        };
+
+        if let Err(err) = GameLoop::start(game, Renderer::new(context)).await {
+            let message = format!("Failed to load assets: {:#}", err);
+            error!("{}", message);
+            renderer.draw_error(&message);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        browser::MemoryStore,
+        engine::{DrawCommand, RecordingRenderer},
+        settings::{KeyBindings, Preset, PRESETS},
+    };
+
+    #[test]
+    fn draw_to_renders_a_whole_frame_at_the_target_scale() {
+        let game = WalkTheDog::new(None, Settings::default());
+        let recording = RecordingRenderer::new();
+        let (_, height, scale) = thumbnail_size(150);
+        assert_eq!(height, 150);
+        recording.renderer().set_scale(scale);
+
+        game.draw_to(recording.renderer());
+        let commands = recording.take_commands();
+        assert!(matches!(
+            commands.first(),
+            Some(DrawCommand::BeginFrame { scale }) if *scale == 0.25
+        ));
+        assert_eq!(commands.last(), Some(&DrawCommand::EndFrame));
+
+        // 描き直しても同じものが描かれる
+        game.draw_to(recording.renderer());
+        assert_eq!(recording.take_commands(), commands);
+    }
+
+    #[test]
+    fn a_thumbnail_of_a_walk_shows_the_same_scene_without_changing_it() {
+        let mut drawn = WalkTheDog::Loaded(Box::new(loaded_walk(17)));
+        let mut untouched = WalkTheDog::Loaded(Box::new(loaded_walk(17)));
+        let screen = RecordingRenderer::new();
+        let thumbnail = RecordingRenderer::new();
+        let (_, _, scale) = thumbnail_size(150);
+        thumbnail.renderer().set_scale(scale);
+        // Walk::thumbnailがオフスクリーンのcanvasに描くのと同じ描き方
+        let draw_thumbnail = |game: &WalkTheDog| match game {
+            WalkTheDog::Loaded(walk) => walk.draw_frame(thumbnail.renderer()),
+            WalkTheDog::Loading { .. } => unreachable!(),
+        };
+        let mut keystate = KeyState::new();
+        keystate.press("ArrowRight");
+        for tick in 0..INTRO_FRAMES as u64 + 60 {
+            keystate.start_update();
+            drawn.update(&keystate, tick);
+            untouched.update(&keystate, tick);
+            // 毎フレーム縮小版も描いておく
+            draw_thumbnail(&drawn);
+        }
+        thumbnail.take_commands();
+
+        drawn.draw_to(screen.renderer());
+        draw_thumbnail(&drawn);
+        let on_screen = screen.take_commands();
+        let in_thumbnail = thumbnail.take_commands();
+        assert_eq!(on_screen[0], DrawCommand::BeginFrame { scale: 1.0 });
+        assert_eq!(in_thumbnail[0], DrawCommand::BeginFrame { scale });
+        assert!(on_screen.len() > 2);
+        assert_eq!(on_screen[1..], in_thumbnail[1..]);
+
+        untouched.draw_to(screen.renderer());
+        assert_eq!(screen.take_commands(), on_screen);
+    }
+
+    #[test]
+    fn daily_seed_is_the_same_all_day_and_changes_the_next_day() {
+        assert_eq!(daily_seed(20261016), daily_seed(20261016));
+        assert_ne!(daily_seed(20261016), daily_seed(20261017));
+        assert_eq!(format_day(20261016), "2026-10-16");
+    }
+
+    #[test]
+    fn daily_best_only_counts_for_the_same_day() {
+        let json = serde_json::to_string(&DailyBest {
+            day: 20261016,
+            distance: 4200,
+        })
+        .unwrap();
+        assert_eq!(daily_best(&json, 20261016), Some(4200));
+        assert_eq!(daily_best(&json, 20261017), None);
+        assert_eq!(daily_best("4200", 20261016), None);
+    }
+
+    #[test]
+    fn settings_menu_changes_the_selected_item() {
+        let mut menu = SettingsMenu::default();
+        let mut settings = Settings::default();
+
+        assert!(menu.handle_key("ArrowUp", &mut settings));
+        assert_eq!(SETTINGS_ITEMS[menu.selected], SettingsItem::AntiFrustration);
+        menu.handle_key("ArrowLeft", &mut settings);
+        assert!(!settings.anti_frustration);
+
+        menu.handle_key("ArrowUp", &mut settings);
+        assert_eq!(SETTINGS_ITEMS[menu.selected], SettingsItem::Hitboxes);
+        menu.handle_key("Enter", &mut settings);
+        assert!(settings.show_hitboxes);
+
+        menu.handle_key("ArrowUp", &mut settings);
+        menu.handle_key("ArrowLeft", &mut settings);
+        assert_eq!(settings.key_preset, Preset::Ijkl);
+
+        menu.handle_key("ArrowUp", &mut settings);
+        menu.handle_key("ArrowLeft", &mut settings);
+        menu.handle_key("ArrowLeft", &mut settings);
+        assert_eq!(settings.game_speed, 0.5);
+        assert_eq!(
+            settings,
+            Settings {
+                show_hitboxes: true,
+                game_speed: 0.5,
+                key_preset: Preset::Ijkl,
+                anti_frustration: false,
+                ..Settings::default()
+            }
+        );
+
+        assert!(!menu.handle_key("Escape", &mut settings));
+    }
+
+    #[test]
+    fn no_preset_binds_a_reserved_key() {
+        PRESETS.iter().for_each(|preset| {
+            let bindings = KeyBindings::preset(*preset);
+            [Action::Run, Action::Jump, Action::Slide]
+                .iter()
+                .flat_map(|action| bindings.keys(*action))
+                .for_each(|key| {
+                    assert!(
+                        !RESERVED_KEYS.contains(key),
+                        "{:?} binds the reserved key {}",
+                        preset,
+                        key
+                    );
+                });
+        });
+    }
+
+    fn sheet_with(animations: &[(&str, u16)]) -> Sheet {
+        let mut frames = HashMap::new();
+        for (animation, cells) in animations {
+            for index in 0..*cells {
+                let rect = || engine::SheetRect {
+                    x: 0,
+                    y: 0,
+                    w: 1,
+                    h: 1,
+                };
+                frames.insert(
+                    FrameNameFormat::default().format(animation, index),
+                    Cell {
+                        frame: rect(),
+                        sprite_source_size: rect(),
+                        source_size: None,
+                        duration_ms: None,
+                    },
+                );
+            }
+        }
+        Sheet { frames, meta: None }
+    }
+
+    #[test]
+    fn the_player_width_matches_the_sheet() {
+        let mut sheet: Sheet =
+            serde_json::from_str(include_str!("../static/rhb_trimmed.json")).unwrap();
+        assert!(check_player_width(&sheet).is_ok());
+
+        if let Some(cell) = sheet.frames.get_mut("Run (3).png") {
+            cell.source_size = Some(engine::SheetSize { w: 180, h: 136 });
+        }
+        let err = check_player_width(&sheet).unwrap_err();
+        assert!(err.to_string().contains("'Run (3).png' is 180 pixels wide"));
+    }
+
+    #[test]
+    fn shadow_stays_on_the_floor_and_shrinks_with_height() {
+        let standing = Rect::new_from_x_y(100, 380, 60, 100);
+        let on_floor = shadow_rect(&standing, 0);
+        assert_eq!(on_floor, Rect::new_from_x_y(100, 475, 60, 10));
+
+        let jumping = Rect::new_from_x_y(100, 280, 60, 100);
+        let in_air = shadow_rect(&jumping, 100);
+        assert_eq!(in_air.y() + in_air.h / 2, 480);
+        assert!(in_air.w < on_floor.w);
+        assert_eq!(in_air.x() + in_air.w / 2, 130);
+    }
+
+    #[test]
+    fn scene_keeps_exact_coordinates() {
+        let scene = Scene {
+            seed: 42,
+            distance: 1234,
+            obstacles: vec![
+                ObstacleSnapshot::Stone {
+                    x: -17,
+                    y: 546,
+                    filter: None,
+                    scale: 1.0,
+                },
+                ObstacleSnapshot::Platform {
+                    x: 383,
+                    y: 375,
+                    sprites: vec!["13.png".into(), "14.png".into(), "15.png".into()],
+                    filter: Some("hue-rotate(300deg)".into()),
+                    scale: 0.5,
+                },
+            ],
+        };
+        let json = serde_json::to_string(&scene).unwrap();
+        assert!(json.contains(r#"{"type":"stone","x":-17,"y":546,"filter":null}"#));
+        assert!(json.contains(r#""filter":"hue-rotate(300deg)","scale":0.5}"#));
+        assert_eq!(serde_json::from_str::<Scene>(&json).unwrap(), scene);
+    }
+
+    // 画像には触らないので、中身の無いHtmlImageElementで足りる
+    fn platform_sheet() -> Rc<SpriteSheet> {
+        let rect = |w, h| engine::SheetRect { x: 0, y: 0, w, h };
+        let frames = ["13.png", "14.png", "15.png"]
+            .iter()
+            .map(|name| {
+                let cell = Cell {
+                    frame: rect(128, 93),
+                    sprite_source_size: rect(128, 93),
+                    source_size: None,
+                    duration_ms: None,
+                };
+                (name.to_string(), cell)
+            })
+            .collect();
+        Rc::new(SpriteSheet::new(
+            Sheet { frames, meta: None },
+            JsValue::NULL.unchecked_into(),
+        ))
+    }
+
+    #[test]
+    fn scaled_platforms_keep_their_bottom_and_reload_in_place() {
+        let sprites = ["13.png", "14.png", "15.png"];
+        let position = Point { x: 100, y: 375 };
+        let platform = segments::floating_platform(platform_sheet(), position, &sprites, None);
+        let bottom = platform.position.y + 93;
+
+        let giant =
+            segments::floating_platform(platform_sheet(), position, &sprites, None).with_scale(2.0);
+
+        assert_eq!(giant.position.y + 186, bottom);
+        assert_eq!(giant.left(), platform.left());
+        assert_eq!(
+            giant.right() - giant.left(),
+            2 * (platform.right() - platform.left())
+        );
+        // 保存した左上から置き直すと同じ場所に戻る
+        let reloaded =
+            segments::floating_platform(platform_sheet(), giant.position, &sprites, None)
+                .with_scale(2.0)
+                .with_top(giant.position.y);
+        assert_eq!(reloaded.bounding_boxes, giant.bounding_boxes);
+    }
+
+    // シートはゲームと同じものを使う。画像は中身の無いものだが、大きさは本物の画像と同じにして
+    // assets.jsonの名前を付けておく。描いても記録されるだけになる
+    fn loaded_walk(seed: u64) -> Walk {
+        loaded_walk_with(seed, &Settings::default(), Rc::new(MemoryStore::default()))
+    }
+
+    fn loaded_walk_with(seed: u64, settings: &Settings, store: Rc<dyn Store>) -> Walk {
+        let sheet = |json| serde_json::from_str::<Sheet>(json).unwrap();
+        let stone = Rc::new(engine::test_image("stone", 90, 54));
+        let obstacle_sheet = Rc::new(SpriteSheet::new(
+            sheet(include_str!("../static/tiles.json")),
+            engine::test_image("tiles", 650, 485),
+        ));
+        let course = Course {
+            obstacles: stone_and_platform(
+                stone.clone(),
+                obstacle_sheet.clone(),
+                0,
+                &segments::Palette::default(),
+                1.0,
+            ),
+            source: SegmentSource::Procedural,
+            length: None,
+        };
+        let images = WalkImages {
+            background: TiledImage::new(
+                engine::test_image("background", 1000, 750),
+                0,
+                WIDTH + CAMERA_LOOK_AHEAD,
+            ),
+            stone,
+            heart: engine::test_image("heart", 24, 24),
+            obstacle_sheet,
+        };
+        let audio = Audio::silent(settings.audio());
+        let boy = RedHatBoy::new(
+            sheet(include_str!("../static/rhb_trimmed.json")),
+            engine::test_image("boy", 1989, 366),
+        )
+        .unwrap();
+        Walk::new(boy, images, course, seed, settings, store, audio)
+    }
+
+    const WALK_GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/walk.txt");
+
+    // 決まった入力で固定ステップを進め、途中で描いた命令を記録と比べる。
+    // 描画を変えた時は UPDATE_GOLDEN=1 cargo test で記録を書き直す
+    #[test]
+    fn the_mute_key_changes_the_gain_and_saves_it() {
+        let store = Rc::new(MemoryStore::default());
+        let settings = Settings {
+            volume: 0.4,
+            ..Settings::default()
+        };
+        let mut game = WalkTheDog::Loaded(Box::new(loaded_walk_with(5, &settings, store.clone())));
+        let mut keystate = KeyState::new();
+        let mut press_mute = |game: &mut WalkTheDog| {
+            keystate.press(MUTE_KEY);
+            keystate.start_update();
+            game.update(&keystate, 0);
+            keystate.release(MUTE_KEY);
+            match game {
+                WalkTheDog::Loaded(walk) => walk.audio.gain(),
+                WalkTheDog::Loading { .. } => unreachable!(),
+            }
+        };
+
+        assert_eq!(press_mute(&mut game), 0.0);
+        let saved = Settings::load(store.as_ref());
+        assert!(saved.muted);
+        assert_eq!(saved.volume, 0.4);
+
+        // ミュートを解除すると前の音量に戻り、それも保存される
+        assert_eq!(press_mute(&mut game), 0.4);
+        assert!(!Settings::load(store.as_ref()).muted);
+    }
+
+    #[test]
+    fn running_past_where_the_boy_kept_falling_clears_the_saved_deaths() {
+        let store = Rc::new(MemoryStore::default());
+        let mut deaths = DeathHistory::default();
+        [3000, 3200, 2900]
+            .iter()
+            .for_each(|death| deaths.record(*death));
+        deaths.save(store.as_ref()).unwrap();
+        let mut walk = loaded_walk_with(13, &Settings::default(), store.clone());
+        walk.deaths = DeathHistory::load(store.as_ref());
+        assert_eq!(walk.deaths, deaths);
+
+        let mut game = WalkTheDog::Loaded(Box::new(walk));
+        let mut keystate = KeyState::new();
+        keystate.press("ArrowRight");
+        for tick in 0..INTRO_FRAMES as u64 {
+            keystate.start_update();
+            game.update(&keystate, tick);
+        }
+        if let WalkTheDog::Loaded(walk) = &mut game {
+            walk.obstacles.clear();
+            walk.distance = 3480;
+        }
+        for tick in 0..20 {
+            keystate.start_update();
+            game.update(&keystate, INTRO_FRAMES as u64 + tick);
+        }
+
+        assert_eq!(DeathHistory::load(store.as_ref()), DeathHistory::default());
+    }
+
+    #[test]
+    fn a_seeded_walk_draws_the_recorded_frames() {
+        let mut game = WalkTheDog::Loaded(Box::new(loaded_walk(42)));
+        let recording = RecordingRenderer::new();
+        let mut keystate = KeyState::new();
+        keystate.press("ArrowRight");
+        let mut frames = String::new();
+        for tick in 0..240 {
+            match tick {
+                120 => keystate.press("Space"),
+                121 => keystate.release("Space"),
+                _ => {}
+            }
+            keystate.start_update();
+            game.update(&keystate, tick);
+            if tick % 60 == 59 {
+                game.draw_to(recording.renderer());
+                frames.push_str(&format!("# tick {}\n", tick));
+                recording
+                    .take_commands()
+                    .iter()
+                    .for_each(|command| frames.push_str(&format!("{:?}\n", command)));
+            }
+        }
+
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(WALK_GOLDEN, &frames).unwrap();
+        }
+        let golden = std::fs::read_to_string(WALK_GOLDEN).unwrap();
+        assert!(
+            frames == golden,
+            "Frames differ from {}, rerun with UPDATE_GOLDEN=1 if the change is intended",
+            WALK_GOLDEN
+        );
+    }
+
+    #[test]
+    fn each_obstacle_scores_once_as_it_passes_the_boy() {
+        let mut game = WalkTheDog::Loaded(Box::new(loaded_walk(7)));
+        let mut keystate = KeyState::new();
+        keystate.press("ArrowRight");
+        let mut tick = 0;
+        let mut step = |game: &mut WalkTheDog| {
+            keystate.start_update();
+            game.update(&keystate, tick);
+            tick += 1;
+        };
+        for _ in 0..INTRO_FRAMES {
+            step(&mut game);
+        }
+
+        let walk = match &mut game {
+            WalkTheDog::Loaded(walk) => walk,
+            WalkTheDog::Loading { .. } => unreachable!(),
+        };
+        let boy_box = walk.boy.hazard_box();
+        // 少年の足元から少しだけ下を通るので、どれもニアミスになる
+        let near_miss = boy_box.bottom() + walk.scoring.near_miss_distance / 2;
+        let stones = [40, 90, 140].map(|gap| -> Box<dyn Obstacle> {
+            let position = Point {
+                x: boy_box.right() + gap,
+                y: near_miss,
+            };
+            Box::new(Barrier::new(Image::new(walk.stone.clone(), position)))
+        });
+        walk.obstacles = stones.into();
+        let bonus = walk.scoring.near_miss_bonus;
+
+        let mut awarded = vec![];
+        for _ in 0..120 {
+            let before = game_score(&game);
+            step(&mut game);
+            let gained = game_score(&game) - before;
+            if gained > 0 {
+                awarded.push(gained);
+            }
+        }
+        assert_eq!(awarded, vec![bonus; 3]);
+    }
+
+    #[test]
+    fn knockout_settings_apply_from_the_next_respawn() {
+        let mut game = WalkTheDog::Loaded(Box::new(loaded_walk(11)));
+        let mut keystate = KeyState::new();
+        keystate.press("ArrowRight");
+        let mut tick = 0;
+        let mut step = |game: &mut WalkTheDog| {
+            keystate.start_update();
+            game.update(&keystate, tick);
+            tick += 1;
+        };
+        // 登場が終わった次のフレームで走り出す
+        for _ in 0..=INTRO_FRAMES {
+            step(&mut game);
+        }
+
+        let walk = match &mut game {
+            WalkTheDog::Loaded(walk) => walk,
+            WalkTheDog::Loading { .. } => unreachable!(),
+        };
+        walk.obstacles.clear();
+        walk.settings.falling_frames = 45;
+        walk.settings.knockout_animation = KnockoutAnimation::Loop;
+        walk.boy.knock_out(KnockOutCause::Barrier);
+        // 倒れている途中の少年は、倒れ始めた時の長さのまま倒れきる
+        assert_eq!(
+            walk.boy.state_machine.context().falling_frames,
+            FALLING_FRAMES
+        );
+
+        for _ in 0..FALLING_FRAMES {
+            step(&mut game);
+        }
+        let walk = match &game {
+            WalkTheDog::Loaded(walk) => walk,
+            WalkTheDog::Loading { .. } => unreachable!(),
+        };
+        assert_eq!(walk.lives, STARTING_LIVES - 1);
+        assert_eq!(walk.boy.state_tag(), RedHatBoyStateTag::Running);
+        assert_eq!(walk.boy.state_machine.context().falling_frames, 45);
+        assert_eq!(walk.boy.physics.knockout_animation, KnockoutAnimation::Loop);
+    }
+
+    #[test]
+    fn a_shared_seed_stays_unassisted_when_the_setting_changes() {
+        let mut walk = loaded_walk(13);
+        assert!(walk.anti_frustration());
+
+        walk.change_settings(|settings| settings.anti_frustration = false);
+        assert!(!walk.anti_frustration());
+
+        walk.shared_seed = true;
+        walk.change_settings(|settings| settings.anti_frustration = true);
+        assert!(!walk.anti_frustration());
+    }
+
+    #[test]
+    fn the_walk_hears_each_real_state_change_once() {
+        let mut walk = loaded_walk(5);
+        while walk.boy.in_intro() {
+            walk.boy.update();
+        }
+        walk.state_changes.take();
+        walk.boy.run_right();
+        walk.boy.jump();
+        // フレームが進むだけの更新や、空中でのジャンプは状態を変えない
+        walk.boy.update();
+        walk.boy.jump();
+        walk.boy.knock_out(KnockOutCause::Barrier);
+        let changes: Vec<_> = walk
+            .state_changes
+            .borrow()
+            .iter()
+            .map(|change| (change.from, change.to))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (RedHatBoyStateTag::Idle, RedHatBoyStateTag::Running),
+                (RedHatBoyStateTag::Running, RedHatBoyStateTag::Jumping),
+                (RedHatBoyStateTag::Jumping, RedHatBoyStateTag::Falling),
+            ]
+        );
+
+        walk.report_state_changes();
+        assert!(walk.state_changes.borrow().is_empty());
+    }
+
+    #[test]
+    fn a_knockout_event_names_the_state_the_boy_was_in() {
+        let event = TelemetryEvent::Knockout {
+            seed: 3,
+            distance: 120,
+            state: RedHatBoyStateTag::Jumping,
+            cause: Some(KnockOutCause::Barrier),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"knockout","seed":3,"distance":120,"state":"jumping","cause":"barrier"}"#
+        );
+    }
+
+    fn game_score(game: &WalkTheDog) -> u32 {
+        match game {
+            WalkTheDog::Loaded(walk) => walk.score,
+            WalkTheDog::Loading { .. } => 0,
+        }
+    }
+
+    #[test]
+    fn frame_counts_match_the_sheet() {
+        let sheet = sheet_with(&[
+            ("Idle", 10),
+            ("Run", 8),
+            ("Slide", 5),
+            ("Jump", 12),
+            ("Dead", 10),
+        ]);
+
+        assert!(check_frame_counts(&sheet, &FrameNames::default()).is_ok());
+    }
+
+    #[test]
+    fn the_sheet_meta_names_the_frames() {
+        let mut sheet = sheet_with(&[("Idle", 10), ("Slide", 5), ("Jump", 12), ("Dead", 10)]);
+        for index in 0..8 {
+            let cell = sheet.frames["Idle (1).png"].clone();
+            sheet.frames.insert(format!("run_{:04}.png", index), cell);
+        }
+        assert!(check_frame_counts(&sheet, &FrameNames::from_sheet(&sheet)).is_err());
+
+        sheet.meta = Some(
+            serde_json::from_str(
+                r#"{"frameNames": {"animations": {"Run": {"template": "run_{n:04}.png", "firstIndex": 0}}}}"#,
+            )
+            .unwrap(),
+        );
+        let frame_names = FrameNames::from_sheet(&sheet);
+
+        assert_eq!(frame_names.format("Run", 7), "run_0007.png");
+        assert_eq!(frame_names.format("Idle", 0), "Idle (1).png");
+        assert!(check_frame_counts(&sheet, &frame_names).is_ok());
+    }
+
+    #[test]
+    fn an_extra_cell_in_the_sheet_is_reported() {
+        let sheet = sheet_with(&[
+            ("Idle", 10),
+            ("Run", 9),
+            ("Slide", 5),
+            ("Jump", 12),
+            ("Dead", 10),
+        ]);
+
+        let err = check_frame_counts(&sheet, &FrameNames::default()).unwrap_err();
+
+        assert!(err.to_string().contains("'Run' has 9 cells"));
+    }
+
+    fn with_durations(mut sheet: Sheet, animation: &str, durations: &[u32]) -> Sheet {
+        for (index, duration) in durations.iter().enumerate() {
+            let name = FrameNameFormat::default().format(animation, index as u16);
+            sheet.frames.get_mut(&name).unwrap().duration_ms = Some(*duration);
+        }
+        sheet
+    }
+
+    #[test]
+    fn cell_durations_pick_the_frame() {
+        let sheet = with_durations(
+            sheet_with(&[
+                ("Idle", 10),
+                ("Run", 8),
+                ("Slide", 5),
+                ("Jump", 12),
+                ("Dead", 10),
+            ]),
+            "Run",
+            &[100, 50, 50, 50, 50, 50, 20, 20],
+        );
+
+        let timings = check_frame_counts(&sheet, &FrameNames::default()).unwrap();
+
+        let run = &timings["Run"];
+        assert_eq!(run.cells, 8);
+        assert_eq!(run.index(0), 0);
+        assert_eq!(run.index(5), 0);
+        assert_eq!(run.index(6), 1);
+        assert_eq!(run.index(23), 7);
+        assert_eq!(timings["Idle"].index(5), 1);
+    }
+
+    #[test]
+    fn durations_longer_than_the_state_loop_are_reported() {
+        let sheet = with_durations(
+            sheet_with(&[
+                ("Idle", 10),
+                ("Run", 8),
+                ("Slide", 5),
+                ("Jump", 12),
+                ("Dead", 10),
+            ]),
+            "Run",
+            &[100; 8],
+        );
+
+        let err = check_frame_counts(&sheet, &FrameNames::default()).unwrap_err();
+
+        assert!(err.to_string().contains("'Run' lasts 800 ms"));
+    }
+
+    #[test]
+    fn jump_while_falling_is_dropped() {
+        let falling: RedHatBoyStateMachine = RedHatBoyState::new().run().knock_out().into();
+
+        let result = falling.try_transition(Event::Jump);
+
+        assert_eq!(
+            result.err(),
+            Some(DroppedEvent {
+                state: RedHatBoyStateTag::Falling,
+                event: Event::Jump,
+            })
+        );
+    }
+
+    #[test]
+    fn each_dropped_pair_is_logged_once_per_stay_in_a_state() {
+        let mut boy = loaded_walk(1).boy;
+        while boy.in_intro() {
+            boy.update();
+        }
+        boy.run_right();
+        let dropped = |event| DroppedEvent {
+            state: RedHatBoyStateTag::Running,
+            event,
+        };
+
+        assert!(boy.first_drop_in_state(dropped(Event::Run)));
+        assert!(!boy.first_drop_in_state(dropped(Event::Run)));
+        // 間に別の組が入っても、出力済みの組は出力しない
+        assert!(boy.first_drop_in_state(dropped(Event::StandUp)));
+        assert!(!boy.first_drop_in_state(dropped(Event::Run)));
+        assert!(boy.first_drop_in_state(dropped(Event::Land(400, 0))));
+        assert!(!boy.first_drop_in_state(dropped(Event::Land(380, 2))));
+
+        // 状態が変わったら、また出力する
+        boy.jump();
+        assert!(boy.first_drop_in_state(dropped(Event::Run)));
+    }
+
+    #[test]
+    fn jumping_cancels_a_slide_from_the_floor() {
+        let mut state: RedHatBoyStateMachine = RedHatBoyState::new().run().slide().into();
+        for _ in 0..5 {
+            state = state.try_transition(Event::Update).unwrap();
+        }
+        assert_eq!(state.tag(), RedHatBoyStateTag::Sliding);
+
+        state = state.try_transition(Event::Jump).unwrap();
+
+        // スライドの低い絵と当たり判定を引き継がず、床から普通のジャンプと同じ速さで跳ぶ
+        let jump = RedHatBoyState::new().run().jump();
+        assert_eq!(state.tag(), RedHatBoyStateTag::Jumping);
+        assert_eq!(state.frame_name(), jump.frame_name());
+        assert_eq!(state.context().frame, 0);
+        assert_eq!(state.context().position.y, FLOOR);
+        assert_eq!(state.context().velocity.y, jump.context().velocity.y);
+    }
+
+    fn wall_jump_from_high_up() -> RedHatBoyStateMachine {
+        let mut context = *RedHatBoyState::new().run().context();
+        context.position.y = 100;
+        RedHatBoyStateMachine::new_in_state(RedHatBoyStateTag::Jumping, context)
+            .try_transition(Event::WallJump)
+            .unwrap()
+    }
+
+    #[test]
+    fn wall_jump_is_locked_out_for_a_while() {
+        let mut state = wall_jump_from_high_up();
+        let lockout = state.context().wall_jump_lockout;
+        assert!(lockout > 0);
+
+        for _ in 1..lockout {
+            state = state.try_transition(Event::Update).unwrap();
+            assert!(state.try_transition(Event::WallJump).is_err());
+        }
+        state = state.try_transition(Event::Update).unwrap();
+
+        assert_eq!(state.tag(), RedHatBoyStateTag::Jumping);
+        assert!(state.try_transition(Event::WallJump).is_ok());
+    }
+
+    #[test]
+    fn a_boy_started_high_in_a_jump_lands_on_the_floor() {
+        let mut context = *RedHatBoyState::new().run().context();
+        context.position.y = 100;
+        let mut boy = RedHatBoy::new_in_state(
+            serde_json::from_str(include_str!("../static/rhb_trimmed.json")).unwrap(),
+            JsValue::NULL.unchecked_into(),
+            RedHatBoyStateTag::Jumping,
+            context,
+        )
+        .unwrap();
+        assert_eq!(boy.pos_y(), 100);
+
+        for _ in 0..200 {
+            if boy.state_tag() != RedHatBoyStateTag::Jumping {
+                break;
+            }
+            boy.update();
+        }
+        assert_eq!(boy.state_tag(), RedHatBoyStateTag::Running);
+        assert_eq!(boy.pos_y(), FLOOR);
+    }
+
+    #[test]
+    fn a_locked_out_wall_jump_keeps_the_wall_contact() {
+        let mut boy = loaded_walk(1).boy;
+        boy.state_machine = wall_jump_from_high_up();
+        boy.hit_wall();
+        boy.jump();
+        assert_eq!(boy.wall_contact, 1);
+
+        while boy.state_machine.context().wall_jump_lockout > 0 {
+            boy.state_machine = boy.state_machine.try_transition(Event::Update).unwrap();
+        }
+        boy.jump();
+        assert_eq!(boy.wall_contact, 0);
+        assert!(boy.state_machine.context().wall_jump_lockout > 0);
+    }
+
+    #[test]
+    fn wall_jump_scrolls_the_other_way_until_the_lockout_ends() {
+        let mut state = wall_jump_from_high_up();
+        // 少年が左へ跳ね返るので、世界は右へ流れる
+        assert!(scroll_velocity(state.context().velocity.x, 0) > 0);
+        assert!(state.context().velocity.y < 0);
+
+        while state.context().wall_jump_lockout > 0 {
+            state = state.try_transition(Event::Update).unwrap();
+        }
+
+        assert!(scroll_velocity(state.context().velocity.x, 0) < 0);
+    }
+
+    #[test]
+    fn crouching_lasts_until_standing_up() {
+        let running = RedHatBoyState::new().run();
+        let mut state: RedHatBoyStateMachine = running.crouch().into();
+        assert!(state.context().velocity.x < running.context().velocity.x);
+
+        // 時間で終わるスライドと違い、何フレーム経ってもしゃがんだまま
+        for _ in 0..100 {
+            state = state.try_transition(Event::Update).unwrap();
+            assert_eq!(state.tag(), RedHatBoyStateTag::Crouching);
+        }
+        state = state.try_transition(Event::StandUp).unwrap();
+
+        assert_eq!(state.tag(), RedHatBoyStateTag::Running);
+        assert_eq!(state.context().velocity.x, running.context().velocity.x);
+    }
+
+    #[test]
+    fn crouching_holds_the_lowest_slide_frame() {
+        let sheet = sheet_with(&[
+            ("Idle", 10),
+            ("Run", 8),
+            ("Slide", 5),
+            ("Jump", 12),
+            ("Dead", 10),
+        ]);
+        let timings = check_frame_counts(&sheet, &FrameNames::default()).unwrap();
+        let mut state: RedHatBoyStateMachine = RedHatBoyState::new().run().crouch().into();
+
+        for _ in 0..10 {
+            let index = timings[state.frame_name()].index(state.context().frame);
+            assert_eq!(
+                FrameNameFormat::default().format(state.frame_name(), index),
+                "Slide (3).png"
+            );
+            state = state.try_transition(Event::Update).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_hazard_knocks_out_a_crouching_boy() {
+        let state: RedHatBoyStateMachine = RedHatBoyState::new().run().crouch().into();
+
+        let state = state.try_transition(Event::KnockOut).unwrap();
+
+        assert_eq!(state.tag(), RedHatBoyStateTag::Falling);
+    }
+
+    #[test]
+    fn intro_runs_in_from_off_screen_to_the_centre() {
+        let mut intro = RedHatBoyState::intro();
+        assert!(intro.context().position.x + PLAYER_WIDTH <= 0);
+
+        let mut frames = 0;
+        let idle = loop {
+            frames += 1;
+            match intro.update() {
+                IntroEndState::Intro(next) => intro = next,
+                IntroEndState::Complete(idle) => break idle,
+            }
+        };
+
+        assert_eq!(frames, INTRO_FRAMES);
+        let centre = idle.context().position.x + PLAYER_WIDTH / 2;
+        assert_eq!(centre, WIDTH / 2);
+    }
+
+    #[test]
+    fn jumping_from_a_height_lands_running() {
+        let mut context = *RedHatBoyState::new().context();
+        context.position.y = 300;
+        let mut state = RedHatBoyStateMachine::new_in_state(RedHatBoyStateTag::Jumping, context);
+
+        while state.tag() == RedHatBoyStateTag::Jumping {
+            state = state.try_transition(Event::Update).unwrap();
+        }
+
+        assert_eq!(state.tag(), RedHatBoyStateTag::Running);
+    }
+
+    #[test]
+    fn grounded_from_the_exact_frame_of_landing() {
+        let mut state: RedHatBoyStateMachine = RedHatBoyState::new().run().into();
+        assert!(state.is_grounded());
+        state = state.try_transition(Event::Jump).unwrap();
+        state = state.try_transition(Event::Update).unwrap();
+        while state.tag() == RedHatBoyStateTag::Jumping {
+            assert!(!state.is_grounded());
+            state = state.try_transition(Event::Update).unwrap();
+        }
+        assert!(state.is_grounded());
+        // 床の上を走り続けても重力の速さは貯まるが、立っていることに変わりはない
+        for _ in 0..30 {
+            state = state.try_transition(Event::Update).unwrap();
+            assert!(state.is_grounded());
+        }
+    }
+
+    #[test]
+    fn resting_on_a_platform_counts_as_grounded() {
+        let platform_top = 400;
+        let mut state: RedHatBoyStateMachine = RedHatBoyState::new().run().into();
+        state = state.try_transition(Event::Jump).unwrap();
+        while state.context().velocity.y <= 0 {
+            state = state.try_transition(Event::Update).unwrap();
+        }
+        assert!(!state.is_grounded());
+        state = state.try_transition(Event::Land(platform_top, 0)).unwrap();
+        assert!(state.context().position.y < FLOOR);
+        assert!(state.is_grounded());
+        // 足場が無くなって落ち始めたら宙に浮いている
+        state = state.try_transition(Event::Update).unwrap();
+        assert!(!state.is_grounded());
+    }
+
+    // 足場に立ってから、足場が無くなったものとして落ち始める
+    fn run_off_a_ledge(input_windows: InputWindows) -> RedHatBoyStateMachine {
+        let mut state: RedHatBoyStateMachine = RedHatBoyState::new().run().land_on(400, 0).into();
+        state.context_mut().input_windows = input_windows;
+        state.try_transition(Event::Update).unwrap()
+    }
+
+    // 床へ落ちていく途中のジャンプで、着地の何フレーム前に押したか
+    fn jump_before_landing(input_windows: InputWindows, frames_early: u8) -> RedHatBoyStateTag {
+        let mut context = *RedHatBoyState::new().context();
+        context.position.y = FLOOR - 100;
+        context.velocity.y = 10;
+        context.input_windows = input_windows;
+        let mut state = RedHatBoyStateMachine::new_in_state(RedHatBoyStateTag::Jumping, context);
+        let mut landing_in = 0;
+        let mut probe = state;
+        while probe.tag() == RedHatBoyStateTag::Jumping && probe.context().velocity.y > 0 {
+            probe = probe.try_transition(Event::Update).unwrap();
+            landing_in += 1;
+        }
+        for frame in 0..landing_in {
+            if landing_in - frame == frames_early {
+                // 猶予が無ければ空中のジャンプは捨てられる
+                state = state.try_transition(Event::Jump).unwrap_or(state);
+            }
+            state = state.try_transition(Event::Update).unwrap();
+        }
+        state.tag()
+    }
+
+    #[test]
+    fn zero_input_windows_are_strict() {
+        let strict = InputWindows::new(0, 0);
+        let mut context = *RedHatBoyState::new().context();
+        context.position.y = 300;
+        context.input_windows = strict;
+        let jumping = RedHatBoyStateMachine::new_in_state(RedHatBoyStateTag::Jumping, context);
+        assert!(jumping.try_transition(Event::Jump).is_err());
+        assert_eq!(jump_before_landing(strict, 1), RedHatBoyStateTag::Running);
+
+        let falling_off = run_off_a_ledge(strict);
+        assert_eq!(falling_off.tag(), RedHatBoyStateTag::Running);
+        assert!(falling_off.try_transition(Event::Jump).is_err());
+    }
+
+    #[test]
+    fn jump_pressed_just_before_landing_is_buffered() {
+        let windows = InputWindows::new(3, 0);
+        assert_eq!(jump_before_landing(windows, 1), RedHatBoyStateTag::Jumping);
+        assert_eq!(jump_before_landing(windows, 3), RedHatBoyStateTag::Jumping);
+        assert_eq!(jump_before_landing(windows, 4), RedHatBoyStateTag::Running);
+    }
+
+    // 足場から走り落ちて床に着く何フレーム前にジャンプを押したか
+    fn jump_before_landing_off_a_ledge(
+        input_windows: InputWindows,
+        frames_early: u8,
+    ) -> RedHatBoyStateTag {
+        let mut state = run_off_a_ledge(input_windows);
+        let mut landing_in = 0;
+        let mut probe = state;
+        while probe.context().position.y < FLOOR {
+            probe = probe.try_transition(Event::Update).unwrap();
+            landing_in += 1;
+        }
+        for frame in 0..landing_in {
+            if landing_in - frame == frames_early {
+                assert!(!state.context().can_jump());
+                state = state.try_transition(Event::Jump).unwrap_or(state);
+            }
+            state = state.try_transition(Event::Update).unwrap();
+        }
+        state.tag()
+    }
+
+    #[test]
+    fn jump_pressed_while_falling_off_a_ledge_is_buffered() {
+        let windows = InputWindows::new(3, 0);
+        assert_eq!(
+            jump_before_landing_off_a_ledge(windows, 1),
+            RedHatBoyStateTag::Jumping
+        );
+        assert_eq!(
+            jump_before_landing_off_a_ledge(windows, 3),
+            RedHatBoyStateTag::Jumping
+        );
+        assert_eq!(
+            jump_before_landing_off_a_ledge(windows, 4),
+            RedHatBoyStateTag::Running
+        );
+        assert_eq!(
+            jump_before_landing_off_a_ledge(InputWindows::new(0, 0), 1),
+            RedHatBoyStateTag::Running
+        );
+    }
+
+    #[test]
+    fn can_still_jump_shortly_after_running_off_a_ledge() {
+        let windows = InputWindows::new(0, 3);
+        let mut state = run_off_a_ledge(windows);
+        assert!(state.try_transition(Event::Jump).is_ok());
+        state = state.try_transition(Event::Update).unwrap();
+        state = state.try_transition(Event::Update).unwrap();
+        assert!(state.try_transition(Event::Jump).is_ok());
+        state = state.try_transition(Event::Update).unwrap();
+        assert!(state.try_transition(Event::Jump).is_err());
+    }
+
+    #[test]
+    fn fast_fall_lands_on_the_floor() {
+        let mut context = *RedHatBoyState::new().context();
+        context.position.y = 100;
+        let mut state = RedHatBoyStateMachine::new_in_state(RedHatBoyStateTag::Jumping, context)
+            .try_transition(Event::FastFall)
+            .unwrap();
+
+        while state.tag() == RedHatBoyStateTag::Jumping {
+            assert!(state.context().velocity.y <= 30);
+            state = state.try_transition(Event::Update).unwrap();
+        }
+
+        assert_eq!(state.tag(), RedHatBoyStateTag::Running);
+        assert_eq!(
+            state.context().position.y,
+            RedHatBoyState::new().context().position.y
+        );
+        assert!(!state.context().fast_falling);
+    }
+
+    #[test]
+    fn standing_on_a_descending_platform_stays_on_it() {
+        let player_height = HEIGHT - RedHatBoyState::new().context().position.y;
+        let mut top = 300;
+        let mut state: RedHatBoyStateMachine = RedHatBoyState::new().run().land_on(top, 2).into();
+
+        for _ in 0..30 {
+            top += 2;
+            state = state.try_transition(Event::Update).unwrap();
+            let feet = state.context().position.y + player_height;
+            assert!(feet >= top, "fell behind the platform: {} < {}", feet, top);
+            state = state.try_transition(Event::Land(top, 2)).unwrap();
+        }
+
+        assert_eq!(state.context().position.y + player_height, top);
+    }
+
+    #[test]
+    fn a_boy_landing_on_a_sinking_platform_rides_it_down() {
+        let sprites = ["13.png", "14.png", "15.png"];
+        let sheet = serde_json::from_str(include_str!("../static/rhb_trimmed.json")).unwrap();
+        let mut context = *RedHatBoyState::new().context();
+        context.position.y = 150;
+        context.velocity.y = 5;
+        let mut boy = RedHatBoy::new_in_state(
+            sheet,
+            JsValue::NULL.unchecked_into(),
+            RedHatBoyStateTag::Jumping,
+            context,
+        )
+        .unwrap();
+        let position = Point {
+            x: boy.bounding_box().x() - 50,
+            y: 300,
+        };
+        let mut platform = segments::floating_platform(platform_sheet(), position, &sprites, None);
+
+        let mut riding = None;
+        for _ in 0..60 {
+            platform.move_vertically(2);
+            boy.update();
+            platform.check_intersection(&mut boy);
+            let gap = platform.bounding_boxes()[0].y() - boy.state_machine.context().position.y;
+            match riding {
+                None if boy.state_machine.tag() == RedHatBoyStateTag::Running => riding = Some(gap),
+                None => {}
+                // 走る絵によって当たり判定の下端が変わるので、1px浮くフレームはある
+                Some(riding) => {
+                    assert_eq!(boy.state_machine.tag(), RedHatBoyStateTag::Running);
+                    assert!(
+                        (gap - riding).abs() <= 1,
+                        "the boy drifted off the platform: {} vs {}",
+                        gap,
+                        riding
+                    );
+                }
+            }
+        }
+        assert!(riding.is_some(), "the boy never landed");
+    }
+
+    #[test]
+    fn jumping_off_an_ascending_platform_gets_a_boost() {
+        let boosted: RedHatBoyStateMachine =
+            RedHatBoyState::new().run().land_on(300, -3).jump().into();
+        let normal: RedHatBoyStateMachine = RedHatBoyState::new().run().jump().into();
+
+        assert_eq!(
+            boosted.context().velocity.y,
+            normal.context().velocity.y - 3
+        );
     }
 
-    fn run_right(&mut self) {
-        self.state_machine = self.state_machine.transition(Event::Run);
-    }
+    #[test]
+    fn name_entry_ignores_non_printable_keys() {
+        let mut entry = NameEntry::default();
+        for key in ["A", "Shift", "ArrowLeft", "b", "Backspace", "c", "\u{7}"] {
+            assert!(!entry.handle_key(key));
+        }
 
-    fn slide(&mut self) {
-        self.state_machine = self.state_machine.transition(Event::Slide);
+        assert_eq!(entry.name(), "Ac");
+        assert!(entry.handle_key("Enter"));
     }
 
-    fn jump(&mut self) {
-        self.state_machine = self.state_machine.transition(Event::Jump);
+    #[test]
+    fn name_entry_stops_at_the_max_length() {
+        let mut entry = NameEntry::default();
+        for _ in 0..MAX_NAME_LENGTH + 5 {
+            entry.handle_key("x");
+        }
+
+        assert_eq!(entry.name().len(), MAX_NAME_LENGTH);
+        assert_eq!(NameEntry::default().name(), PLAYER_NAME);
     }
 
-    fn knock_out(&mut self) {
-        self.state_machine = self.state_machine.transition(Event::KnockOut);
+    #[test]
+    fn longer_falls_knock_out_exactly_once() {
+        let mut state: RedHatBoyStateMachine = RedHatBoyState::new().run().into();
+        state.context_mut().falling_frames = 60;
+        state = state.try_transition(Event::KnockOut).unwrap();
+        let mut knocked_out = 0;
+        for _ in 0..100 {
+            let before = state.tag();
+            state = state.try_transition(Event::Update).unwrap();
+            if before == RedHatBoyStateTag::Falling && state.tag() == RedHatBoyStateTag::KnockedOut
+            {
+                knocked_out += 1;
+                assert_eq!(state.context().frame, 59);
+            }
+        }
+        assert_eq!(knocked_out, 1);
+        assert_eq!(state.tag(), RedHatBoyStateTag::KnockedOut);
+        // 生き返っても設定は残る
+        state = state.try_transition(Event::Respawn).unwrap();
+        assert_eq!(state.context().falling_frames, 60);
     }
 
-    fn land_on(&mut self, position: i16) {
-        self.state_machine = self.state_machine.transition(Event::Land(position))
+    #[test]
+    fn falling_slows_to_a_stop_without_turning_around() {
+        for speed in [RUNNING_SPEED, 7, -5] {
+            let mut state: RedHatBoyStateMachine = RedHatBoyState::new().run().into();
+            state.context_mut().falling_frames = 120;
+            state = state.try_transition(Event::KnockOut).unwrap();
+            state.context_mut().velocity.x = speed;
+            let mut travelled = 0;
+            let mut previous = speed;
+            for _ in 0..(DECELERATION_INTERVAL as i16 * speed.abs() * 3) {
+                state = state.try_transition(Event::Update).unwrap();
+                assert_eq!(state.tag(), RedHatBoyStateTag::Falling);
+                let velocity = state.context().velocity.x;
+                assert!(velocity.signum() == speed.signum() || velocity == 0);
+                assert!(velocity.abs() <= previous.abs());
+                travelled += velocity.abs();
+                previous = velocity;
+            }
+            assert_eq!(previous, 0);
+            // 1ずつ落とすので、止まるまでに進む距離は速さの2乗程度で収まる
+            let speed = speed.abs();
+            assert!(travelled <= DECELERATION_INTERVAL as i16 * speed * (speed + 1) / 2);
+        }
     }
 
-    fn walk_speed(&self) -> i16 {
-        self.state_machine.context().velocity.x
+    #[test]
+    fn knocked_out_shows_the_last_dead_frame() {
+        use KnockoutAnimation::*;
+        use RedHatBoyStateTag::*;
+        assert_eq!(knockout_index(Falling, 15, 10, Hold), 9);
+        assert_eq!(knockout_index(Falling, 15, 10, Loop), 5);
+        assert_eq!(knockout_index(Falling, 4, 10, Hold), 4);
+        assert_eq!(knockout_index(KnockedOut, 19, 10, Loop), 9);
+        assert_eq!(knockout_index(KnockedOut, 0, 10, Hold), 9);
     }
-}
 
-use red_hat_boy_states::*;
+    #[test]
+    fn opening_obstacles_keep_a_safe_lead_from_the_spawn() {
+        let lead = SpawnConfig::for_width(WIDTH).opening_lead;
+        let mut obstacles: Vec<Box<dyn Obstacle>> =
+            vec![Box::new(Span(150, 160)), Box::new(Span(0, 10))];
+        keep_opening_clear(&mut obstacles, lead);
+        let first = obstacles.iter().map(|obstacle| obstacle.left()).min();
+        assert_eq!(first, Some(red_hat_boy_states::STARTING_POINT + lead));
+        // 並びの間隔は変えない
+        assert_eq!(obstacles[0].left() - obstacles[1].left(), 150);
 
-mod red_hat_boy_states {
-    use super::HEIGHT;
-    use crate::engine::Point;
-    // 地面の高さ
-    const FLOOR: i16 = 479;
-    const PLAYER_HEIGHT: i16 = HEIGHT - FLOOR;
-    const STARTING_POINT: i16 = -20;
-    // rhb.jsonにおけるフレームの名前
-    const IDLE_FRAME_NAME: &str = "Idle";
-    const RUN_FRAME_NAME: &str = "Run";
-    const SLIDING_FRAME_NAME: &str = "Slide";
-    const JUMPING_FRAME_NAME: &str = "Jump";
-    const FALLING_FRAME_NAME: &str = "Dead";
-    // rhb.jsonにおけるフレームの枚数*3
-    const IDLE_FRAMES: u8 = 30;
-    const RUNNING_FRAMES: u8 = 24;
-    const SLIDING_FRAMES: u8 = 15;
-    const JUMPING_FRAMES: u8 = 36;
-    const FALLING_FRAMES: u8 = 30;
+        // すでに十分遠ければ動かさない
+        let far_x = red_hat_boy_states::STARTING_POINT + lead + 10;
+        let mut far: Vec<Box<dyn Obstacle>> = vec![Box::new(Span(far_x, far_x + 10))];
+        keep_opening_clear(&mut far, lead);
+        assert_eq!(far[0].left(), far_x);
+    }
 
-    const RUNNING_SPEED: i16 = 3;
-    const JUMP_SPEED: i16 = -20;
-    const GRAVITY: i16 = 1;
-    const TERMINAL_VELOCITY: i16 = 20;
+    #[test]
+    fn segments_must_name_a_theme_the_level_defines() {
+        let level: Level = serde_json::from_str(
+            r#"{
+                "background": "BG.png",
+                "themes": { "night": { "background": "Night.png", "music": "night.mp3" } },
+                "segments": [
+                    { "type": "two_stones", "offset": 0 },
+                    { "type": "stone_and_platform", "offset": 100, "theme": "night" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(check_level(&level).is_ok());
+        assert_eq!(level.segments[0].theme, None);
 
-    #[derive(Copy, Clone)]
-    pub struct RedHatBoyContext {
-        pub frame: u8,
-        pub position: Point,
-        pub velocity: Point,
+        let missing: Level = serde_json::from_str(
+            r#"{ "background": "BG.png", "segments": [{ "type": "two_stones", "offset": 0, "theme": "cave" }] }"#,
+        )
+        .unwrap();
+        assert!(check_level(&missing).is_err());
     }
 
-    impl RedHatBoyContext {
-        pub fn update(mut self, frame_count: u8) -> Self {
-            if self.velocity.y < TERMINAL_VELOCITY {
-                self.velocity.y += GRAVITY;
-            }
-            self.frame = (self.frame + 1) % frame_count;
-            // self.position += self.velocity;
-            self.position.y += self.velocity.y;
-            self.position.y = self.position.y.min(FLOOR);
-            self
-        }
+    #[test]
+    fn theme_fade_follows_the_scrolled_distance() {
+        assert_eq!(fade_progress(0, THEME_FADE_DISTANCE), 0.0);
+        assert_eq!(
+            fade_progress(THEME_FADE_DISTANCE / 2, THEME_FADE_DISTANCE),
+            0.5
+        );
+        assert_eq!(
+            fade_progress(THEME_FADE_DISTANCE * 2, THEME_FADE_DISTANCE),
+            1.0
+        );
+        assert_eq!(fade_progress(-10, THEME_FADE_DISTANCE), 0.0);
+    }
 
-        fn reset_frame(mut self) -> Self {
-            self.frame = 0;
-            self
+    #[test]
+    fn small_vertical_movements_stay_inside_the_dead_zone() {
+        let mut camera = Camera::default();
+        // 床からのジャンプの頂点まででは縦に動かない
+        for lift in [-5, -100, -210, 0] {
+            camera.follow(0, lift, 0, None);
+            assert_eq!(camera.position().y, 0);
         }
-
-        fn run_right(mut self) -> Self {
-            self.velocity.x += RUNNING_SPEED;
-            self
+        // 遊びを出た分だけ追いかける
+        let half = CameraConfig::default().dead_zone_height / 2;
+        for _ in 0..500 {
+            camera.follow(0, -half - 100, 0, None);
         }
+        assert_eq!(camera.position().y, -100);
+        assert_eq!(dead_zone_follow(0.0, 30.0, 40), 10.0);
+        assert_eq!(dead_zone_follow(0.0, -30.0, 40), -10.0);
+        assert_eq!(dead_zone_follow(5.0, 20.0, 40), 5.0);
+    }
 
-        fn set_vertical_velocity(mut self, y: i16) -> Self {
-            self.velocity.y = y;
-            self
+    #[test]
+    fn horizontal_following_is_smooth_while_running() {
+        let mut camera = Camera::default();
+        camera.set_config(CameraConfig {
+            dead_zone_width: 10,
+            ..CameraConfig::default()
+        });
+        // 遊びの半分だけ手前で止まる
+        let target = 100 + CAMERA_LOOK_AHEAD - 5;
+        let mut previous = camera.x;
+        for _ in 0..200 {
+            camera.follow(100, 0, RUNNING_SPEED, None);
+            // 目標へ向かって少しずつ、行き過ぎずに近づく
+            assert!(camera.x >= previous);
+            assert!(camera.x - previous <= target as f32 * CAMERA_SMOOTHING);
+            previous = camera.x;
         }
+        assert_eq!(camera.position().x, target);
+    }
 
-        fn stop(mut self) -> Self {
-            self.velocity.x = 0;
-            self.velocity.y = GRAVITY;
-            self
+    #[test]
+    fn camera_swings_its_look_ahead_when_turning() {
+        let mut camera = Camera::default();
+        for _ in 0..100 {
+            camera.follow(0, 0, RUNNING_SPEED, None);
         }
+        assert_eq!(camera.look_ahead.value(), CAMERA_LOOK_AHEAD as f32);
 
-        fn set_on(mut self, position: i16) -> Self {
-            let position = position - PLAYER_HEIGHT;
-            self.position.y = position;
-            self
-        }
-    }
+        camera.follow(0, 0, -RUNNING_SPEED, None);
+        let swinging = camera.look_ahead.value();
+        assert!(swinging < CAMERA_LOOK_AHEAD as f32 && swinging > -CAMERA_LOOK_AHEAD as f32);
 
-    #[derive(Copy, Clone)]
-    pub struct RedHatBoyState<S> {
-        pub context: RedHatBoyContext,
-        _state: S,
-    }
-    impl<S> RedHatBoyState<S> {
-        pub fn context(&self) -> &RedHatBoyContext {
-            &self.context
+        for _ in 1..CAMERA_TURN_FRAMES as usize {
+            camera.follow(0, 0, -RUNNING_SPEED, None);
         }
+        assert_eq!(camera.look_ahead.value(), -CAMERA_LOOK_AHEAD as f32);
     }
 
-    #[derive(Copy, Clone)]
-    pub struct Idle;
-    impl RedHatBoyState<Idle> {
-        pub fn new() -> Self {
-            RedHatBoyState {
-                context: RedHatBoyContext {
-                    frame: 0,
-                    position: Point {
-                        x: STARTING_POINT,
-                        y: FLOOR,
-                    },
-                    velocity: Point { x: 0, y: 0 },
-                },
-                _state: Idle {},
+    #[test]
+    fn camera_dead_zone_parses_from_the_url() {
+        assert_eq!(
+            "40, 300".parse::<CameraConfig>().unwrap(),
+            CameraConfig {
+                dead_zone_width: 40,
+                dead_zone_height: 300,
             }
+        );
+        assert!("40".parse::<CameraConfig>().is_err());
+        assert!("-1,300".parse::<CameraConfig>().is_err());
+        assert!("wide,tall".parse::<CameraConfig>().is_err());
+    }
+
+    #[test]
+    fn the_camera_never_passes_the_finish_line() {
+        let mut game = WalkTheDog::Loaded(Box::new(loaded_walk(9)));
+        let mut keystate = KeyState::new();
+        keystate.press("ArrowRight");
+        for tick in 0..INTRO_FRAMES as u64 {
+            keystate.start_update();
+            game.update(&keystate, tick);
+        }
+        if let WalkTheDog::Loaded(walk) = &mut game {
+            walk.obstacles.clear();
+            walk.finish_line = Some(FinishLine { x: 700 });
         }
 
-        pub fn run(self) -> RedHatBoyState<Running> {
-            RedHatBoyState {
-                context: self.context.reset_frame().run_right(),
-                _state: Running {},
+        let mut tick = INTRO_FRAMES as u64;
+        loop {
+            keystate.start_update();
+            game.update(&keystate, tick);
+            let walk = match &game {
+                WalkTheDog::Loaded(walk) => walk,
+                WalkTheDog::Loading { .. } => unreachable!(),
+            };
+            if walk.level_complete.is_some() {
+                break;
             }
+            let end = walk.camera_level_end().unwrap();
+            let camera = walk.camera.position().x;
+            // スタート位置から測った線の位置と、画面上の線の位置は同じだけ離れている
+            assert_eq!(
+                end + red_hat_boy_states::STARTING_POINT,
+                walk.finish_line.as_ref().unwrap().right()
+            );
+            assert!(
+                camera <= end,
+                "tick {}: camera {} is past {}",
+                tick,
+                camera,
+                end
+            );
+            assert!(tick < 1000, "the boy never reached the finish line");
+            tick += 1;
         }
+        let walk = match &game {
+            WalkTheDog::Loaded(walk) => walk,
+            WalkTheDog::Loading { .. } => unreachable!(),
+        };
+        // ゴールした時にも、カメラはゴールの線より先を映していない
+        let finish = walk.finish_line.as_ref().unwrap().right();
+        assert!(walk.camera.position().x + red_hat_boy_states::STARTING_POINT <= finish);
+    }
 
-        pub fn frame_name(&self) -> &str {
-            IDLE_FRAME_NAME
+    #[test]
+    fn camera_stays_inside_the_level() {
+        let mut camera = Camera::default();
+        for _ in 0..200 {
+            camera.follow(-300, 50, -RUNNING_SPEED, None);
         }
-
-        pub fn update(mut self) -> Self {
-            self.context = self.context.update(IDLE_FRAMES);
-            self
+        assert_eq!(camera.position(), Point { x: 0, y: 0 });
+        for _ in 0..500 {
+            camera.follow(400, 0, RUNNING_SPEED, Some(250));
         }
+        assert_eq!(camera.position().x, 250);
+        // 終わりが始まりより手前なら始まりに合わせる
+        assert_eq!(clamp_to_level(30.0, 0.0, Some(-50)), (0.0, 0.0));
     }
 
-    #[derive(Copy, Clone)]
-    pub struct Running;
-    impl RedHatBoyState<Running> {
-        pub fn frame_name(&self) -> &str {
-            RUN_FRAME_NAME
+    #[test]
+    fn crumbling_starts_on_landing_and_ends_after_the_countdown() {
+        let mut crumble = Crumble::Solid;
+        for _ in 0..100 {
+            crumble = crumble.update(false);
         }
+        assert_eq!(crumble, Crumble::Solid);
 
-        pub fn update(mut self) -> Self {
-            self.context = self.context.update(RUNNING_FRAMES);
-            self
+        crumble = crumble.update(true);
+        assert_eq!(crumble, Crumble::Crumbling(CRUMBLE_FRAMES));
+        let mut frames = 1;
+        while crumble != Crumble::Gone {
+            // 乗り続けても数え直さない
+            crumble = crumble.update(true);
+            assert!(crumble.alpha() < 1.0);
+            assert!(crumble.shake().abs() <= CRUMBLE_SHAKE);
+            frames += 1;
         }
+        assert_eq!(frames, CRUMBLE_FRAMES as u32 + 1);
+        // 崩れた後に着地しても戻らない
+        assert_eq!(crumble.update(true), Crumble::Gone);
+        assert_eq!(crumble.shake(), 0);
+    }
 
-        pub fn slide(self) -> RedHatBoyState<Sliding> {
-            RedHatBoyState {
-                context: self.context.reset_frame(),
-                _state: Sliding {},
-            }
-        }
+    #[test]
+    fn skipping_moves_in_steps_that_add_up_to_the_distance() {
+        let steps: Vec<i16> = skip_steps(120, 50).collect();
+        assert_eq!(steps, vec![50, 50, 20]);
+        assert!(skip_steps(SKIP_DISTANCE, SKIP_STEP).all(|step| step <= SKIP_STEP));
+        assert_eq!(
+            skip_steps(SKIP_DISTANCE, SKIP_STEP).sum::<i16>(),
+            SKIP_DISTANCE
+        );
+        assert_eq!(skip_steps(-10, 50).count(), 0);
+    }
 
-        pub fn jump(self) -> RedHatBoyState<Jumping> {
-            RedHatBoyState {
-                context: self.context.set_vertical_velocity(JUMP_SPEED).reset_frame(),
-                _state: Jumping {},
-            }
-        }
+    #[test]
+    fn skipping_ahead_keeps_segments_apart_and_the_timeline_at_the_last_one() {
+        let snapshots = |walk: &Walk| -> Vec<Option<ObstacleSnapshot>> {
+            walk.obstacles
+                .iter()
+                .map(|obstacle| obstacle.snapshot())
+                .collect()
+        };
+        let mut skipped = loaded_walk(21);
+        skipped.skip_forward(SKIP_DISTANCE);
 
-        pub fn knock_out(self) -> RedHatBoyState<Falling> {
-            RedHatBoyState {
-                context: self.context.reset_frame().stop(),
-                _state: Falling {},
+        // 1歩ずつ飛ばしても同じ場面になるので、1歩ごとに新しいセグメントの位置を確かめる
+        let mut stepped = loaded_walk(21);
+        let buffer = stepped.spawn_config.obstacle_buffer;
+        let mut spawned = 0;
+        for _ in 0..SKIP_DISTANCE / SKIP_STEP {
+            let edge = stepped.timeline - SKIP_STEP;
+            stepped.skip_forward(SKIP_STEP);
+            stepped.obstacles.iter().for_each(|obstacle| {
+                assert!(
+                    obstacle.right() <= edge || obstacle.left() >= edge + buffer,
+                    "obstacle {}..{} overlaps the segment ending at {}",
+                    obstacle.left(),
+                    obstacle.right(),
+                    edge
+                );
+            });
+            if stepped.timeline > edge {
+                spawned += 1;
             }
         }
 
-        pub fn land_on(self, position: i16) -> RedHatBoyState<Running> {
-            RedHatBoyState {
-                context: self.context.set_on(position as i16),
-                _state: Running {},
-            }
-        }
+        assert!(spawned > 1, "only {} segments were spawned", spawned);
+        assert_eq!(snapshots(&skipped), snapshots(&stepped));
+        assert_eq!(skipped.timeline, stepped.timeline);
+        assert_eq!(skipped.timeline, rightmost(&skipped.obstacles));
+        assert_eq!(skipped.travelled, SKIP_DISTANCE as u64);
     }
 
-    #[derive(Copy, Clone)]
-    pub struct Sliding;
-    impl RedHatBoyState<Sliding> {
-        pub fn frame_name(&self) -> &str {
-            SLIDING_FRAME_NAME
+    #[test]
+    fn running_scrolls_obstacles_by_running_speed_each_tick() {
+        let mut game = WalkTheDog::Loaded(Box::new(loaded_walk(3)));
+        let mut keystate = KeyState::new();
+        keystate.press("ArrowRight");
+        for tick in 0..INTRO_FRAMES as u64 {
+            keystate.start_update();
+            game.update(&keystate, tick);
         }
-
-        pub fn update(mut self) -> SlidingEndState {
-            self.context = self.context.update(SLIDING_FRAMES);
-            if self.context.frame + 1 >= SLIDING_FRAMES {
-                SlidingEndState::Complete(self.stand())
-            } else {
-                SlidingEndState::Sliding(self)
-            }
+        if let WalkTheDog::Loaded(walk) = &mut game {
+            walk.obstacles = vec![Box::new(Span(500, 560))];
         }
 
-        pub fn stand(self) -> RedHatBoyState<Running> {
-            RedHatBoyState {
-                context: self.context.reset_frame(),
-                _state: Running,
-            }
+        for tick in 1..=10 {
+            keystate.start_update();
+            game.update(&keystate, tick as u64);
+            let walk = match &game {
+                WalkTheDog::Loaded(walk) => walk,
+                WalkTheDog::Loading { .. } => unreachable!(),
+            };
+            assert_eq!(walk.boy.state_tag(), RedHatBoyStateTag::Running);
+            assert_eq!(walk.obstacles[0].left(), 500 - RUNNING_SPEED * tick);
         }
+    }
 
-        pub fn land_on(self, position: i16) -> RedHatBoyState<Sliding> {
-            RedHatBoyState {
-                context: self.context.set_on(position),
-                _state: Sliding {},
-            }
-        }
+    #[test]
+    fn every_state_has_its_own_tag() {
+        use RedHatBoyStateTag::*;
+        let context = *RedHatBoyState::new().context();
+        let tags = [
+            Intro, Idle, Running, Sliding, Crouching, Jumping, Falling, KnockedOut,
+        ];
 
-        pub fn knock_out(self) -> RedHatBoyState<Falling> {
-            RedHatBoyState {
-                context: self.context.reset_frame().stop(),
-                _state: Falling {},
-            }
-        }
-    }
-    pub enum SlidingEndState {
-        Complete(RedHatBoyState<Running>),
-        Sliding(RedHatBoyState<Sliding>),
+        tags.iter().for_each(|&tag| {
+            assert_eq!(RedHatBoyStateMachine::new_in_state(tag, context).tag(), tag);
+        });
+        tags.iter().enumerate().for_each(|(i, a)| {
+            tags[i + 1..].iter().for_each(|b| assert_ne!(a, b));
+        });
     }
 
-    #[derive(Copy, Clone)]
-    pub struct Jumping;
-    impl RedHatBoyState<Jumping> {
-        pub fn frame_name(&self) -> &str {
-            JUMPING_FRAME_NAME
-        }
+    // Droppedはtry_transitionがErrを返す、つまり何もしないことを表す
+    enum Expected {
+        To(RedHatBoyStateTag),
+        Dropped,
+    }
 
-        pub fn update(mut self) -> JumpingEndState {
-            self.context = self.context.update(JUMPING_FRAMES);
-            if self.context.position.y >= FLOOR {
-                JumpingEndState::Landing(self.land_on(HEIGHT.into()))
-            } else {
-                JumpingEndState::Jumping(self)
-            }
-        }
+    #[test]
+    fn transition_table() {
+        use Expected::*;
+        use RedHatBoyStateTag::*;
+        // 空中で、登場の途中の位置。Updateで状態が変わらないようにしておく
+        let mut context = *RedHatBoyState::new().context();
+        context.position = Point { x: -200, y: 300 };
 
-        pub fn land_on(self, position: i16) -> RedHatBoyState<Running> {
-            RedHatBoyState {
-                context: self.context.reset_frame().set_on(position),
-                _state: Running {},
-            }
-        }
+        let table = [
+            (Intro, Event::Run, Dropped),
+            (Intro, Event::Slide, Dropped),
+            (Intro, Event::Crouch, Dropped),
+            (Intro, Event::StandUp, Dropped),
+            (Intro, Event::Jump, Dropped),
+            (Intro, Event::KnockOut, Dropped),
+            (Intro, Event::WallJump, Dropped),
+            (Intro, Event::FastFall, Dropped),
+            (Intro, Event::Respawn, Dropped),
+            (Intro, Event::Land(400, 0), Dropped),
+            (Intro, Event::Update, To(Intro)),
+            (Idle, Event::Run, To(Running)),
+            (Idle, Event::Slide, Dropped),
+            (Idle, Event::Crouch, Dropped),
+            (Idle, Event::StandUp, Dropped),
+            (Idle, Event::Jump, Dropped),
+            (Idle, Event::KnockOut, Dropped),
+            (Idle, Event::WallJump, Dropped),
+            (Idle, Event::FastFall, Dropped),
+            (Idle, Event::Respawn, Dropped),
+            (Idle, Event::Land(400, 0), Dropped),
+            (Idle, Event::Update, To(Idle)),
+            (Running, Event::Run, Dropped),
+            (Running, Event::Slide, To(Sliding)),
+            (Running, Event::Crouch, To(Crouching)),
+            (Running, Event::StandUp, Dropped),
+            (Running, Event::Jump, To(Jumping)),
+            (Running, Event::KnockOut, To(Falling)),
+            (Running, Event::WallJump, Dropped),
+            (Running, Event::FastFall, Dropped),
+            (Running, Event::Respawn, Dropped),
+            (Running, Event::Land(400, 0), To(Running)),
+            (Running, Event::Update, To(Running)),
+            (Sliding, Event::Run, Dropped),
+            (Sliding, Event::Slide, Dropped),
+            (Sliding, Event::Crouch, To(Crouching)),
+            (Sliding, Event::StandUp, Dropped),
+            (Sliding, Event::Jump, To(Jumping)),
+            (Sliding, Event::KnockOut, To(Falling)),
+            (Sliding, Event::WallJump, Dropped),
+            (Sliding, Event::FastFall, Dropped),
+            (Sliding, Event::Respawn, Dropped),
+            (Sliding, Event::Land(400, 0), To(Sliding)),
+            (Sliding, Event::Update, To(Sliding)),
+            (Crouching, Event::Run, Dropped),
+            (Crouching, Event::Slide, Dropped),
+            (Crouching, Event::Crouch, To(Crouching)),
+            (Crouching, Event::StandUp, To(Running)),
+            (Crouching, Event::Jump, Dropped),
+            (Crouching, Event::KnockOut, To(Falling)),
+            (Crouching, Event::WallJump, Dropped),
+            (Crouching, Event::FastFall, Dropped),
+            (Crouching, Event::Respawn, Dropped),
+            (Crouching, Event::Land(400, 0), To(Crouching)),
+            (Crouching, Event::Update, To(Crouching)),
+            (Jumping, Event::Run, Dropped),
+            (Jumping, Event::Slide, Dropped),
+            (Jumping, Event::Crouch, Dropped),
+            (Jumping, Event::StandUp, Dropped),
+            (Jumping, Event::Jump, To(Jumping)),
+            (Jumping, Event::KnockOut, To(Falling)),
+            (Jumping, Event::WallJump, To(Jumping)),
+            (Jumping, Event::FastFall, To(Jumping)),
+            (Jumping, Event::Respawn, Dropped),
+            (Jumping, Event::Land(400, 0), To(Running)),
+            (Jumping, Event::Update, To(Jumping)),
+            (Falling, Event::Run, Dropped),
+            (Falling, Event::Slide, Dropped),
+            (Falling, Event::Crouch, Dropped),
+            (Falling, Event::StandUp, Dropped),
+            (Falling, Event::Jump, Dropped),
+            (Falling, Event::KnockOut, Dropped),
+            (Falling, Event::WallJump, Dropped),
+            (Falling, Event::FastFall, Dropped),
+            (Falling, Event::Respawn, Dropped),
+            (Falling, Event::Land(400, 0), Dropped),
+            (Falling, Event::Update, To(Falling)),
+            (KnockedOut, Event::Run, Dropped),
+            (KnockedOut, Event::Slide, Dropped),
+            (KnockedOut, Event::Crouch, Dropped),
+            (KnockedOut, Event::StandUp, Dropped),
+            (KnockedOut, Event::Jump, Dropped),
+            (KnockedOut, Event::KnockOut, Dropped),
+            (KnockedOut, Event::WallJump, Dropped),
+            (KnockedOut, Event::FastFall, Dropped),
+            (KnockedOut, Event::Respawn, To(Running)),
+            (KnockedOut, Event::Land(400, 0), Dropped),
+            (KnockedOut, Event::Update, To(KnockedOut)),
+        ];
 
-        pub fn knock_out(self) -> RedHatBoyState<Falling> {
-            RedHatBoyState {
-                context: self.context.reset_frame().stop(),
-                _state: Falling {},
+        assert_eq!(table.len(), 8 * 11);
+        table.into_iter().for_each(|(from, event, expected)| {
+            let result = RedHatBoyStateMachine::new_in_state(from, context)
+                .try_transition(event)
+                .map(|state| state.tag());
+            match expected {
+                To(to) => assert_eq!(result.ok(), Some(to), "{:?} + {:?}", from, event),
+                Dropped => assert!(
+                    result.is_err(),
+                    "{:?} + {:?} should be dropped",
+                    from,
+                    event
+                ),
             }
-        }
-    }
-    pub enum JumpingEndState {
-        Landing(RedHatBoyState<Running>),
-        Jumping(RedHatBoyState<Jumping>),
+        });
     }
 
-    #[derive(Copy, Clone)]
-    pub struct Falling;
+    struct Span(i16, i16);
 
-    impl RedHatBoyState<Falling> {
-        pub fn frame_name(&self) -> &str {
-            FALLING_FRAME_NAME
+    impl Obstacle for Span {
+        fn check_intersection(&self, _boy: &mut RedHatBoy) -> Contact {
+            Contact::Miss
         }
-
-        pub fn update(mut self) -> FallingEndState {
-            self.context = self.context.update(FALLING_FRAMES);
-            if self.context.frame + 1 >= FALLING_FRAMES {
-                FallingEndState::Complete(self.knock_out())
-            } else {
-                FallingEndState::Falling(self)
-            }
+        fn draw(&self, _renderer: &Renderer) {}
+        fn move_horizontally(&mut self, x: i16) {
+            self.0 += x;
+            self.1 += x;
         }
-
-        pub fn knock_out(self) -> RedHatBoyState<KnockedOut> {
-            RedHatBoyState {
-                context: self.context,
-                _state: KnockedOut {},
-            }
+        fn left(&self) -> i16 {
+            self.0
+        }
+        fn right(&self) -> i16 {
+            self.1
         }
     }
 
-    #[derive(Copy, Clone)]
-    pub struct KnockedOut;
-    impl RedHatBoyState<KnockedOut> {
-        pub fn frame_name(&self) -> &str {
-            FALLING_FRAME_NAME
-        }
+    #[test]
+    fn the_trajectory_is_stroked_as_one_line() {
+        let walk = loaded_walk(2);
+        let arc = walk.boy.predicted_arc(TRAJECTORY_TICKS);
+        let recording = RecordingRenderer::new();
+        walk.draw_trajectory(recording.renderer());
+
+        let commands = recording.take_commands();
+        assert_eq!(commands.len(), arc.len() + 2);
+        assert_eq!(commands[0], DrawCommand::BeginPath);
+        assert_eq!(commands[1], DrawCommand::MoveTo(arc[0]));
+        assert!(commands[2..commands.len() - 1]
+            .iter()
+            .zip(&arc[1..])
+            .all(|(command, point)| *command == DrawCommand::LineTo(*point)));
+        assert_eq!(
+            commands[commands.len() - 1],
+            DrawCommand::Stroke {
+                color: "#00ff88".to_string(),
+                width: TRAJECTORY_LINE_WIDTH,
+            }
+        );
     }
 
-    pub enum FallingEndState {
-        Complete(RedHatBoyState<KnockedOut>),
-        Falling(RedHatBoyState<Falling>),
+    // 当たり判定を呼ばれた回数を数える。障害物の外からも読めるように共有する
+    struct Probe(Rc<RefCell<(Rect, u32)>>);
+
+    impl Obstacle for Probe {
+        fn check_intersection(&self, _boy: &mut RedHatBoy) -> Contact {
+            self.0.borrow_mut().1 += 1;
+            Contact::Miss
+        }
+        fn draw(&self, _renderer: &Renderer) {}
+        fn move_horizontally(&mut self, x: i16) {
+            let rect = &mut self.0.borrow_mut().0;
+            rect.set_x(rect.x() + x);
+        }
+        fn left(&self) -> i16 {
+            self.0.borrow().0.x()
+        }
+        fn right(&self) -> i16 {
+            self.0.borrow().0.right()
+        }
     }
-}
 
-#[wasm_bindgen(start)]
-pub fn main_js() -> Result<(), JsValue> {
-    console_error_panic_hook::set_once();
+    #[test]
+    fn every_obstacle_touching_the_boy_is_checked() {
+        let mut walk = loaded_walk(21);
+        let boy_box = walk.boy.bounding_box();
+        let probes: Vec<Rc<RefCell<(Rect, u32)>>> = (0..60)
+            .map(|i| {
+                let rect = Rect::new_from_x_y(i * 45 - 300, boy_box.y(), 30 + i % 4 * 40, 20);
+                Rc::new(RefCell::new((rect, 0)))
+            })
+            .collect();
+        walk.obstacles = probes
+            .iter()
+            .map(|probe| Box::new(Probe(probe.clone())) as Box<dyn Obstacle>)
+            .collect();
 
-    browser::spawn_local(async move {
-        let game = WalkTheDog::new();
+        let mut touched = 0;
+        for _ in 0..80 {
+            probes.iter().for_each(|probe| probe.borrow_mut().1 = 0);
+            walk.obstacles_moved = false;
+            walk.move_obstacles(-11);
 
-        GameLoop::start(game)
-            .await
-            .expect("Could not start game loop");
-    });
+            let boy_box = walk.boy.bounding_box();
+            let mut checked = 0;
+            probes.iter().for_each(|probe| {
+                let (rect, calls) = &*probe.borrow();
+                // 全部を調べた時に当たるものは、必ず調べられている
+                if rect.intersects(&boy_box) {
+                    assert_eq!(*calls, 1, "{:?} touches the boy", rect);
+                    touched += 1;
+                }
+                assert!(*calls <= 1);
+                checked += calls;
+            });
+            // 少年から遠い障害物は調べない
+            assert!(checked < 5, "checked {} obstacles", checked);
+        }
+        assert!(touched > 0);
+    }
 
-    Ok(())
+    #[test]
+    fn no_tint_at_the_start() {
+        assert_eq!(day_night_tint(0), None);
+        assert!(day_night_tint(DAY_NIGHT_CYCLE / 2).is_some());
+        assert_eq!(day_night_tint(DAY_NIGHT_CYCLE), None);
+    }
 }
@@ -0,0 +1,56 @@
+use anyhow::{anyhow, Result};
+use gloo_utils::format::JsValueSerdeExt;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::browser;
+
+// URLの?leaderboard=...でスコアを送る先を指定する
+const ENDPOINT_PARAM: &str = "leaderboard";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub score: u32,
+}
+
+// ゲームオーバー画面に出すランキングの状態
+#[derive(Clone, Debug, PartialEq)]
+pub enum LeaderboardStatus {
+    Loading,
+    Loaded(Vec<Entry>),
+    // 送信か取得に失敗した。ゲームはそのまま続けられる
+    Offline,
+}
+
+pub struct Leaderboard {
+    endpoint: String,
+}
+
+impl Leaderboard {
+    pub fn new(endpoint: &str) -> Self {
+        Leaderboard {
+            endpoint: endpoint.to_string(),
+        }
+    }
+
+    pub fn from_query() -> Result<Option<Self>> {
+        Ok(browser::query_param(ENDPOINT_PARAM)?.map(|endpoint| Leaderboard::new(&endpoint)))
+    }
+
+    pub async fn submit_score(&self, name: &str, score: u32) -> Result<()> {
+        let entry = Entry {
+            name: name.to_string(),
+            score,
+        };
+        let body = js_sys::JSON::stringify(&JsValue::from_serde(&entry)?)
+            .map_err(|err| anyhow!("Could not serialize score {:#?}", err))?;
+        browser::post_json(&self.endpoint, &String::from(body)).await
+    }
+
+    // 上位から順に並んだスコアを返す
+    pub async fn fetch_leaderboard(&self) -> Result<Vec<Entry>> {
+        let json = browser::fetch_json(&self.endpoint).await?;
+        Ok(json.into_serde::<Vec<Entry>>()?)
+    }
+}
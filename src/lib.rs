@@ -1,5 +1,11 @@
 #[macro_use]
 mod browser;
+mod deaths;
 mod engine;
+mod fractal;
 mod game;
+mod leaderboard;
 mod segments;
+mod settings;
+mod sound;
+mod tween;
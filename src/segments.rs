@@ -1,10 +1,12 @@
-use std::rc::Rc;
+use std::{rc::Rc, str::FromStr};
 
+use anyhow::{anyhow, Result};
+use rand::{distributions::WeightedIndex, prelude::*};
 use web_sys::HtmlImageElement;
 
 use crate::{
     engine::{Image, Point, Rect, SpriteSheet},
-    game::{Barrier, Obstacle, Platform},
+    game::{Barrier, CrumblingPlatform, Obstacle, Platform},
 };
 
 const LOW_PLATFORM: i16 = 420;
@@ -33,61 +35,550 @@ const FLOATING_PLATFORM_BOUNDING_BOXES: [Rect; 3] = [
     ),
 ];
 
+// レベル定義のJSONで使えるセグメントの種類
+pub const SEGMENT_TYPES: [&str; 4] = [
+    "stone_and_platform",
+    "platform_and_stone",
+    "two_stones",
+    "crumbling_platform",
+];
+
+// 中くらいと難しいセグメントの障害物にかける色の変化
+const MEDIUM_FILTER: &str = "hue-rotate(300deg)";
+const HARD_FILTER: &str = "hue-rotate(180deg) saturate(1.5)";
+
+// 1セグメント生成するごとに、易しいセグメントの重みから難しいセグメントへ移す量
+const DIFFICULTY_STEP: f32 = 0.5;
+const MIN_EASY_WEIGHT: f32 = 20.0;
+// 何度も倒れている辺りで、難しいセグメントと中くらいのセグメントの重みのうち
+// 易しいセグメントへ移す割合。気付かれない程度に留める
+const EASED_HARD: f32 = 0.5;
+const EASED_MEDIUM: f32 = 0.25;
+// 難しいセグメントが続きそうな時に間に挟むセグメント
+const RECOVERY_SEGMENT: &str = "stone_and_platform";
+
+// SEGMENT_TYPESの各セグメントを、名前の文字列ではなく型で指定する時に使う
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SegmentKind {
+    StoneAndPlatform,
+    PlatformAndStone,
+    TwoStones,
+    CrumblingPlatform,
+}
+
+impl SegmentKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SegmentKind::StoneAndPlatform => "stone_and_platform",
+            SegmentKind::PlatformAndStone => "platform_and_stone",
+            SegmentKind::TwoStones => "two_stones",
+            SegmentKind::CrumblingPlatform => "crumbling_platform",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            SegmentKind::StoneAndPlatform,
+            SegmentKind::PlatformAndStone,
+            SegmentKind::TwoStones,
+            SegmentKind::CrumblingPlatform,
+        ]
+        .into_iter()
+        .find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+pub fn difficulty(kind: &str) -> Difficulty {
+    match kind {
+        "platform_and_stone" | "crumbling_platform" => Difficulty::Medium,
+        "two_stones" => Difficulty::Hard,
+        _ => Difficulty::Easy,
+    }
+}
+
+// 難易度ごとのセグメントの出やすさ。合計が100である必要はない
+#[derive(Copy, Clone, Debug)]
+pub struct SpawnWeights {
+    easy: f32,
+    medium: f32,
+    hard: f32,
+}
+
+impl Default for SpawnWeights {
+    fn default() -> Self {
+        SpawnWeights {
+            easy: 60.0,
+            medium: 30.0,
+            hard: 10.0,
+        }
+    }
+}
+
+impl SpawnWeights {
+    pub fn new(easy: f32, medium: f32, hard: f32) -> Result<Self> {
+        let weights = [easy, medium, hard];
+        if weights
+            .iter()
+            .any(|weight| !weight.is_finite() || *weight < 0.0)
+        {
+            return Err(anyhow!(
+                "Spawn weights must be non-negative, got {:?}",
+                weights
+            ));
+        }
+        if weights.iter().all(|weight| *weight == 0.0) {
+            return Err(anyhow!("At least one spawn weight must be above zero"));
+        }
+        Ok(SpawnWeights { easy, medium, hard })
+    }
+
+    // 進むほど難しいセグメントが出やすくなる
+    pub fn raise_difficulty(&mut self) {
+        let step = DIFFICULTY_STEP.min(self.easy - MIN_EASY_WEIGHT).max(0.0);
+        self.easy -= step;
+        self.hard += step;
+    }
+
+    fn eased(&self) -> Self {
+        let hard = self.hard * (1.0 - EASED_HARD);
+        let medium = self.medium * (1.0 - EASED_MEDIUM);
+        SpawnWeights {
+            easy: self.easy + (self.hard - hard) + (self.medium - medium),
+            medium,
+            hard,
+        }
+    }
+
+    pub fn choose(&self, rng: &mut impl Rng) -> &'static str {
+        let tiers = [Difficulty::Easy, Difficulty::Medium, Difficulty::Hard];
+        let weights = [self.easy, self.medium, self.hard];
+        // newで検証済みなので失敗しない
+        let tier = WeightedIndex::new(weights)
+            .map(|index| tiers[index.sample(rng)])
+            .unwrap_or(Difficulty::Easy);
+        let kinds: Vec<&'static str> = SEGMENT_TYPES
+            .iter()
+            .copied()
+            .filter(|kind| difficulty(kind) == tier)
+            .collect();
+        kinds.choose(rng).copied().unwrap_or(SEGMENT_TYPES[0])
+    }
+}
+
+// URLで指定する"60,30,10"のような、易しい・普通・難しいの順の重み
+impl FromStr for SpawnWeights {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let weights = value
+            .split(',')
+            .map(|weight| weight.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|err| anyhow!("Invalid spawn weights '{}': {}", value, err))?;
+        match weights[..] {
+            [easy, medium, hard] => SpawnWeights::new(easy, medium, hard),
+            _ => Err(anyhow!(
+                "Expected three spawn weights like '60,30,10', got '{}'",
+                value
+            )),
+        }
+    }
+}
+
+// 重みに従ってセグメントを選ぶが、難しいセグメントは2つ続けない
+pub struct SegmentPicker {
+    weights: SpawnWeights,
+    last: Option<Difficulty>,
+    queued: Option<&'static str>,
+    // trueの間は易しいセグメントに寄せて選ぶ。元の重みと難しくなる速さは変えない
+    easing: bool,
+}
+
+impl SegmentPicker {
+    pub fn new(weights: SpawnWeights) -> Self {
+        SegmentPicker {
+            weights,
+            last: None,
+            queued: None,
+            easing: false,
+        }
+    }
+
+    pub fn set_weights(&mut self, weights: SpawnWeights) {
+        self.weights = weights;
+    }
+
+    pub fn set_easing(&mut self, easing: bool) {
+        self.easing = easing;
+    }
+
+    pub fn next(&mut self, rng: &mut impl Rng) -> &'static str {
+        let kind = match self.queued.take() {
+            Some(kind) => kind,
+            None => {
+                let kind = if self.easing {
+                    self.weights.eased().choose(rng)
+                } else {
+                    self.weights.choose(rng)
+                };
+                self.weights.raise_difficulty();
+                if self.last == Some(Difficulty::Hard) && difficulty(kind) == Difficulty::Hard {
+                    // 選ばれたセグメントは捨てずに、易しいセグメントの次に出す
+                    self.queued = Some(kind);
+                    RECOVERY_SEGMENT
+                } else {
+                    kind
+                }
+            }
+        };
+        self.last = Some(difficulty(kind));
+        kind
+    }
+}
+
+// 難易度ごとの障害物の見た目。当たり判定は見た目に関係なく同じものを使う
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub platform_sprites: [String; 3],
+    // CSSのfilter。Noneなら元の色のまま描く
+    pub filter: Option<String>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            platform_sprites: FLOATING_PLATFORM_SPRITES.map(String::from),
+            filter: None,
+        }
+    }
+}
+
+impl Palette {
+    pub fn with_filter(filter: &str) -> Self {
+        Palette {
+            filter: Some(filter.to_string()),
+            ..Palette::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Palettes {
+    easy: Palette,
+    medium: Palette,
+    hard: Palette,
+}
+
+// 易しいセグメントは今までと同じ見た目にする
+impl Default for Palettes {
+    fn default() -> Self {
+        Palettes {
+            easy: Palette::default(),
+            medium: Palette::with_filter(MEDIUM_FILTER),
+            hard: Palette::with_filter(HARD_FILTER),
+        }
+    }
+}
+
+impl Palettes {
+    pub fn for_tier(&self, tier: Difficulty) -> &Palette {
+        match tier {
+            Difficulty::Easy => &self.easy,
+            Difficulty::Medium => &self.medium,
+            Difficulty::Hard => &self.hard,
+        }
+    }
+}
+
+// scaleは障害物の大きさの倍率。どの障害物も下端の位置は変わらない
+pub fn segment(
+    kind: &str,
+    stone: Rc<HtmlImageElement>,
+    sprite_sheet: Rc<SpriteSheet>,
+    offset_x: i16,
+    palettes: &Palettes,
+    scale: f32,
+) -> Result<Vec<Box<dyn Obstacle>>> {
+    let palette = palettes.for_tier(difficulty(kind));
+    match kind {
+        "stone_and_platform" => Ok(stone_and_platform(
+            stone,
+            sprite_sheet,
+            offset_x,
+            palette,
+            scale,
+        )),
+        "platform_and_stone" => Ok(platform_and_stone(
+            stone,
+            sprite_sheet,
+            offset_x,
+            palette,
+            scale,
+        )),
+        "two_stones" => Ok(two_stones(stone, offset_x, palette, scale)),
+        "crumbling_platform" => Ok(crumbling_platform(
+            stone,
+            sprite_sheet,
+            offset_x,
+            palette,
+            scale,
+        )),
+        _ => Err(anyhow!(
+            "Unknown segment type '{}', expected one of {:?}",
+            kind,
+            SEGMENT_TYPES
+        )),
+    }
+}
+
 pub fn stone_and_platform(
-    stone: HtmlImageElement,
+    stone: Rc<HtmlImageElement>,
     sprite_sheet: Rc<SpriteSheet>,
     offset_x: i16,
+    palette: &Palette,
+    scale: f32,
 ) -> Vec<Box<dyn Obstacle>> {
     const INITIAL_STONE_OFFSET: i16 = 210;
     const INITIAL_PLATRFORM_OFFSET: i16 = 370;
     vec![
-        Box::new(Barrier::new(Image::new(
-            stone,
-            Point {
-                x: offset_x + INITIAL_STONE_OFFSET,
-                y: STONE_ON_GROUND,
-            },
-        ))),
+        Box::new(
+            Barrier::new(
+                Image::new(
+                    stone,
+                    Point {
+                        x: offset_x + INITIAL_STONE_OFFSET,
+                        y: STONE_ON_GROUND,
+                    },
+                )
+                .with_scale(scale),
+            )
+            .with_filter(palette.filter.as_deref()),
+        ),
         Box::new(create_floating_platform(
             sprite_sheet,
+            palette,
             Point {
                 x: offset_x + INITIAL_PLATRFORM_OFFSET,
                 y: LOW_PLATFORM,
             },
+            scale,
         )),
     ]
 }
 
 pub fn platform_and_stone(
-    stone: HtmlImageElement,
+    stone: Rc<HtmlImageElement>,
     sprite_sheet: Rc<SpriteSheet>,
     offset_x: i16,
+    palette: &Palette,
+    scale: f32,
 ) -> Vec<Box<dyn Obstacle>> {
     const INITIAL_STONE_OFFSET: i16 = 420;
     const INITIAL_PLATRFORM_OFFSET: i16 = 130;
     vec![
         Box::new(create_floating_platform(
             sprite_sheet,
+            palette,
             Point {
                 x: offset_x + INITIAL_PLATRFORM_OFFSET,
                 y: HIGH_PLATFORM,
             },
+            scale,
         )),
-        Box::new(Barrier::new(Image::new(
-            stone,
+        Box::new(
+            Barrier::new(
+                Image::new(
+                    stone,
+                    Point {
+                        x: offset_x + INITIAL_STONE_OFFSET,
+                        y: STONE_ON_GROUND,
+                    },
+                )
+                .with_scale(scale),
+            )
+            .with_filter(palette.filter.as_deref()),
+        ),
+    ]
+}
+
+pub fn two_stones(
+    stone: Rc<HtmlImageElement>,
+    offset_x: i16,
+    palette: &Palette,
+    scale: f32,
+) -> Vec<Box<dyn Obstacle>> {
+    const FIRST_STONE_OFFSET: i16 = 150;
+    const SECOND_STONE_OFFSET: i16 = 450;
+    vec![
+        Box::new(
+            Barrier::new(
+                Image::new(
+                    stone.clone(),
+                    Point {
+                        x: offset_x + FIRST_STONE_OFFSET,
+                        y: STONE_ON_GROUND,
+                    },
+                )
+                .with_scale(scale),
+            )
+            .with_filter(palette.filter.as_deref()),
+        ),
+        Box::new(
+            Barrier::new(
+                Image::new(
+                    stone,
+                    Point {
+                        x: offset_x + SECOND_STONE_OFFSET,
+                        y: STONE_ON_GROUND,
+                    },
+                )
+                .with_scale(scale),
+            )
+            .with_filter(palette.filter.as_deref()),
+        ),
+    ]
+}
+
+// stone_and_platformと同じ並びだが、足場は乗るとすぐに崩れる。
+// 崩れても下の床に落ちるだけなので、越えられなくなることはない
+pub fn crumbling_platform(
+    stone: Rc<HtmlImageElement>,
+    sprite_sheet: Rc<SpriteSheet>,
+    offset_x: i16,
+    palette: &Palette,
+    scale: f32,
+) -> Vec<Box<dyn Obstacle>> {
+    const INITIAL_STONE_OFFSET: i16 = 210;
+    const INITIAL_PLATRFORM_OFFSET: i16 = 370;
+    vec![
+        Box::new(
+            Barrier::new(
+                Image::new(
+                    stone,
+                    Point {
+                        x: offset_x + INITIAL_STONE_OFFSET,
+                        y: STONE_ON_GROUND,
+                    },
+                )
+                .with_scale(scale),
+            )
+            .with_filter(palette.filter.as_deref()),
+        ),
+        Box::new(CrumblingPlatform::new(create_floating_platform(
+            sprite_sheet,
+            palette,
             Point {
-                x: offset_x + INITIAL_STONE_OFFSET,
-                y: STONE_ON_GROUND,
+                x: offset_x + INITIAL_PLATRFORM_OFFSET,
+                y: LOW_PLATFORM,
             },
+            scale,
         ))),
     ]
 }
 
-fn create_floating_platform(sprite_sheet: Rc<SpriteSheet>, position: Point) -> Platform {
+fn create_floating_platform(
+    sprite_sheet: Rc<SpriteSheet>,
+    palette: &Palette,
+    position: Point,
+    scale: f32,
+) -> Platform {
+    let sprites = palette.platform_sprites.each_ref().map(String::as_str);
+    floating_platform(sprite_sheet, position, &sprites, palette.filter.as_deref()).with_scale(scale)
+}
+
+// 保存した場面から作り直す時は、セグメントを通さずに1つずつ置く
+pub fn floating_platform(
+    sprite_sheet: Rc<SpriteSheet>,
+    position: Point,
+    sprites: &[&str],
+    filter: Option<&str>,
+) -> Platform {
     Platform::new(
         sprite_sheet,
         position,
-        &FLOATING_PLATFORM_SPRITES,
+        sprites,
         &FLOATING_PLATFORM_BOUNDING_BOXES,
     )
+    .with_filter(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_segment_type_has_a_kind() {
+        SEGMENT_TYPES.iter().for_each(|name| {
+            assert_eq!(
+                SegmentKind::from_name(name).map(|kind| kind.name()),
+                Some(*name)
+            );
+        });
+        assert_eq!(SegmentKind::from_name("three_stones"), None);
+    }
+
+    #[test]
+    fn easy_tier_keeps_the_original_look() {
+        let easy = Palettes::default().for_tier(Difficulty::Easy).clone();
+
+        assert_eq!(
+            easy.platform_sprites,
+            FLOATING_PLATFORM_SPRITES.map(String::from)
+        );
+        assert_eq!(easy.filter, None);
+    }
+
+    #[test]
+    fn spawn_weights_are_validated() {
+        assert!(SpawnWeights::new(60.0, 30.0, 10.0).is_ok());
+        assert!(SpawnWeights::new(60.0, -1.0, 10.0).is_err());
+        assert!(SpawnWeights::new(0.0, 0.0, 0.0).is_err());
+        assert!(SpawnWeights::new(f32::NAN, 1.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn spawn_weights_parse_from_the_url() {
+        let weights: SpawnWeights = "20, 30,50".parse().unwrap();
+        assert_eq!(
+            (weights.easy, weights.medium, weights.hard),
+            (20.0, 30.0, 50.0)
+        );
+        assert!("60,30".parse::<SpawnWeights>().is_err());
+        assert!("60,30,hard".parse::<SpawnWeights>().is_err());
+        assert!("0,0,0".parse::<SpawnWeights>().is_err());
+    }
+
+    #[test]
+    fn same_seed_chooses_same_segments() {
+        let weights = SpawnWeights::default();
+        let mut first = StdRng::seed_from_u64(42);
+        let mut second = StdRng::seed_from_u64(42);
+
+        let first: Vec<_> = (0..20).map(|_| weights.choose(&mut first)).collect();
+        let second: Vec<_> = (0..20).map(|_| weights.choose(&mut second)).collect();
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn hard_segments_never_follow_each_other() {
+        let mut picker = SegmentPicker::new(SpawnWeights::new(10.0, 10.0, 80.0).unwrap());
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let kinds: Vec<_> = (0..50).map(|_| picker.next(&mut rng)).collect();
+
+        assert!(kinds
+            .iter()
+            .any(|kind| difficulty(kind) == Difficulty::Hard));
+        assert!(kinds.windows(2).all(|pair| {
+            difficulty(pair[0]) != Difficulty::Hard || difficulty(pair[1]) != Difficulty::Hard
+        }));
+    }
 }
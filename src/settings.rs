@@ -0,0 +1,369 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    browser::Store,
+    engine::{AudioSettings, GameSpeed, KeyState},
+    game::{KnockoutAnimation, PhysicsConfig},
+};
+
+const SETTINGS_KEY: &str = "walk_the_dog.settings";
+// Settingsにまとめる前に、1つずつ保存していた時のキー
+const LEGACY_MUTED_KEY: &str = "walk_the_dog.muted";
+const LEGACY_VOLUME_KEY: &str = "walk_the_dog.volume";
+const LEGACY_GAME_SPEED_KEY: &str = "walk_the_dog.game_speed";
+// 入力の猶予の上限。これより長いと、押したのがだいぶ前のジャンプや崖から落ちきる頃のジャンプまで通ってしまう
+pub const MAX_INPUT_WINDOW: u8 = 10;
+const JUMP_BUFFER_FRAMES: u8 = 6;
+const COYOTE_FRAMES: u8 = 6;
+
+// 設定画面で変えられる値。まとめて1つのJSONとしてlocalStorageに保存する。
+// 保存されていない項目は既定値になるので、項目を増やしても古い保存データを読める
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub muted: bool,
+    pub volume: f32,
+    pub game_speed: f32,
+    pub show_hitboxes: bool,
+    pub key_preset: Preset,
+    // 同じ辺りで何度も倒れたら、その辺りのセグメントを少し易しくする
+    pub anti_frustration: bool,
+    // 着地の少し前に押したジャンプを覚えておくフレーム数
+    pub jump_buffer: u8,
+    // 足場から走り落ちた後もジャンプできるフレーム数
+    pub coyote_time: u8,
+    // 倒れるアニメーションのフレーム数と、絵が足りない時に最後の絵で止めるか繰り返すか
+    pub falling_frames: u8,
+    pub knockout_animation: KnockoutAnimation,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            muted: false,
+            volume: 1.0,
+            game_speed: 1.0,
+            show_hitboxes: false,
+            key_preset: Preset::default(),
+            anti_frustration: true,
+            jump_buffer: JUMP_BUFFER_FRAMES,
+            coyote_time: COYOTE_FRAMES,
+            falling_frames: PhysicsConfig::default().falling_frames,
+            knockout_animation: KnockoutAnimation::default(),
+        }
+    }
+}
+
+impl Settings {
+    // 読めない時は既定値にする。まだ保存されていない時は古いキーの値を引き継ぐ
+    pub fn load(store: &dyn Store) -> Self {
+        store
+            .load(SETTINGS_KEY)
+            .and_then(|json| {
+                json.map_or_else(
+                    || Settings::migrate(store),
+                    |json| Settings::from_json(&json),
+                )
+            })
+            .unwrap_or_else(|err| {
+                warn!("Could not read settings, using defaults {:#?}", err);
+                Settings::default()
+            })
+    }
+
+    // 一度保存すれば次からはJSONの方を読むので、引き継ぐのは最初の1回だけ
+    fn migrate(store: &dyn Store) -> Result<Self> {
+        let settings = Settings::from_legacy(|key| store.load(key).ok().flatten());
+        if let Err(err) = settings.save(store) {
+            warn!("Could not save migrated settings {:#?}", err);
+        }
+        Ok(settings)
+    }
+
+    // 古いキーが無い時や壊れている時は、その項目だけ既定値にする
+    fn from_legacy(load: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Settings::default();
+        Settings {
+            muted: load(LEGACY_MUTED_KEY)
+                .and_then(|muted| muted.parse().ok())
+                .unwrap_or(default.muted),
+            volume: load(LEGACY_VOLUME_KEY)
+                .and_then(|volume| volume.parse().ok())
+                .unwrap_or(default.volume),
+            game_speed: load(LEGACY_GAME_SPEED_KEY)
+                .and_then(|speed| speed.parse().ok())
+                .unwrap_or(default.game_speed),
+            ..default
+        }
+        .sanitized()
+    }
+
+    pub fn save(&self, store: &dyn Store) -> Result<()> {
+        store.save(SETTINGS_KEY, &serde_json::to_string(self)?)
+    }
+
+    fn from_json(json: &str) -> Result<Self> {
+        let settings = serde_json::from_str::<Settings>(json)
+            .map_err(|err| anyhow!("Broken settings '{}': {}", json, err))?;
+        Ok(settings.sanitized())
+    }
+
+    // 手で書き換えられた値も範囲内に収める
+    fn sanitized(self) -> Self {
+        let audio = self.audio();
+        let input_windows = self.input_windows();
+        Settings {
+            muted: audio.muted,
+            volume: audio.volume,
+            game_speed: self.game_speed().value(),
+            jump_buffer: input_windows.jump_buffer,
+            coyote_time: input_windows.coyote_time,
+            // 0フレームだと倒れるアニメーションが出ない
+            falling_frames: self.falling_frames.max(1),
+            ..self
+        }
+    }
+
+    pub fn audio(&self) -> AudioSettings {
+        AudioSettings::new(self.muted, self.volume)
+    }
+
+    pub fn game_speed(&self) -> GameSpeed {
+        GameSpeed::new(self.game_speed)
+    }
+
+    pub fn key_bindings(&self) -> KeyBindings {
+        KeyBindings::preset(self.key_preset)
+    }
+
+    pub fn input_windows(&self) -> InputWindows {
+        InputWindows::new(self.jump_buffer, self.coyote_time)
+    }
+
+    pub fn physics(&self) -> PhysicsConfig {
+        PhysicsConfig {
+            falling_frames: self.falling_frames,
+            knockout_animation: self.knockout_animation,
+            ..PhysicsConfig::default()
+        }
+    }
+}
+
+// ジャンプの入力の猶予。どちらも0にすると、地面にいるフレームで押したジャンプしか受け付けない
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InputWindows {
+    pub jump_buffer: u8,
+    pub coyote_time: u8,
+}
+
+impl Default for InputWindows {
+    fn default() -> Self {
+        InputWindows {
+            jump_buffer: JUMP_BUFFER_FRAMES,
+            coyote_time: COYOTE_FRAMES,
+        }
+    }
+}
+
+impl InputWindows {
+    // 長すぎる猶予はMAX_INPUT_WINDOWに縮める
+    pub fn new(jump_buffer: u8, coyote_time: u8) -> Self {
+        InputWindows {
+            jump_buffer: jump_buffer.min(MAX_INPUT_WINDOW),
+            coyote_time: coyote_time.min(MAX_INPUT_WINDOW),
+        }
+    }
+}
+
+// 左手用などの、最初から用意してある操作キーの組み合わせ
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    #[default]
+    Arrows,
+    Wasd,
+    Ijkl,
+}
+
+pub const PRESETS: [Preset; 3] = [Preset::Arrows, Preset::Wasd, Preset::Ijkl];
+
+impl Preset {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::Arrows => "arrows",
+            Preset::Wasd => "WASD",
+            Preset::Ijkl => "IJKL",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        self.step(1)
+    }
+
+    pub fn previous(&self) -> Self {
+        self.step(PRESETS.len() - 1)
+    }
+
+    fn step(&self, by: usize) -> Self {
+        let index = PRESETS
+            .iter()
+            .position(|preset| preset == self)
+            .unwrap_or(0);
+        PRESETS[(index + by) % PRESETS.len()]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Run,
+    Jump,
+    Slide,
+}
+
+// 操作ごとのKeyboardEvent.code。Spaceはどの組み合わせでも跳べる
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyBindings {
+    run: &'static [&'static str],
+    jump: &'static [&'static str],
+    slide: &'static [&'static str],
+}
+
+impl KeyBindings {
+    pub fn preset(preset: Preset) -> Self {
+        match preset {
+            Preset::Arrows => KeyBindings {
+                run: &["ArrowRight"],
+                jump: &["Space"],
+                slide: &["ArrowDown"],
+            },
+            Preset::Wasd => KeyBindings {
+                run: &["KeyD"],
+                jump: &["KeyW", "Space"],
+                slide: &["KeyS"],
+            },
+            Preset::Ijkl => KeyBindings {
+                run: &["KeyL"],
+                jump: &["KeyI", "Space"],
+                slide: &["KeyK"],
+            },
+        }
+    }
+
+    pub fn keys(&self, action: Action) -> &'static [&'static str] {
+        match action {
+            Action::Run => self.run,
+            Action::Jump => self.jump,
+            Action::Slide => self.slide,
+        }
+    }
+
+    // 押されているキーは毎回今の割り当てで見直すので、切り替えた直前に
+    // 押していたキーで操作が続くことはない
+    pub fn is_pressed(&self, keystate: &KeyState, action: Action) -> bool {
+        self.keys(action)
+            .iter()
+            .any(|code| keystate.is_pressed(code))
+    }
+
+    pub fn was_pressed(&self, keystate: &KeyState, action: Action) -> bool {
+        self.keys(action)
+            .iter()
+            .any(|code| keystate.was_pressed(code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::browser::MemoryStore;
+
+    #[test]
+    fn missing_and_unknown_fields_fall_back_to_defaults() {
+        let settings = Settings::from_json(r#"{"muted": true, "colorblind": true}"#).unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                muted: true,
+                ..Settings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn legacy_keys_are_carried_over() {
+        let settings = Settings::from_legacy(|key| match key {
+            LEGACY_MUTED_KEY => Some("true".to_string()),
+            LEGACY_VOLUME_KEY => Some("0.25".to_string()),
+            LEGACY_GAME_SPEED_KEY => Some("abc".to_string()),
+            _ => None,
+        });
+        assert_eq!(
+            settings,
+            Settings {
+                muted: true,
+                volume: 0.25,
+                ..Settings::default()
+            }
+        );
+        assert_eq!(Settings::from_legacy(|_| None), Settings::default());
+    }
+
+    #[test]
+    fn legacy_keys_are_migrated_only_once() {
+        let store = MemoryStore::default();
+        store.save(LEGACY_MUTED_KEY, "true").unwrap();
+        let migrated = Settings::load(&store);
+        assert!(migrated.muted);
+        assert!(store.load(SETTINGS_KEY).unwrap().is_some());
+
+        // 保存した後は古いキーを見ない
+        store.save(LEGACY_VOLUME_KEY, "0.5").unwrap();
+        assert_eq!(Settings::load(&store), migrated);
+    }
+
+    #[test]
+    fn remapped_keys_take_effect_immediately() {
+        let mut keystate = KeyState::new();
+        keystate.press("ArrowRight");
+        keystate.start_update();
+        let arrows = KeyBindings::preset(Preset::Arrows);
+        let wasd = KeyBindings::preset(Preset::Wasd);
+        assert!(arrows.is_pressed(&keystate, Action::Run));
+        // 押しっぱなしの矢印キーは、WASDに切り替えた後は走る操作にならない
+        assert!(!wasd.is_pressed(&keystate, Action::Run));
+        assert!(!wasd.was_pressed(&keystate, Action::Run));
+    }
+
+    #[test]
+    fn presets_cycle_in_both_directions() {
+        PRESETS.iter().for_each(|preset| {
+            assert_eq!(preset.next().previous(), *preset);
+        });
+        assert_eq!(Preset::Ijkl.next(), Preset::Arrows);
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        assert!(Settings::from_json("not json").is_err());
+        assert_eq!(
+            Settings::from_json(r#"{"volume": 3.0, "game_speed": 0.1}"#).unwrap(),
+            Settings {
+                volume: 1.0,
+                game_speed: GameSpeed::MIN,
+                ..Settings::default()
+            }
+        );
+        assert_eq!(
+            Settings::from_json(r#"{"jump_buffer": 200, "coyote_time": 0}"#)
+                .unwrap()
+                .input_windows(),
+            InputWindows::new(MAX_INPUT_WINDOW, 0)
+        );
+        let physics = Settings::from_json(r#"{"falling_frames": 0, "knockout_animation": "loop"}"#)
+            .unwrap()
+            .physics();
+        assert_eq!(physics.falling_frames, 1);
+        assert_eq!(physics.knockout_animation, KnockoutAnimation::Loop);
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use js_sys::ArrayBuffer;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{AudioBuffer, AudioBufferSourceNode, AudioContext, AudioNode, GainNode};
+
+pub fn create_audio_context() -> Result<AudioContext> {
+    AudioContext::new().map_err(|err| anyhow!("Could not create audio context: {:#?}", err))
+}
+
+fn create_buffer_source(ctx: &AudioContext) -> Result<AudioBufferSourceNode> {
+    ctx.create_buffer_source()
+        .map_err(|err| anyhow!("Error creating buffer source {:#?}", err))
+}
+
+fn connect_with_audio_node(
+    buffer_source: &AudioBufferSourceNode,
+    destination: &AudioNode,
+) -> Result<AudioNode> {
+    buffer_source
+        .connect_with_audio_node(destination)
+        .map_err(|err| anyhow!("Error connecting audio source to destination {:#?}", err))
+}
+
+// 全ての音はこのGainNodeを通して鳴らす
+pub fn create_master_gain(ctx: &AudioContext) -> Result<GainNode> {
+    create_gain(ctx, &ctx.destination())
+}
+
+pub fn create_gain(ctx: &AudioContext, destination: &AudioNode) -> Result<GainNode> {
+    let gain = ctx
+        .create_gain()
+        .map_err(|err| anyhow!("Error creating gain node {:#?}", err))?;
+    gain.connect_with_audio_node(destination)
+        .map_err(|err| anyhow!("Error connecting gain node to destination {:#?}", err))?;
+    Ok(gain)
+}
+
+// 今の値からsecondsかけてvalueまで直線的に変える。途中で呼ばれたら予定を置き換える
+pub fn ramp_gain(ctx: &AudioContext, gain: &GainNode, value: f32, seconds: f64) -> Result<()> {
+    let param = gain.gain();
+    let now = ctx.current_time();
+    param
+        .cancel_scheduled_values(now)
+        .and_then(|param| param.set_value_at_time(param.value(), now))
+        .and_then(|param| param.linear_ramp_to_value_at_time(value, now + seconds))
+        .map(|_| ())
+        .map_err(|err| anyhow!("Could not ramp gain {:#?}", err))
+}
+
+pub fn play_looping_sound(
+    ctx: &AudioContext,
+    destination: &AudioNode,
+    buffer: &AudioBuffer,
+) -> Result<AudioBufferSourceNode> {
+    let track_source = create_buffer_source(ctx)?;
+    track_source.set_buffer(Some(buffer));
+    track_source.set_loop(true);
+    connect_with_audio_node(&track_source, destination)?;
+    track_source
+        .start()
+        .map_err(|err| anyhow!("Could not start sound! {:#?}", err))?;
+    Ok(track_source)
+}
+
+pub fn stop_sound(track_source: &AudioBufferSourceNode) -> Result<()> {
+    track_source
+        .stop()
+        .map_err(|err| anyhow!("Could not stop sound! {:#?}", err))
+}
+
+pub async fn decode_audio_data(
+    ctx: &AudioContext,
+    array_buffer: &ArrayBuffer,
+) -> Result<AudioBuffer> {
+    JsFuture::from(
+        ctx.decode_audio_data(array_buffer)
+            .map_err(|err| anyhow!("Could not decode audio from array buffer {:#?}", err))?,
+    )
+    .await
+    .map_err(|err| anyhow!("Could not convert promise to future {:#?}", err))?
+    .dyn_into()
+    .map_err(|err| anyhow!("Could not cast into AudioBuffer {:#?}", err))
+}
+
+// suspendとresumeはPromiseを返すが、完了を待つ必要はない
+pub fn suspend(ctx: &AudioContext) -> Result<()> {
+    ctx.suspend()
+        .map(|_| ())
+        .map_err(|err| anyhow!("Could not suspend audio {:#?}", err))
+}
+
+pub fn resume(ctx: &AudioContext) -> Result<()> {
+    ctx.resume()
+        .map(|_| ())
+        .map_err(|err| anyhow!("Could not resume audio {:#?}", err))
+}
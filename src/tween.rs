@@ -0,0 +1,98 @@
+// ブラウザに依存しないアニメーション用の補間
+
+pub type Easing = fn(f32) -> f32;
+
+pub fn lerp(from: f32, to: f32, t: f32) -> f32 {
+    from + (to - from) * t
+}
+
+pub fn linear(t: f32) -> f32 {
+    t
+}
+
+pub fn ease_out_cubic(t: f32) -> f32 {
+    1.0 - (1.0 - t).powi(3)
+}
+
+pub fn ease_in_out(t: f32) -> f32 {
+    if t < 0.5 {
+        4.0 * t * t * t
+    } else {
+        1.0 - (-2.0 * t + 2.0).powi(3) / 2.0
+    }
+}
+
+// fromからtoまでdurationかけて変化する値。dtの単位はdurationと揃えること
+#[derive(Copy, Clone)]
+pub struct Tween {
+    from: f32,
+    to: f32,
+    duration: f32,
+    elapsed: f32,
+    easing: Easing,
+}
+
+impl Tween {
+    pub fn new(from: f32, to: f32, duration: f32, easing: Easing) -> Self {
+        Tween {
+            from,
+            to,
+            duration,
+            elapsed: 0.0,
+            easing,
+        }
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        self.elapsed = (self.elapsed + dt).min(self.duration);
+    }
+
+    pub fn target(&self) -> f32 {
+        self.to
+    }
+
+    pub fn value(&self) -> f32 {
+        lerp(self.from, self.to, (self.easing)(self.progress()))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+
+    // durationが0以下の時は最初から終わっている
+    fn progress(&self) -> f32 {
+        if self.duration <= 0.0 {
+            1.0
+        } else {
+            (self.elapsed / self.duration).clamp(0.0, 1.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn easings_start_at_zero_and_end_at_one() {
+        [linear, ease_out_cubic, ease_in_out]
+            .iter()
+            .for_each(|easing| {
+                assert_eq!(easing(0.0), 0.0);
+                assert_eq!(easing(1.0), 1.0);
+            });
+        assert_eq!(ease_in_out(0.5), 0.5);
+    }
+
+    #[test]
+    fn tween_reaches_its_target() {
+        let mut tween = Tween::new(10.0, 20.0, 100.0, ease_out_cubic);
+
+        tween.advance(50.0);
+        assert!(tween.value() > 15.0 && !tween.is_complete());
+
+        tween.advance(80.0);
+        assert_eq!(tween.value(), 20.0);
+        assert!(tween.is_complete());
+    }
+}